@@ -1,25 +1,26 @@
 /// Conversion of game constraints from [Defn] to [Multiverse] ready for solving:
-/// [line], [zone6] and [zone18]
+/// [line], [column], [zone6] and [zone18], or all at once with [all]
 use itertools::Itertools;
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
+use std::ops::RangeInclusive;
 
 use defn;
 use defn::Color;
 use defn::Modifier;
 use defn::Orientation;
+use misc;
 use misc::Coords;
 use multiverse::Layout;
 use multiverse::Multiverse;
 
-/// This multiverse constructor is common for Zone6 anywhere, Line anywhere and Zone18
+/// This multiverse constructor is common for Zone6 anywhere, Line anywhere and Zone18 anywhere
 /// The output contains a single layout
-fn distribute_anywhere(scope_vec: &Vec<Coords>, blue_count: usize) -> Multiverse {
-    if scope_vec.len() == 0 {
+fn distribute_anywhere(scope_vec: &[Coords], blue_count: usize) -> Multiverse {
+    if scope_vec.is_empty() {
         assert!(blue_count == 0);
         return Multiverse::empty();
     }
-    assert!(scope_vec.len() > 0);
     assert!(scope_vec.len() >= blue_count);
     let scope_set: BTreeSet<_> = scope_vec.iter().cloned().collect();
     let layout = Layout::new(BTreeMap::from([(scope_set.clone(), blue_count as u16)]));
@@ -29,8 +30,8 @@ fn distribute_anywhere(scope_vec: &Vec<Coords>, blue_count: usize) -> Multiverse
 
 /// This multiverse constructor is for Line together
 /// The output has one layout per solution
-fn distribute_together(scope_vec: &Vec<Coords>, blue_count: usize) -> Multiverse {
-    assert!(scope_vec.len() > 0);
+fn distribute_together(scope_vec: &[Coords], blue_count: usize) -> Multiverse {
+    assert!(!scope_vec.is_empty());
     assert!(scope_vec.len() >= blue_count);
     let scope_set: BTreeSet<_> = scope_vec.iter().cloned().collect();
     let solution_count = {
@@ -47,10 +48,9 @@ fn distribute_together(scope_vec: &Vec<Coords>, blue_count: usize) -> Multiverse
     for i0 in 0..solution_count {
         let mut blues = BTreeSet::new();
         let mut blacks = scope_set.clone();
-        for i in i0..(i0 + blue_count) {
-            let coords = scope_vec[i];
-            assert!(blacks.remove(&coords));
-            blues.insert(coords);
+        for coords in &scope_vec[i0..(i0 + blue_count)] {
+            assert!(blacks.remove(coords));
+            blues.insert(*coords);
         }
         assert_eq!(blues.len(), blue_count);
         assert_eq!(blacks.len() + blues.len(), scope_vec.len());
@@ -64,117 +64,117 @@ fn distribute_together(scope_vec: &Vec<Coords>, blue_count: usize) -> Multiverse
         layouts.push(Layout::new(map));
     }
     let mv = Multiverse::new(scope_set, layouts);
-    assert_eq!(Some(solution_count as u64), mv.solution_count_upper_bound());
+    assert_eq!(
+        Some(solution_count as u128),
+        mv.solution_count_upper_bound()
+    );
     mv
 }
 
 /// This multiverse constructor is for Line separated
-/// It is the only constructor that creates layouts with overlapping solutions
-fn distribute_separated(scope_vec: &Vec<Coords>, blue_count: usize) -> Multiverse {
+/// A solution is identified by its pivot, the first black cell that follows the first group of
+/// blues, and by the size of that first group. Each layout covers one (pivot, group size) pair,
+/// hence the layouts describe disjoint sets of solutions.
+fn distribute_separated(scope_vec: &[Coords], blue_count: usize) -> Multiverse {
     assert!(blue_count >= 2);
     assert!(scope_vec.len() >= 3);
     assert!(scope_vec.len() > blue_count);
     let scope_set: BTreeSet<_> = scope_vec.iter().cloned().collect();
-    let pivot_position_count = scope_vec.len() - 2;
     let mut layouts = vec![];
-    for ipivot in 1..(1 + pivot_position_count) {
-        let mut before = BTreeSet::new();
-        let pivot = BTreeSet::from([scope_vec[ipivot]]);
-        let mut after = BTreeSet::new();
-        for i in 0..ipivot {
-            before.insert(scope_vec[i]);
-        }
-        for i in (ipivot + 1)..scope_vec.len() {
-            after.insert(scope_vec[i]);
-        }
-        assert_eq!(before.len() + 1 + after.len(), scope_vec.len());
+    for ipivot in 1..(scope_vec.len() - 1) {
+        let after: BTreeSet<_> = scope_vec[(ipivot + 1)..].iter().cloned().collect();
         for i in 1..blue_count {
             let j = blue_count - i;
-            assert!(j >= 1);
-            if i > before.len() || j > after.len() {
+            if i > ipivot || j > after.len() {
                 continue;
             }
+            // The first group of blues is right before the pivot, the cells before the group
+            // are black.
+            let group: BTreeSet<_> = scope_vec[(ipivot - i)..ipivot].iter().cloned().collect();
+            let mut blacks: BTreeSet<_> = scope_vec[..(ipivot - i)].iter().cloned().collect();
+            blacks.insert(scope_vec[ipivot]);
+            assert_eq!(blacks.len() + group.len() + after.len(), scope_vec.len());
             layouts.push(Layout::new(BTreeMap::from([
-                (before.clone(), i as u16),
-                (pivot.clone(), 0),
+                (blacks, 0),
+                (group, i as u16),
                 (after.clone(), j as u16),
             ])));
         }
     }
+    let mv = Multiverse::new(scope_set, layouts);
+    let all = misc::n_choose_k(scope_vec.len() as u64, blue_count as u64);
+    if let Some(all) = all {
+        // All the solutions but the ones where the blues are together
+        let together = (scope_vec.len() - blue_count + 1) as u128;
+        assert_eq!(Some(all - together), mv.solution_count_upper_bound());
+    }
+    mv
+}
+
+/// Multiverse constructor for inequality constraints, the number of blues in the scope lies within
+/// `min_count..=max_count`.
+/// The output contains one layout per blue count, hence the layouts describe disjoint sets of
+/// solutions.
+fn distribute_in_range(scope_vec: &[Coords], min_count: usize, max_count: usize) -> Multiverse {
+    assert!(min_count <= max_count);
+    assert!(scope_vec.len() >= min_count);
+    if scope_vec.is_empty() {
+        return Multiverse::empty();
+    }
+    let scope_set: BTreeSet<_> = scope_vec.iter().cloned().collect();
+    let layouts = (min_count..=max_count.min(scope_vec.len()))
+        .map(|blue_count| Layout::new(BTreeMap::from([(scope_set.clone(), blue_count as u16)])))
+        .collect();
     Multiverse::new(scope_set, layouts)
 }
 
-fn has_compatible_contiguity(
-    blues: &BTreeSet<usize>,
-    blacks: &BTreeSet<usize>,
-    together: bool,
-) -> bool {
-    let last_blue_idx = blues.last().expect("Can't be empty");
-    let first_blue_idx = blues.first().expect("Can't be empty");
-    let all_blues_togethers = last_blue_idx - first_blue_idx == blues.len() - 1;
-    let last_black_idx = blacks.last().expect("Can't be empty");
-    let first_black_idx = blacks.first().expect("Can't be empty");
-    let all_blacks_togethers = last_black_idx - first_black_idx == blacks.len() - 1;
-    match (all_blues_togethers, all_blacks_togethers) {
-        (true, true) => {
-            // Colors are groupped together and don't loop over index 0
-            assert!(*first_blue_idx == 0 || *first_black_idx == 0);
-            assert!(*last_blue_idx == 5 || *last_black_idx == 5);
-            if !together {
-                return false;
-            };
-        }
-        (true, false) => {
-            // Colors are grouped together and blacks loop over index 0
-            if !together {
-                return false;
-            };
-        }
-        (false, true) => {
-            // Colors are grouped together and blues loop over index 0
-            if !together {
-                return false;
-            };
-        }
-        (false, false) => {
-            // Colors are not grouped together
-            if together {
-                return false;
-            };
-        }
-    };
-    true
+/// Multiverse constructor for "at least `blue_count` blues in the scope"
+pub fn distribute_at_least(scope_vec: &[Coords], blue_count: usize) -> Multiverse {
+    distribute_in_range(scope_vec, blue_count, scope_vec.len())
+}
+
+/// Multiverse constructor for "at most `blue_count` blues in the scope"
+pub fn distribute_at_most(scope_vec: &[Coords], blue_count: usize) -> Multiverse {
+    distribute_in_range(scope_vec, 0, blue_count)
+}
+
+/// Whether the blue indices of a ring of 6 cells form a single group of consecutive cells when
+/// `together`, or at least 2 groups otherwise. The ring loops over index 0, the other indices
+/// (including the gaps) are black. No blue or only blues count as a single group.
+fn has_compatible_contiguity(blues: &BTreeSet<usize>, together: bool) -> bool {
+    let group_count = blues
+        .iter()
+        .filter(|i| !blues.contains(&((*i + 5) % 6)))
+        .count();
+    if together {
+        group_count <= 1
+    } else {
+        group_count >= 2
+    }
 }
 
 /// This multiverse constructor is for Zone6 together and Zone6 separated
-/// The output contains one layout per solution
+/// The output contains one layout per solution. A gap, i.e. a `None` of `scope_arr`, breaks the
+/// ring: the blues on both of its sides are not together. Without solution, e.g. a separated hint
+/// with less than 2 blues, the output has no layouts, which is then reported as a contradiction of
+/// the grid.
 fn distribute_in_ring(
-    scope_arr: &[(Coords, bool); 6],
+    scope_arr: &[Option<Coords>; 6],
     blue_count: usize,
     together: bool,
 ) -> Multiverse {
-    if together {
-        let scope_vec: Vec<_> = scope_arr
-            .iter()
-            .filter_map(|(coords, is_gap)| if *is_gap { None } else { Some(*coords) })
-            .collect();
-        if blue_count <= 1 || blue_count == scope_vec.len() {
-            return distribute_anywhere(&scope_vec, blue_count);
-        }
-    } else {
-        assert!(blue_count >= 2);
+    if together && blue_count <= 1 {
+        let scope_vec: Vec<_> = scope_arr.iter().flatten().cloned().collect();
+        return distribute_anywhere(&scope_vec, blue_count);
     }
-    let scope_set: BTreeSet<_> = scope_arr
-        .iter()
-        .filter_map(|(c, is_gap)| if *is_gap { None } else { Some(*c) })
-        .collect();
+    let scope_set: BTreeSet<_> = scope_arr.iter().flatten().cloned().collect();
     let mut layouts = vec![];
     let idxs: BTreeSet<_> = (0..6).collect();
     for blues in idxs.iter().combinations(blue_count) {
         let blues: BTreeSet<_> = blues.iter().cloned().cloned().collect();
         let mut a_gap_is_blue = false;
         for i in &blues {
-            if scope_arr[*i].1 {
+            if scope_arr[*i].is_none() {
                 a_gap_is_blue = true;
                 break;
             }
@@ -182,16 +182,12 @@ fn distribute_in_ring(
         if a_gap_is_blue {
             continue;
         }
-        let blacks: BTreeSet<_> = idxs.difference(&blues).cloned().collect();
-        if !has_compatible_contiguity(&blues, &blacks, together) {
+        if !has_compatible_contiguity(&blues, together) {
             continue;
         }
-        let blues: BTreeSet<_> = blues.iter().map(|i| scope_arr[*i].0).collect();
-        let blacks: BTreeSet<_> = blacks
-            .iter()
-            .filter(|i| !scope_arr[**i].1)
-            .map(|i| scope_arr[*i].0)
-            .collect();
+        let blacks: BTreeSet<_> = idxs.difference(&blues).cloned().collect();
+        let blues: BTreeSet<_> = blues.iter().filter_map(|i| scope_arr[*i]).collect();
+        let blacks: BTreeSet<_> = blacks.iter().filter_map(|i| scope_arr[*i]).collect();
         assert_eq!(scope_set.len(), blues.len() + blacks.len());
         let mut bc = vec![];
         bc.push((blues, blue_count as u16));
@@ -200,71 +196,594 @@ fn distribute_in_ring(
         }
         layouts.push(Layout::new(bc.into_iter().collect()));
     }
-    assert!(layouts.len() > 0);
     Multiverse::new(scope_set, layouts)
 }
 
+/// The colored cells among `cells` and their number of blues
+fn colored_scope<'a>(
+    cells: impl IntoIterator<Item = (Coords, &'a defn::Cell)>,
+) -> (Vec<Coords>, usize) {
+    let mut scope = Vec::new();
+    let mut blue_count = 0;
+    for (c, cell) in cells {
+        match defn::color_of_cell(cell) {
+            None => (),
+            Some(Color::Blue) => {
+                blue_count += 1;
+                scope.push(c);
+            }
+            Some(Color::Black) => {
+                scope.push(c);
+            }
+        }
+    }
+    (scope, blue_count)
+}
+
+pub fn zone6(defn: &defn::Defn, coords: Coords, modifier: Modifier) -> Multiverse {
+    let together = match modifier {
+        Modifier::Anywhere => {
+            let (scope, blue_count) = colored_scope(defn::neighbors6_in(defn, coords));
+            return distribute_anywhere(&scope, blue_count);
+        }
+        Modifier::Together => true,
+        Modifier::Separated => false,
+    };
+    // The ring needs the positions of its gaps, the neighbors out of the range of the coords
+    // being gaps as well
+    let mut blue_count = 0;
+    let neighborhood = coords.checked_neighbors6();
+    let scope_arr = neighborhood.map(|c| match defn.get(&c?).and_then(defn::color_of_cell)? {
+        Color::Blue => {
+            blue_count += 1;
+            c
+        }
+        Color::Black => c,
+    });
+    distribute_in_ring(&scope_arr, blue_count, together)
+}
+
+/// Are the cells of `blues` a single group of adjacent cells
+fn is_connected(blues: &BTreeSet<Coords>) -> bool {
+    let first = match blues.first() {
+        None => return true,
+        Some(first) => *first,
+    };
+    let mut seen = BTreeSet::from([first]);
+    let mut todo = vec![first];
+    while let Some(c) = todo.pop() {
+        for neighbor in c.neighbors6() {
+            if blues.contains(&neighbor) && seen.insert(neighbor) {
+                todo.push(neighbor);
+            }
+        }
+    }
+    seen.len() == blues.len()
+}
+
+/// This multiverse constructor is for Zone18 together and Zone18 separated. The blues of a
+/// together constraint form a single group of adjacent cells, the ones of a separated constraint
+/// form at least 2 groups.
+/// The output contains one layout per solution
+fn distribute_in_flower(scope_vec: &[Coords], blue_count: usize, together: bool) -> Multiverse {
+    assert!(scope_vec.len() >= blue_count);
+    if together && blue_count <= 1 {
+        return distribute_anywhere(scope_vec, blue_count);
+    }
+    let scope_set: BTreeSet<_> = scope_vec.iter().cloned().collect();
+    let mut layouts = vec![];
+    for blues in scope_vec.iter().cloned().combinations(blue_count) {
+        let blues: BTreeSet<_> = blues.into_iter().collect();
+        if is_connected(&blues) != together {
+            continue;
+        }
+        let blacks: BTreeSet<_> = scope_set.difference(&blues).cloned().collect();
+        let mut map = BTreeMap::new();
+        if !blues.is_empty() {
+            map.insert(blues, blue_count as u16);
+        }
+        if !blacks.is_empty() {
+            map.insert(blacks, 0);
+        }
+        layouts.push(Layout::new(map));
+    }
+    Multiverse::new(scope_set, layouts)
+}
+
+pub fn zone18(defn: &defn::Defn, coords: Coords, modifier: Modifier) -> Multiverse {
+    let (scope, blue_count) = colored_scope(defn::neighbors18_in(defn, coords));
+    match modifier {
+        Modifier::Anywhere => distribute_anywhere(&scope, blue_count),
+        Modifier::Together => distribute_in_flower(&scope, blue_count, true),
+        Modifier::Separated => distribute_in_flower(&scope, blue_count, false),
+    }
+}
+
+/// Constraint of a hint that counts the blues of the second ring around `coords`, i.e. the 12
+/// cells of the 18 neighbors that are not direct neighbors. Not part of the string definition,
+/// for the variants built in code.
+pub fn zone12(defn: &defn::Defn, coords: Coords) -> Multiverse {
+    let (scope, blue_count) = colored_scope(defn::neighbors12_in(defn, coords));
+    distribute_anywhere(&scope, blue_count)
+}
+
+/// The constraint `mv` only holds if the cell at `coords` has `color`, i.e. when `coords` is the
+/// cell of a hidden hint. When it has the other color, the cells of the scope of `mv` are free.
+/// `coords` shouldn't be part of the scope of `mv`.
+pub fn conditional(mv: &Multiverse, coords: Coords, color: Color) -> Multiverse {
+    assert!(!mv.scope.contains(&coords));
+    let (holds, free) = match color {
+        Color::Blue => (1, 0),
+        Color::Black => (0, 1),
+    };
+    let key = BTreeSet::from([coords]);
+    if mv.scope.is_empty() {
+        // Nothing to constrain, `coords` is free
+        return distribute_in_range(&[coords], 0, 1);
+    }
+    let mut scope = mv.scope.clone();
+    scope.insert(coords);
+    let mut layouts = vec![];
+    for lay in &mv.layouts {
+        let mut bc = lay.binomial_coefs.clone();
+        bc.insert(key.clone(), holds);
+        layouts.push(Layout::new(bc));
+    }
+    for blue_count in 0..=mv.scope.len() {
+        let bc = BTreeMap::from([(key.clone(), free), (mv.scope.clone(), blue_count as u16)]);
+        layouts.push(Layout::new(bc));
+    }
+    Multiverse::new(scope, layouts)
+}
+
+/// The ranges spanned by each of the 3 cube coordinates of the cells of `defn`
+fn bounds(defn: &defn::Defn) -> [RangeInclusive<isize>; 3] {
+    let mut min = [isize::MAX; 3];
+    let mut max = [isize::MIN; 3];
+    for c in defn.keys() {
+        for (i, x) in [c.q(), c.r(), c.s()].iter().enumerate() {
+            min[i] = min[i].min(*x);
+            max[i] = max[i].max(*x);
+        }
+    }
+    [min[0]..=max[0], min[1]..=max[1], min[2]..=max[2]]
+}
+
+/// How a line hint treats the holes of its axis, i.e. the positions without a colored cell
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum LineGaps {
+    /// Walk over the holes up to the edge of the grid, the hint counts all the cells of its half
+    /// axis. This is what the base game does.
+    #[default]
+    Skip,
+    /// Stop at the first hole that follows a cell, the hint only counts the contiguous run of
+    /// cells that starts after it. The holes between the hint and that run are still skipped.
+    Stop,
+}
+
+/// Constraint of a line hint with the gaps of the base game, see [line_with_gaps]
+pub fn line(
+    defn: &defn::Defn,
+    coords: Coords,
+    orientation: Orientation,
+    modifier: Modifier,
+) -> Multiverse {
+    line_with_gaps(defn, coords, orientation, modifier, LineGaps::Skip)
+}
+
+/// Constraint of the line hint at `coords`: the cells from `coords` towards `orientation`, up to
+/// the edge of the grid or, depending on `gaps`, up to the first hole.
+pub fn line_with_gaps(
+    defn: &defn::Defn,
+    coords: Coords,
+    orientation: Orientation,
+    modifier: Modifier,
+    gaps: LineGaps,
+) -> Multiverse {
+    let d = orientation.direction();
+    let [q, r, s] = bounds(defn);
+    // Stop when leaving the populated region
+    let in_bounds = |c: &Coords| q.contains(&c.q()) && r.contains(&c.r()) && s.contains(&c.s());
+    let mut scope = Vec::new();
+    let mut blue_count = 0;
+    let mut c = coords;
+    while in_bounds(&c) {
+        let color = defn.get(&c).and_then(defn::color_of_cell);
+        match color {
+            None if gaps == LineGaps::Stop && !scope.is_empty() => break,
+            None => (),
+            Some(Color::Blue) => {
+                blue_count += 1;
+                scope.push(c);
+            }
+            Some(Color::Black) => {
+                scope.push(c);
+            }
+        }
+        c = match c.checked_add(d) {
+            None => break,
+            Some(c) => c,
+        };
+    }
+    match modifier {
+        Modifier::Anywhere => distribute_anywhere(&scope, blue_count),
+        Modifier::Together if scope.is_empty() => Multiverse::empty(),
+        Modifier::Together => distribute_together(&scope, blue_count),
+        Modifier::Separated if blue_count < 2 || scope.len() <= blue_count => {
+            // The blues of a malformed hint can't be separated. Without layouts the constraint
+            // can't be satisfied, which is then reported as a contradiction of the grid.
+            Multiverse::new(scope.into_iter().collect(), vec![])
+        }
+        Modifier::Separated => distribute_separated(&scope, blue_count),
+    }
+}
+
+/// Constraint of a column hint at `coords`: the cells of `defn` that share its `q` coordinate, on
+/// both sides of the hint, hold its number of blues. This differs from a line hint oriented
+/// `Bottom`, which only reads from the hint to the end of the grid and which may have a modifier.
+pub fn column(defn: &defn::Defn, coords: Coords) -> Multiverse {
+    let column = defn.iter().filter(|(c, _)| c.q() == coords.q());
+    let (scope, blue_count) = colored_scope(column.map(|(c, cell)| (*c, cell)));
+    distribute_anywhere(&scope, blue_count)
+}
+
+/// Constraint of a custom hint: `blue_count` of the cells of `scope` are blue.
+/// `scope` must not contain duplicates and `blue_count` must not exceed its length, this function
+/// panics otherwise.
+pub fn custom(scope: &[Coords], blue_count: usize) -> Multiverse {
+    distribute_anywhere(scope, blue_count)
+}
+
+/// Same as [custom] but the blues are consecutive in `scope`, e.g. like the cells of a line.
+/// The order of `scope` matters, the cells don't have to be adjacent on the grid.
+pub fn custom_together(scope: &[Coords], blue_count: usize) -> Multiverse {
+    if scope.is_empty() {
+        return distribute_anywhere(scope, blue_count);
+    }
+    distribute_together(scope, blue_count)
+}
+
+/// Same as [custom] but the blues form at least 2 groups of consecutive cells in `scope`.
+/// Additionally requires `blue_count >= 2` and `blue_count < scope.len()`, without which no
+/// solution exists.
+pub fn custom_separated(scope: &[Coords], blue_count: usize) -> Multiverse {
+    distribute_separated(scope, blue_count)
+}
+
+pub fn global_blue_count(defn: &defn::Defn) -> Multiverse {
+    let mut scope = Vec::new();
+    let mut blue_count = 0;
+    for (c, cell) in defn {
+        match defn::color_of_cell(cell) {
+            None => (),
+            Some(Color::Blue) => {
+                blue_count += 1;
+                scope.push(*c);
+            }
+            Some(Color::Black) => {
+                scope.push(*c);
+            }
+        }
+    }
+    distribute_anywhere(&scope, blue_count)
+}
+
+/// The constraint of the hint at `coords`, `None` if there is no hint at `coords`
+fn of_hint(defn: &defn::Defn, coords: Coords, gaps: LineGaps) -> Option<Multiverse> {
+    match defn.get(&coords)? {
+        defn::Cell::Line { o, m } => Some(line_with_gaps(defn, coords, *o, *m, gaps)),
+        defn::Cell::Column => Some(column(defn, coords)),
+        defn::Cell::Zone6 { m, .. } => Some(zone6(defn, coords, *m)),
+        defn::Cell::Zone18 { m, .. } => Some(zone18(defn, coords, *m)),
+        defn::Cell::Empty | defn::Cell::Zone0 { .. } => None,
+    }
+}
+
+/// The cells that the hint at `coords` constrains, i.e. the scope of its constraint, the lines
+/// having the gaps of the base game. `None` if there is no hint at `coords`.
+pub fn scope_of(defn: &defn::Defn, coords: Coords) -> Option<BTreeSet<Coords>> {
+    of_hint(defn, coords, LineGaps::default()).map(|mv| mv.scope)
+}
+
+/// The constraints of a puzzle, the ones of the solver, see [all]
+#[derive(Debug, Clone)]
+pub struct AllConstraints {
+    /// The hints shown from the start keyed by their coords, i.e. the lines, the columns and the
+    /// revealed zones
+    pub visible: BTreeMap<Coords, Multiverse>,
+    /// The zones that are not revealed yet keyed by their coords. Such a hint only holds once its
+    /// cell turns out to be blue, see [conditional].
+    pub hidden: BTreeMap<Coords, Multiverse>,
+    /// The count of blue cells of the whole grid, see [global_blue_count]
+    pub global: Multiverse,
+}
+
+/// The constraints of all the hints of `defn`, the lines having the gaps of the base game, see
+/// [all_with_gaps]
+pub fn all(defn: &defn::Defn) -> AllConstraints {
+    all_with_gaps(defn, LineGaps::default())
+}
+
+/// The constraints of all the hints of `defn`, split between the visible and the hidden ones,
+/// along with the global one. These are the constraints of the solver.
+pub fn all_with_gaps(defn: &defn::Defn, gaps: LineGaps) -> AllConstraints {
+    let mut visible = BTreeMap::new();
+    let mut hidden = BTreeMap::new();
+    for (coords, cell) in defn::constraint_cells(defn) {
+        let mv = of_hint(defn, coords, gaps).expect("Unreachable");
+        match cell {
+            defn::Cell::Zone6 {
+                revealed: false, ..
+            }
+            | defn::Cell::Zone18 {
+                revealed: false, ..
+            } => hidden.insert(coords, mv),
+            _ => visible.insert(coords, mv),
+        };
+    }
+    AllConstraints {
+        visible,
+        hidden,
+        global: global_blue_count(defn),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use misc;
+    use env;
+    use env::Env;
+    use misc::Direction;
     use multiverse::State;
+    use std::time::{Duration, Instant};
 
-    fn nk(n: u64, k: u64) -> u64 {
+    fn nk(n: u64, k: u64) -> u128 {
         misc::n_choose_k(n, k).unwrap()
     }
 
     fn mock_zone6_anywhere(center: &Coords, blue_count: usize) -> Multiverse {
-        distribute_anywhere(&center.neighbors6().iter().cloned().collect(), blue_count)
+        distribute_anywhere(&center.neighbors6(), blue_count)
     }
 
-    fn mock_line_together(topmost: &Coords, cell_count: usize, blue_count: usize) -> Multiverse {
-        // Towards down
-        let mut scope_vec = vec![];
-        for i in 0..(cell_count as isize) {
-            scope_vec.push(Coords::new(
-                topmost.q() + 0 * i,
-                topmost.r() + 1 * i,
-                topmost.s() - 1 * i,
-            ))
+    #[test]
+    pub fn test_zone18_modifiers() {
+        // The 18 cells around the center have 6 + 12 + 18 pairs of adjacent cells
+        let scope = Coords::new(0, 0, 0).neighbors18();
+        let together = distribute_in_flower(&scope, 2, true);
+        assert_eq!(together.solution_count_exact(), Some(36));
+        let separated = distribute_in_flower(&scope, 2, false);
+        assert_eq!(separated.solution_count_exact(), Some(nk(18, 2) - 36));
+        let together = distribute_in_flower(&scope, 1, true);
+        assert_eq!(together.solution_count_exact(), Some(18));
+        let separated = distribute_in_flower(&scope, 1, false);
+        assert_eq!(separated.solution_count_exact(), Some(0));
+        let together = distribute_in_flower(&scope, 18, true);
+        assert_eq!(together.solution_count_exact(), Some(1));
+    }
+
+    #[test]
+    pub fn test_scope_of() {
+        let defn = defn::of_string(include_str!("../extra/the_trial.txt")).unwrap();
+        for (coords, cell) in &defn {
+            let scope = scope_of(&defn, *coords);
+            match cell {
+                defn::Cell::Zone6 { .. } => {
+                    let expected: BTreeSet<_> = coords
+                        .neighbors6()
+                        .iter()
+                        .filter(|c| defn.get(c).and_then(defn::color_of_cell).is_some())
+                        .cloned()
+                        .collect();
+                    assert_eq!(scope, Some(expected));
+                }
+                defn::Cell::Zone18 { .. } => {
+                    let scope = scope.unwrap();
+                    assert!(scope.iter().all(|c| coords.distance(c) <= 2));
+                }
+                defn::Cell::Line { o, .. } => {
+                    let scope = scope.unwrap();
+                    assert!(!scope.is_empty());
+                    let d = o.direction();
+                    // Each cell is `distance` steps away from the hint towards `o`
+                    assert!(scope
+                        .iter()
+                        .all(|c| (0..coords.distance(c)).fold(*coords, |at, _| at + d) == *c));
+                }
+                defn::Cell::Zone0 { .. } => assert_eq!(scope, None),
+                _ => (),
+            }
+        }
+        assert_eq!(scope_of(&defn, Coords::new(100, -50, -50)), None);
+    }
+
+    #[test]
+    pub fn test_all() {
+        let defn = defn::of_string(include_str!("../extra/the_trial.txt")).unwrap();
+        let constraints = all(&defn);
+        let hints: BTreeSet<_> = defn::constraint_cells(&defn).map(|(c, _)| c).collect();
+        let keys = |map: &BTreeMap<Coords, Multiverse>| map.keys().cloned().collect();
+        let visible: BTreeSet<_> = keys(&constraints.visible);
+        let hidden: BTreeSet<_> = keys(&constraints.hidden);
+        assert!(visible.is_disjoint(&hidden));
+        assert_eq!(
+            visible.union(&hidden).cloned().collect::<BTreeSet<_>>(),
+            hints
+        );
+        assert!(!hidden.is_empty());
+        for coords in &visible {
+            let cell = &defn[coords];
+            assert!(
+                defn::is_revealed(cell)
+                    || matches!(cell, defn::Cell::Line { .. } | defn::Cell::Column)
+            );
+        }
+        for coords in &hidden {
+            assert!(!defn::is_revealed(&defn[coords]));
+        }
+        // The colors of the grid satisfy all of them
+        let colors: BTreeMap<_, _> = defn
+            .iter()
+            .filter_map(|(c, cell)| defn::color_of_cell(cell).map(|color| (*c, color)))
+            .collect();
+        for (coords, mv) in constraints.visible.iter().chain(&constraints.hidden) {
+            assert_eq!(Some(&mv.scope), scope_of(&defn, *coords).as_ref());
+            assert!(mv.is_consistent(&colors));
+        }
+        assert_eq!(constraints.global.scope.len(), colors.len());
+        assert!(constraints.global.is_consistent(&colors));
+    }
+
+    #[test]
+    pub fn test_zone12() {
+        let center = Coords::new(0, 0, 0);
+        let [outer0, outer1, outer2] = [0, 1, 5].map(|i| center.ring(2)[i]);
+        let zone0 = |color| defn::Cell::Zone0 {
+            revealed: false,
+            color,
+        };
+        let defn = defn::DefnBuilder::new()
+            .place(outer0, zone0(Color::Blue))
+            .place(outer1, zone0(Color::Black))
+            .place(outer2, zone0(Color::Blue))
+            .place(center.neighbors6()[0], zone0(Color::Blue))
+            .build()
+            .unwrap();
+        let mv = zone12(&defn, center);
+        assert_eq!(mv.scope, BTreeSet::from([outer0, outer1, outer2]));
+        assert_eq!(mv.solution_count_exact(), Some(nk(3, 2)));
+    }
+
+    #[test]
+    pub fn test_zones_at_the_edge() {
+        // Only the top and top-left neighbors of the bottom-right corner of the coords exist, they
+        // are adjacent across the end of the ring
+        let max = i16::MAX as isize;
+        let corner = Coords::new(max, max, -2 * max);
+        let [top, top_left] = [Direction::Top, Direction::TopLeft]
+            .map(|direction| corner.checked_add(direction.offset()).unwrap());
+        let zone0 = defn::Cell::Zone0 {
+            revealed: false,
+            color: Color::Blue,
+        };
+        let defn = defn::DefnBuilder::new()
+            .place(top, zone0)
+            .place(top_left, zone0)
+            .build()
+            .unwrap();
+        let mv = zone6(&defn, corner, Modifier::Together);
+        assert_eq!(mv.scope, BTreeSet::from([top, top_left]));
+        assert_eq!(mv.solution_count_exact(), Some(1));
+        assert_eq!(
+            zone6(&defn, corner, Modifier::Separated).state(),
+            State::Stuck
+        );
+        let mv = zone18(&defn, corner, Modifier::Together);
+        assert_eq!(mv.scope, BTreeSet::from([top, top_left]));
+        assert_eq!(mv.solution_count_exact(), Some(1));
+    }
+
+    #[test]
+    pub fn test_conditional() {
+        let scope = [Coords::new(0, 1, -1), Coords::new(0, 2, -2)];
+        let mv = distribute_anywhere(&scope, 1);
+        let c = Coords::new(0, 0, 0);
+        // 2 solutions when `c` is black, 4 when it is blue
+        let cond = conditional(&mv, c, Color::Black);
+        assert_eq!(cond.solution_count_exact(), Some(6));
+        assert_eq!(cond.learn(&c, Color::Black).solution_count_exact(), Some(2));
+        let cond = conditional(&Multiverse::empty(), c, Color::Blue);
+        assert_eq!(cond.solution_count_exact(), Some(2));
+    }
+
+    #[test]
+    pub fn test_line_gaps() {
+        // Below the hint: a hole, 2 cells, a hole and a last cell
+        let (o, m) = (Orientation::Bottom, Modifier::Together);
+        let zone0 = defn::Cell::Zone0 {
+            revealed: false,
+            color: Color::Blue,
+        };
+        let column = |i| Coords::new(0, i, -i);
+        let defn = defn::DefnBuilder::new()
+            .place(column(0), defn::Cell::Line { o, m })
+            .place(column(2), zone0)
+            .place(column(3), zone0)
+            .place(column(5), zone0)
+            .build()
+            .unwrap();
+        let mv = line(&defn, column(0), o, m);
+        assert_eq!(mv.scope, BTreeSet::from([column(2), column(3), column(5)]));
+        let mv = line_with_gaps(&defn, column(0), o, m, LineGaps::Stop);
+        assert_eq!(mv.scope, BTreeSet::from([column(2), column(3)]));
+        assert_eq!(mv.solution_count_exact(), Some(1));
+    }
+
+    #[test]
+    pub fn test_long_line() {
+        // A column of 40 blue cells below its hint, that is longer than the standard grid
+        let (o, m) = (Orientation::Bottom, Modifier::Anywhere);
+        let mut builder =
+            defn::DefnBuilder::new().place(Coords::new(0, 0, 0), defn::Cell::Line { o, m });
+        for i in 1..=40 {
+            let cell = defn::Cell::Zone0 {
+                revealed: false,
+                color: Color::Blue,
+            };
+            builder = builder.place(Coords::new(0, i, -i), cell);
         }
-        distribute_together(&scope_vec, blue_count)
+        let defn = builder.build().unwrap();
+        let mv = line(&defn, Coords::new(0, 0, 0), o, m);
+        assert_eq!(mv.scope.len(), 40);
+        assert_eq!(mv.solution_count_exact(), Some(1));
+    }
+
+    /// `cell_count` cells towards down, starting at `topmost`
+    fn column_below(topmost: &Coords, cell_count: usize) -> Vec<Coords> {
+        (0..cell_count as isize)
+            .map(|i| Coords::new(topmost.q(), topmost.r() + i, topmost.s() - i))
+            .collect()
+    }
+
+    fn mock_line_together(topmost: &Coords, cell_count: usize, blue_count: usize) -> Multiverse {
+        distribute_together(&column_below(topmost, cell_count), blue_count)
     }
 
     fn mock_ring_together(center: &Coords, blue_count: usize) -> Multiverse {
-        distribute_in_ring(
-            &center.neighbors6().map(|coords| (coords, false)),
-            blue_count,
-            true,
-        )
+        distribute_in_ring(&center.checked_neighbors6(), blue_count, true)
     }
 
     fn mock_line_separated(topmost: &Coords, cell_count: usize, blue_count: usize) -> Multiverse {
-        // Towards down
-        let mut scope_vec = vec![];
-        for i in 0..(cell_count as isize) {
-            scope_vec.push(Coords::new(
-                topmost.q() + 0 * i,
-                topmost.r() + 1 * i,
-                topmost.s() - 1 * i,
-            ))
-        }
-        distribute_separated(&scope_vec, blue_count)
+        distribute_separated(&column_below(topmost, cell_count), blue_count)
     }
 
     fn mock_ring_separated(center: &Coords, blue_count: usize) -> Multiverse {
-        distribute_in_ring(
-            &center.neighbors6().map(|coords| (coords, false)),
-            blue_count,
-            false,
-        )
+        distribute_in_ring(&center.checked_neighbors6(), blue_count, false)
+    }
+
+    /// Check `mv.invariants()` against the invariants found by enumerating the solutions
+    fn check_invariants(mv: &Multiverse) {
+        let mut blue_for_sure = mv.scope.clone();
+        let mut black_for_sure = mv.scope.clone();
+        for solution in mv.solutions().unwrap() {
+            assert_eq!(solution.len(), mv.scope.len());
+            for (coords, color) in solution {
+                match color {
+                    Color::Blue => black_for_sure.remove(&coords),
+                    Color::Black => blue_for_sure.remove(&coords),
+                };
+            }
+        }
+        let mut expected = BTreeMap::new();
+        expected.extend(blue_for_sure.into_iter().map(|c| (c, Color::Blue)));
+        expected.extend(black_for_sure.into_iter().map(|c| (c, Color::Black)));
+        assert_eq!(expected, mv.invariants());
     }
 
     fn test_two_zone6_horizontal_neighbors(
         blue_count_left: usize,
         blue_count_right: usize,
         invariant_count: usize,
-        solution_count: u64,
+        solution_count: u128,
     ) {
         // Horizontal neighbors are not direct neighbors. They share 2 direct neighbors.
         let mv0 = mock_zone6_anywhere(&Coords::new(0, 0, 0), blue_count_left);
@@ -280,7 +799,9 @@ mod tests {
             mv1.solution_count_upper_bound().unwrap()
         );
         assert_eq!(solution_count, mv.solution_count_upper_bound().unwrap());
+        assert_eq!(solution_count, mv.solution_count_exact().unwrap());
         assert_eq!(invariants.len(), invariant_count);
+        check_invariants(&mv);
     }
 
     #[test]
@@ -354,7 +875,8 @@ mod tests {
 
         // A line of len 4 with 2 separated blues
         let mv0 = mock_line_separated(&Coords::new(0, 0, 0), 4, 2);
-        assert_eq!(4, mv0.solution_count_upper_bound().unwrap()); // Reality is 3 but the algorithm produced overlapping layouts
+        assert_eq!(3, mv0.solution_count_upper_bound().unwrap());
+        assert_eq!(3, mv0.solution_count_exact().unwrap());
         assert_eq!(0, mv0.invariants().len());
 
         // A line of len 4 with 3 separated blues
@@ -364,13 +886,15 @@ mod tests {
 
         // A line of len 5 with 3 separated blues
         let mv0 = mock_line_separated(&Coords::new(0, 0, 0), 5, 3);
-        assert_eq!(10, mv0.solution_count_upper_bound().unwrap()); // Reality is 7 but the algorithm produced overlapping layouts
+        assert_eq!(7, mv0.solution_count_upper_bound().unwrap());
+        assert_eq!(7, mv0.solution_count_exact().unwrap());
         assert_eq!(0, mv0.invariants().len());
 
         // A black circle intersecting on the middle cell and the one below
         let mv1 = mock_zone6_anywhere(&Coords::new(-1, 3, -2), 0);
         let mv = mv0.merge(&mv1);
-        assert_eq!(2, mv.solution_count_upper_bound().unwrap()); // Reality is 1 but the algorithm produced overlapping layouts
+        assert_eq!(1, mv.solution_count_upper_bound().unwrap());
+        assert_eq!(1, mv.solution_count_exact().unwrap());
         assert_eq!(9, mv.invariants().len());
 
         // A blue circle intersecting on the middle cell and the one below
@@ -443,21 +967,71 @@ mod tests {
         assert_eq!(4, mv.invariants().len());
     }
 
+    #[test]
+    pub fn test_ring_gaps() {
+        // The ring around the origin without the cells at `gaps`
+        let ring = |gaps: &[usize]| {
+            let mut ring = Coords::new(0, 0, 0).checked_neighbors6();
+            for i in gaps {
+                ring[*i] = None;
+            }
+            ring
+        };
+        let count = |gaps: &[usize], blue_count, together| {
+            let mv = distribute_in_ring(&ring(gaps), blue_count, together);
+            assert_eq!(mv.scope.len(), 6 - gaps.len());
+            if mv.state() == State::Stuck {
+                0
+            } else {
+                mv.solution_count_upper_bound().unwrap()
+            }
+        };
+
+        // One gap turns the ring into a line of 5 cells
+        assert_eq!(count(&[0], 2, true), 4);
+        assert_eq!(count(&[0], 4, true), 2);
+        assert_eq!(count(&[0], 5, true), 1);
+        assert_eq!(count(&[0], 2, false), 6);
+        assert_eq!(count(&[0], 3, false), 7);
+        assert_eq!(count(&[0], 4, false), 3);
+        assert_eq!(count(&[0], 5, false), 0);
+
+        // Two gaps split it in 2 lines of 1 and 3 cells, or of 2 and 2 cells
+        assert_eq!(count(&[0, 2], 2, true), 2);
+        assert_eq!(count(&[0, 2], 3, true), 1);
+        assert_eq!(count(&[0, 2], 4, true), 0);
+        assert_eq!(count(&[0, 2], 2, false), 4);
+        assert_eq!(count(&[0, 2], 4, false), 1);
+        assert_eq!(count(&[1, 4], 2, true), 2);
+        assert_eq!(count(&[1, 4], 3, true), 0);
+        assert_eq!(count(&[1, 4], 4, true), 0);
+        assert_eq!(count(&[1, 4], 3, false), 4);
+        assert_eq!(count(&[1, 4], 4, false), 1);
+
+        // Less than 2 blues can't be separated
+        for gaps in [&[][..], &[0], &[1, 4]] {
+            assert_eq!(count(gaps, 0, false), 0);
+            assert_eq!(count(gaps, 1, false), 0);
+            assert_eq!(count(gaps, 0, true), 1);
+            assert_eq!(count(gaps, 1, true), 6 - gaps.len() as u128);
+        }
+    }
+
     #[test]
     pub fn test_multiverse_edge_cases() {
         // Flavors of empty
         let empty = Multiverse::empty();
         assert_eq!(empty.state(), State::Empty);
         assert_eq!(0, empty.solution_count_upper_bound().unwrap());
-        assert!(empty.invariants().len() == 0);
+        assert!(empty.invariants().is_empty());
         let empty = Multiverse::new(BTreeSet::new(), vec![]);
         assert_eq!(empty.state(), State::Empty);
         assert_eq!(0, empty.solution_count_upper_bound().unwrap());
-        assert!(empty.invariants().len() == 0);
+        assert!(empty.invariants().is_empty());
         let empty = empty.merge(&empty);
         assert_eq!(empty.state(), State::Empty);
         assert_eq!(0, empty.solution_count_upper_bound().unwrap());
-        assert!(empty.invariants().len() == 0);
+        assert!(empty.invariants().is_empty());
 
         // Intersection with empty
         let c = Coords::new(0, 0, 0);
@@ -465,7 +1039,7 @@ mod tests {
         let running = empty.merge(&running);
         assert_eq!(running.state(), State::Running);
         assert_eq!(nk(6, 3), running.solution_count_upper_bound().unwrap());
-        assert!(running.invariants().len() == 0);
+        assert!(running.invariants().is_empty());
 
         // Stuck
         let stuck = Multiverse::new(BTreeSet::from([c]), vec![]);
@@ -479,101 +1053,343 @@ mod tests {
         let mv = running2.merge(&running);
         assert_eq!(mv.state(), State::Running);
         assert_eq!(nk(6, 3).pow(2), mv.solution_count_upper_bound().unwrap());
-        assert!(mv.invariants().len() == 0);
+        assert!(mv.invariants().is_empty());
     }
-}
 
-pub fn zone6(defn: &defn::Defn, coords: Coords, modifier: Modifier) -> Multiverse {
-    let mut blue_count = 0;
-    let neighborhood = coords.neighbors6();
-    let scope_arr = neighborhood.map(|c| match defn.get(&c).and_then(defn::color_of_cell) {
-        None => (c, true),
-        Some(Color::Blue) => {
-            blue_count += 1;
-            (c, false)
-        }
-        Some(Color::Black) => (c, false),
-    });
-    match modifier {
-        Modifier::Anywhere => {
-            let scope = scope_arr
+    #[test]
+    pub fn test_custom() {
+        let scope: Vec<_> = (0..4).map(|i| Coords::new(i, 0, -i)).collect();
+        assert_eq!(
+            custom(&scope, 2).solution_count_upper_bound(),
+            Some(nk(4, 2))
+        );
+        assert_eq!(custom_together(&scope, 2).solution_count_exact(), Some(3));
+        assert_eq!(custom_separated(&scope, 2).solution_count_exact(), Some(3));
+        // Only the order of the scope matters
+        let shuffled = [scope[2], scope[0], scope[3], scope[1]];
+        let mv = custom_together(&shuffled, 4);
+        assert_eq!(mv.solution_count_exact(), Some(1));
+        assert_eq!(custom_together(&[], 0).state(), State::Empty);
+    }
+
+    #[test]
+    pub fn test_serde_multiverse() {
+        let line: Vec<_> = (0..4).map(|i| Coords::new(i, 0, -i)).collect();
+        let mv = distribute_separated(&line, 2);
+        let json = serde_json::to_string(&mv).unwrap();
+        assert!(json.contains(r#"{"cells":[{"q":0,"r":0}],"blue_count":"#));
+        let mv2: Multiverse = serde_json::from_str(&json).unwrap();
+        assert_eq!(mv2.scope, mv.scope);
+        assert_eq!(mv2.layouts, mv.layouts);
+
+        // Invalid layouts are rejected instead of panicking
+        let bad_count =
+            r#"{"scope":[{"q":0,"r":0}],"layouts":[[{"cells":[{"q":0,"r":0}],"blue_count":2}]]}"#;
+        assert!(serde_json::from_str::<Multiverse>(bad_count).is_err());
+        let bad_scope =
+            r#"{"scope":[{"q":1,"r":0}],"layouts":[[{"cells":[{"q":0,"r":0}],"blue_count":1}]]}"#;
+        assert!(serde_json::from_str::<Multiverse>(bad_scope).is_err());
+    }
+
+    #[test]
+    pub fn test_bounds() {
+        let c = Coords::new(0, 0, 0);
+        let mv = mock_zone6_anywhere(&c, 2);
+        let scope: Vec<_> = mv.scope_iter().cloned().collect();
+        assert!(scope
+            .windows(2)
+            .all(|w| (w[0].q(), w[0].r()) < (w[1].q(), w[1].r())));
+        assert_eq!(
+            mv.bounds(),
+            Some((Coords::new(-1, -1, 2), Coords::new(1, 1, -2)))
+        );
+        assert_eq!(Multiverse::empty().bounds(), None);
+    }
+
+    #[test]
+    pub fn test_project() {
+        let c = Coords::new(0, 0, 0);
+        let line = (0..4).map(|i| Coords::new(i, 0, -i)).collect::<Vec<_>>();
+        let mv = mock_zone6_anywhere(&c, 2).merge(&distribute_together(&line, 3));
+        let invariants = mv.invariants();
+        assert!(!invariants.is_empty());
+        let mut cells = mv.scope.iter().cloned();
+        for len in [1, 2, 3, mv.scope.len()] {
+            let subset: BTreeSet<_> = cells.by_ref().take(len).collect();
+            let projection = mv.project(&subset);
+            assert_eq!(projection.scope, subset);
+            let expected: BTreeMap<_, _> = invariants
                 .iter()
-                .filter_map(|(c, is_gap)| if *is_gap { None } else { Some(*c) })
+                .filter(|(coords, _)| subset.contains(coords))
+                .map(|(coords, color)| (*coords, *color))
                 .collect();
-            distribute_anywhere(&scope, blue_count)
+            assert_eq!(projection.invariants(), expected);
+            assert!(projection.solution_count_exact().unwrap() <= 1 << len);
         }
-        Modifier::Together => distribute_in_ring(&scope_arr, blue_count, true),
-        Modifier::Separated => distribute_in_ring(&scope_arr, blue_count, false),
+        assert_eq!(mv.project(&BTreeSet::new()).state(), State::Empty);
     }
-}
 
-pub fn zone18(defn: &defn::Defn, coords: Coords) -> Multiverse {
-    let mut scope = Vec::new();
-    let mut blue_count = 0;
-    for c in coords.neighbors18() {
-        match defn.get(&c).and_then(defn::color_of_cell) {
-            None => (),
-            Some(Color::Blue) => {
-                blue_count += 1;
-                scope.push(c);
-            }
-            Some(Color::Black) => {
-                scope.push(c);
-            }
-        }
+    #[test]
+    pub fn test_merge_checked() {
+        let mv0 = mock_zone6_anywhere(&Coords::new(0, 0, 0), 3);
+        let mv1 = mock_zone6_anywhere(&Coords::new(1, 0, -1), 2);
+        let env = Env::new(60);
+        let mv = mv0.merge_checked(&mv1, &env).unwrap();
+        assert_eq!(
+            mv.solution_count_upper_bound(),
+            mv0.merge(&mv1).solution_count_upper_bound()
+        );
+        // Merging with an empty multiverse has no cross-product to interrupt
+        let empty = Multiverse::empty();
+        env.cancel();
+        assert!(empty.merge_checked(&mv0, &env).is_ok());
+        let err = mv0.merge_checked(&mv1, &env).unwrap_err();
+        assert!(err.is::<env::Cancelled>());
+        let err = mv0.merge_checked(&mv1, &Env::new(0)).unwrap_err();
+        assert!(err.is::<env::Timeout>());
+        let mut env = Env::with_deadline(Instant::now());
+        env.reset_timer();
+        let err = mv0.merge_checked(&mv1, &env).unwrap_err();
+        assert!(err.is::<env::Timeout>());
+        let env = Env::with_deadline(Instant::now() + Duration::from_secs(60));
+        assert!(mv0.merge_checked(&mv1, &env).is_ok());
     }
-    distribute_anywhere(&scope, blue_count)
-}
 
-pub fn line(
-    defn: &defn::Defn,
-    coords: Coords,
-    orientation: Orientation,
-    modifier: Modifier,
-) -> Multiverse {
-    let (dq, dr, ds) = match orientation {
-        Orientation::Bottom => (0, 1, -1),
-        Orientation::BottomRight => (1, 0, -1),
-        Orientation::BottomLeft => (-1, 1, 0),
-    };
-    let (q, r, s) = (coords.q(), coords.r(), coords.s());
-    let mut scope = Vec::new();
-    let mut blue_count = 0;
-    for i in 0..33 {
-        // 33 is more than the max diagonal len of a grid
-        let c = Coords::new(q + dq * i, r + dr * i, s + ds * i);
-        match defn.get(&c).and_then(defn::color_of_cell) {
-            None => (),
-            Some(Color::Blue) => {
-                blue_count += 1;
-                scope.push(c);
-            }
-            Some(Color::Black) => {
-                scope.push(c);
+    #[test]
+    pub fn test_layout_cap() {
+        // Aligning the 8 cells with their first 4 forks the layout of `mv0` in 5
+        let line: Vec<_> = (0..8).map(|i| Coords::new(i, 0, -i)).collect();
+        let mv0 = distribute_anywhere(&line, 4);
+        let mv1 = distribute_anywhere(&line[..4], 2);
+        let env = Env::new(60).with_layout_cap(5);
+        let mv = mv0.merge_checked(&mv1, &env).unwrap();
+        assert_eq!(
+            mv.solution_count_exact(),
+            mv0.merge(&mv1).solution_count_exact()
+        );
+        let env = Env::new(60).with_layout_cap(4);
+        let err = mv0.merge_checked(&mv1, &env).unwrap_err();
+        assert!(err.is::<env::TooManyLayouts>());
+        // Sticky
+        assert!(env.check_abort().unwrap_err().is::<env::TooManyLayouts>());
+    }
+
+    #[test]
+    pub fn test_merge_all() {
+        let centers = [
+            Coords::new(0, 0, 0),
+            Coords::new(1, 0, -1),
+            Coords::new(5, 0, -5),
+            Coords::new(0, 1, -1),
+        ];
+        let mvs: Vec<_> = centers
+            .iter()
+            .zip([3, 2, 1, 4])
+            .map(|(center, blue_count)| mock_zone6_anywhere(center, blue_count))
+            .collect();
+        let fold = mvs
+            .iter()
+            .fold(Multiverse::empty(), |mv0, mv1| mv0.merge(mv1));
+        let mv = Multiverse::merge_all(&mvs);
+        assert_eq!(mv.scope, fold.scope);
+        assert_eq!(mv.solution_count_exact(), fold.solution_count_exact());
+        let mv = Multiverse::merge_all_checked(mvs.iter().rev(), &Env::new(60)).unwrap();
+        assert_eq!(mv.solution_count_exact(), fold.solution_count_exact());
+        let err = Multiverse::merge_all_checked(&mvs, &Env::new(0)).unwrap_err();
+        assert!(err.is::<env::Timeout>());
+        assert_eq!(Multiverse::merge_all(&[]).state(), State::Empty);
+        assert_eq!(
+            Multiverse::merge_all(vec![mvs[0].clone()]).solution_count_exact(),
+            mvs[0].solution_count_exact()
+        );
+    }
+
+    #[test]
+    pub fn test_dedup_layouts() {
+        let a = Coords::new(0, 0, 0);
+        let b = Coords::new(1, 0, -1);
+        let scope = BTreeSet::from([a, b]);
+        let a_blue = Layout::new(BTreeMap::from([
+            (BTreeSet::from([a]), 1),
+            (BTreeSet::from([b]), 0),
+        ]));
+        let one_blue = Layout::new(BTreeMap::from([(scope.clone(), 1)]));
+        // Two overlapping layouts, both of them agree with `mv1`
+        let mv0 = Multiverse::new(scope.clone(), vec![a_blue.clone(), one_blue]);
+        let mv1 = Multiverse::new(scope.clone(), vec![a_blue.clone()]);
+        let mv = mv0.merge(&mv1);
+        assert_eq!(mv.layouts, vec![a_blue.clone()]);
+        let mv = Multiverse::new(scope, vec![a_blue.clone(), a_blue]);
+        assert_eq!(mv.layouts.len(), 1);
+    }
+
+    #[test]
+    pub fn test_solutions() {
+        let c = Coords::new(0, 0, 0);
+        let mvs = [
+            mock_line_together(&c, 5, 3),
+            mock_line_separated(&c, 5, 3),
+            mock_line_separated(&c, 6, 2),
+            mock_ring_together(&c, 2),
+            mock_ring_separated(&c, 3),
+            mock_line_together(&c, 5, 3).merge(&mock_ring_together(&Coords::new(-1, 3, -2), 4)),
+            mock_line_separated(&c, 5, 3).merge(&mock_zone6_anywhere(&Coords::new(-1, 3, -2), 6)),
+        ];
+        for mv in &mvs {
+            let solutions: Vec<_> = mv.solutions().unwrap().collect();
+            assert_eq!(solutions.len() as u128, mv.solution_count_exact().unwrap());
+            check_invariants(mv);
+
+            // Marginals
+            let marginals = mv.marginals();
+            assert_eq!(marginals.len(), mv.scope.len());
+            for (coords, p) in marginals {
+                let blue_count = solutions
+                    .iter()
+                    .filter(|solution| solution[&coords] == Color::Blue)
+                    .count();
+                let expected = blue_count as f64 / solutions.len() as f64;
+                assert!((p - expected).abs() < 1e-9);
+                match mv.invariants().get(&coords) {
+                    None => assert!(p > 0.0 && p < 1.0),
+                    Some(Color::Blue) => assert_eq!(p, 1.0),
+                    Some(Color::Black) => assert_eq!(p, 0.0),
+                }
             }
         }
+
+        // Too big to be enumerated
+        let scope: Vec<_> = (0..40).map(|i| Coords::new(i, -i, 0)).collect();
+        let mv = distribute_anywhere(&scope, 20);
+        assert!(mv.solutions().is_none());
+        assert!(mv.solution_count_exact().is_some());
     }
-    match modifier {
-        Modifier::Anywhere => distribute_anywhere(&scope, blue_count),
-        Modifier::Together => distribute_together(&scope, blue_count),
-        Modifier::Separated => distribute_separated(&scope, blue_count),
+
+    #[test]
+    pub fn test_inequalities() {
+        let scope: Vec<_> = (0..5).map(|i| Coords::new(i, -i, 0)).collect();
+        for n in 0..=5 {
+            let mv = distribute_at_least(&scope, n);
+            let expected: u128 = (n..=5).map(|k| nk(5, k as u64)).sum();
+            assert_eq!(expected, mv.solution_count_exact().unwrap());
+            assert_eq!(expected, mv.solution_count_upper_bound().unwrap());
+            check_invariants(&mv);
+
+            let mv = distribute_at_most(&scope, n);
+            let expected: u128 = (0..=n).map(|k| nk(5, k as u64)).sum();
+            assert_eq!(expected, mv.solution_count_exact().unwrap());
+            check_invariants(&mv);
+        }
+        assert!(distribute_at_least(&scope, 0).invariants().is_empty());
+        assert_eq!(distribute_at_most(&scope, 7).layouts.len(), 6);
+        assert!(distribute_at_most(&scope, 0)
+            .invariants()
+            .values()
+            .all(|color| *color == Color::Black));
+        assert!(distribute_at_least(&scope, 5)
+            .invariants()
+            .values()
+            .all(|color| *color == Color::Blue));
+
+        // At least 2 blues in the line but only one in its first 2 cells
+        let mv0 = distribute_at_least(&scope[..3], 2);
+        let mv1 = distribute_anywhere(&scope[..2], 1);
+        let mv = mv0.merge(&mv1);
+        check_invariants(&mv);
+        assert_eq!(mv.invariants(), BTreeMap::from([(scope[2], Color::Blue)]));
+
+        // At most 1 blue in the line, and 1 blue in its first 2 cells
+        let mv0 = distribute_at_most(&scope, 1);
+        let mv1 = distribute_anywhere(&scope[..2], 1);
+        let mv = mv0.merge(&mv1);
+        check_invariants(&mv);
+        assert_eq!(
+            mv.invariants(),
+            BTreeMap::from([
+                (scope[2], Color::Black),
+                (scope[3], Color::Black),
+                (scope[4], Color::Black),
+            ])
+        );
     }
-}
 
-pub fn global_blue_count(defn: &defn::Defn) -> Multiverse {
-    let mut scope = Vec::new();
-    let mut blue_count = 0;
-    for (c, cell) in defn {
-        match defn::color_of_cell(cell) {
-            None => (),
-            Some(Color::Blue) => {
-                blue_count += 1;
-                scope.push(*c);
-            }
-            Some(Color::Black) => {
-                scope.push(*c);
+    #[test]
+    pub fn test_learn_many() {
+        let c = Coords::new(0, 0, 0);
+        let mvs = [
+            mock_line_together(&c, 5, 3),
+            mock_line_separated(&c, 5, 3),
+            mock_line_separated(&c, 6, 2),
+            mock_ring_together(&c, 2),
+            mock_ring_separated(&c, 3),
+            mock_line_separated(&c, 5, 3).merge(&mock_zone6_anywhere(&Coords::new(-1, 3, -2), 6)),
+        ];
+        for mv in &mvs {
+            // Learn from each solution, a growing number of cells
+            for solution in mv.solutions().unwrap() {
+                for n in 1..=solution.len() {
+                    let findings: BTreeMap<_, _> = solution
+                        .iter()
+                        .take(n)
+                        .map(|(coords, color)| (*coords, *color))
+                        .collect();
+                    let batched = mv.learn_many(&findings);
+                    let folded = findings
+                        .iter()
+                        .fold(mv.clone(), |mv, (coords, color)| mv.learn(coords, *color));
+                    assert_eq!(batched.scope, folded.scope);
+                    assert_eq!(batched.state(), folded.state());
+                    assert_eq!(
+                        batched.solution_count_upper_bound(),
+                        folded.solution_count_upper_bound()
+                    );
+                    assert_eq!(
+                        batched.solutions().unwrap().collect::<Vec<_>>(),
+                        folded.solutions().unwrap().collect::<Vec<_>>()
+                    );
+                    assert_eq!(batched.invariants(), folded.invariants());
+                }
             }
+
+            // Contradicting findings
+            let findings: BTreeMap<_, _> = mv.scope.iter().map(|c| (*c, Color::Blue)).collect();
+            let findings: BTreeMap<_, _> = findings.into_iter().take(mv.scope.len() - 1).collect();
+            assert_eq!(mv.learn_many(&findings).state(), State::Stuck);
         }
     }
-    distribute_anywhere(&scope, blue_count)
+
+    #[test]
+    pub fn test_learn_invariant() {
+        // `a` is black in all the layouts of `mv`, the only solution is `c` and `d` blue
+        let line: Vec<_> = (0..4).map(|i| Coords::new(i, 0, -i)).collect();
+        let [a, b, c, d] = [line[0], line[1], line[2], line[3]];
+        let mv = distribute_anywhere(&[a], 0)
+            .merge(&distribute_together(&line, 2))
+            .merge(&distribute_anywhere(&[b, c], 1));
+        assert_eq!(mv.invariants().get(&a), Some(&Color::Black));
+        let learned = mv.learn(&a, Color::Black);
+        assert_eq!(learned.scope, BTreeSet::from([b, c, d]));
+        assert_eq!(learned.layouts.len(), mv.layouts.len());
+        assert_eq!(learned.solution_count_exact(), mv.solution_count_exact());
+        let expected = BTreeMap::from([(b, Color::Black), (c, Color::Blue), (d, Color::Blue)]);
+        assert_eq!(learned.invariants(), expected);
+        assert_eq!(mv.learn(&a, Color::Blue).state(), State::Stuck);
+    }
+
+    #[test]
+    pub fn test_big_solution_counts() {
+        let scope: Vec<_> = (0..30).map(|i| Coords::new(i, -i, 0)).collect();
+        let mv = distribute_anywhere(&scope, 15);
+        assert_eq!(mv.solution_count_upper_bound().unwrap(), 155117520);
+        assert_eq!(mv.solution_count_exact().unwrap(), 155117520);
+
+        // Way beyond `u64::MAX`
+        let scope: Vec<_> = (0..40).map(|i| Coords::new(i, -i, 0)).collect();
+        let mv0 = distribute_anywhere(&scope, 20);
+        let scope: Vec<_> = (0..40).map(|i| Coords::new(i, 1 - i, -1)).collect();
+        let mv1 = distribute_anywhere(&scope, 20);
+        let mv = mv0.merge(&mv1);
+        assert_eq!(mv.solution_count_upper_bound().unwrap(), nk(40, 20).pow(2));
+        assert!(mv.solution_count_upper_bound().unwrap() > u64::MAX as u128);
+    }
 }
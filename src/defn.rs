@@ -1,9 +1,28 @@
+use serde::Deserialize;
+use serde::Serialize;
 use std::collections::BTreeMap;
 use std::error::Error;
+use std::fmt;
+use std::fs;
 
+use constraint;
+use constraint::LineGaps;
+#[cfg(feature = "native")]
+use misc;
 use misc::Coords;
+use misc::Direction;
+use misc::OffsetKind;
 
-type Grid33<T> = [[T; 33]; 33];
+/// A 2d grid of the string definition, indexed by `[row][column]`. Its dimensions are the ones
+/// found in the input string.
+type Grid<T> = Vec<Vec<T>>;
+
+/// Number of lines before the first row of the grid in a string definition.
+const HEADER_LINE_COUNT: usize = 5;
+
+/// Dimensions of the grid produced by the game's editor, in rows and chars
+const EDITOR_ROW_COUNT: usize = 33;
+const EDITOR_ROW_WIDTH: usize = 66;
 
 /// The definition of a hexcells puzzle.
 /// Is uses cube coordinates for hexagons: https://www.redblobgames.com/grids/hexagons
@@ -11,35 +30,50 @@ type Grid33<T> = [[T; 33]; 33];
 /// It is passed to the solver for solving.
 pub type Defn = BTreeMap<Coords, Cell>;
 
-fn char_grid_of_string(strdefn: &str) -> Result<Grid33<(char, char)>, Box<dyn Error>> {
-    let mut grid = [[('_', '_'); 33]; 33];
-    let strdefn: Vec<_> = strdefn.trim().split('\n').collect();
-    if strdefn.len() != 38 {
-        return Err(format!(
-            "Wrong number of line in strdefn. Got {}, expected 38",
-            strdefn.len()
-        )
-        .into());
-    }
-    let strdefn = &strdefn[5..];
-    assert_eq!(strdefn.len(), 33);
-    for (i, line) in strdefn.iter().enumerate() {
-        let line = line.trim();
-        if line.len() != 66 {
-            return Err(format!(
-                "All lines should have len 66, found one with len {}",
-                line.len()
-            )
-            .into());
-        }
-        let line: Vec<_> = line.chars().collect();
-        for (j, chunk) in line.chunks(2).enumerate() {
-            let (left, right) = match chunk {
-                [left, right] => (left, right),
-                _ => std::panic::panic_any(0),
-            };
-            grid[i][j] = (*left, *right)
-        }
+/// The header of a string definition. It consists of the first `HEADER_LINE_COUNT` lines, i.e.
+/// the `Hexcells level v1` line, the title, the author and two lines of custom text.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    /// The version of the format, i.e. `v1` in `Hexcells level v1`
+    pub version: String,
+    pub title: String,
+    pub author: String,
+}
+
+fn metadata_of_lines(header: &[&str]) -> Metadata {
+    assert!(header.len() >= 3);
+    let version = header[0].trim();
+    let version = version.strip_prefix("Hexcells level ").unwrap_or(version);
+    Metadata {
+        version: version.to_string(),
+        title: header[1].trim().to_string(),
+        author: header[2].trim().to_string(),
+    }
+}
+
+/// Split the body of a string definition in a grid of (char, char). The row count is the number
+/// of lines that follow the header and the row width is the one of the widest row, in chars (the
+/// game's editor produces 33 rows of 66 chars, but bigger levels exist). The trailing whitespaces
+/// are dropped and the shorter rows are padded with `.`, i.e. with empty cells.
+fn char_grid_of_lines(strdefn: &[&str]) -> Result<Grid<(char, char)>, DefnError> {
+    let rows: Vec<Vec<char>> = strdefn
+        .iter()
+        .map(|line| line.trim_end().chars().collect())
+        .collect();
+    let width = rows.iter().map(|row| row.len()).max().unwrap_or(0);
+    if !width.is_multiple_of(2) {
+        return Err(DefnError::OddLineWidth {
+            row: rows
+                .iter()
+                .position(|row| row.len() == width)
+                .expect("Unreachable"),
+            width,
+        });
+    }
+    let mut grid = vec![];
+    for mut row in rows {
+        row.resize(width, '.');
+        grid.push(row.chunks(2).map(|chunk| (chunk[0], chunk[1])).collect());
     }
     Ok(grid)
 }
@@ -53,6 +87,7 @@ enum TokenLeft {
     Slash,
     Backslash,
     Pipe,
+    Hash,
 }
 
 enum TokenRight {
@@ -62,39 +97,168 @@ enum TokenRight {
     N,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Serialize)]
 pub enum Modifier {
     Anywhere,
     Together,
     Separated,
 }
 
-#[derive(Copy, Clone, Debug)]
+/// The direction in which a line hint reads. The string definition only has downward lines, the
+/// upward ones are assigned at parse time to the hints placed at the bottom end of their axis.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
 pub enum Orientation {
     BottomRight,
     Bottom,
     BottomLeft,
+    TopLeft,
+    Top,
+    TopRight,
 }
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+impl Orientation {
+    /// The offset from one cell of the line to the next one
+    pub fn direction(self) -> Coords {
+        let direction = match self {
+            Orientation::BottomRight => Direction::BottomRight,
+            Orientation::Bottom => Direction::Bottom,
+            Orientation::BottomLeft => Direction::BottomLeft,
+            Orientation::TopLeft => Direction::TopLeft,
+            Orientation::Top => Direction::Top,
+            Orientation::TopRight => Direction::TopRight,
+        };
+        direction.offset()
+    }
+
+    /// The orientation that reads the same axis in the other direction
+    pub fn reversed(self) -> Orientation {
+        match self {
+            Orientation::BottomRight => Orientation::TopLeft,
+            Orientation::Bottom => Orientation::Top,
+            Orientation::BottomLeft => Orientation::TopRight,
+            Orientation::TopLeft => Orientation::BottomRight,
+            Orientation::Top => Orientation::Bottom,
+            Orientation::TopRight => Orientation::BottomLeft,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Color {
     Black,
     Blue,
 }
 
 /// `Cell` is the type of a single cell in a Hexcells level definition
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Serialize)]
 pub enum Cell {
     Empty,
-    Zone0 { revealed: bool, color: Color },
-    Zone6 { revealed: bool, m: Modifier },
-    Zone18 { revealed: bool },
-    Line { o: Orientation, m: Modifier },
+    Zone0 {
+        revealed: bool,
+        color: Color,
+    },
+    Zone6 {
+        revealed: bool,
+        m: Modifier,
+    },
+    Zone18 {
+        revealed: bool,
+        m: Modifier,
+    },
+    Line {
+        o: Orientation,
+        m: Modifier,
+    },
+    /// The remaining blue count of a whole column, written `#+`. Contrary to a `|+` line hint, that
+    /// only counts the cells below it (or above it, see [orient_lines]) and that may require them
+    /// to be together or separated, it counts the cells on both sides of the hint and never has a
+    /// modifier.
+    Column,
+}
+
+/// The location of a cell in the grid of a string definition. `row` and `col` start at 0 on the
+/// first line following the header, `col` counts pairs of chars.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Location {
+    pub row: usize,
+    pub col: usize,
+    pub chars: (char, char),
+}
+
+/// Failure to turn a string definition into a `Defn`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DefnError {
+    /// The string definition doesn't have enough lines
+    WrongLineCount {
+        got: usize,
+        expected_min: usize,
+    },
+    /// The widest line of the grid, at `row`, has an odd len
+    OddLineWidth {
+        row: usize,
+        width: usize,
+    },
+    UnknownToken(Location),
+    InvalidPair(Location),
+    /// Some non-empty cells lie between two actual puzzle cells for a given alignment
+    BadAlignment(Vec<Location>),
+    /// The grid is valid but no alignment puts all its cells on the same hexagon tiling. Holds the
+    /// cells that are misaligned with the rest of the grid, i.e. the smallest of the two sets of
+    /// misaligned cells.
+    Disjoint(Vec<Location>),
+    /// A `DefnBuilder` received two cells at the same coords
+    DuplicateCoords(Coords),
 }
 
-fn lex_left(c: char) -> Result<TokenLeft, Box<dyn Error>> {
+impl Error for DefnError {}
+
+fn format_locations(locations: &[Location]) -> String {
+    let locations: Vec<_> = locations
+        .iter()
+        .map(|at| format!("row {} col {}", at.row, at.col))
+        .collect();
+    locations.join(", ")
+}
+
+impl fmt::Display for DefnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DefnError::WrongLineCount { got, expected_min } => write!(
+                f,
+                "Wrong number of line in strdefn. Got {}, expected at least {}",
+                got, expected_min
+            ),
+            DefnError::OddLineWidth { row, width } => write!(
+                f,
+                "The widest line should have an even len, found len {} at row {}",
+                width, row
+            ),
+            DefnError::UnknownToken(at) => write!(
+                f,
+                "unknown token in cell '{}{}' at row {} col {}",
+                at.chars.0, at.chars.1, at.row, at.col
+            ),
+            DefnError::InvalidPair(at) => write!(
+                f,
+                "invalid cell '{}{}' at row {} col {}",
+                at.chars.0, at.chars.1, at.row, at.col
+            ),
+            DefnError::BadAlignment(cells) => write!(
+                f,
+                "Bad alignment in hexcells definition at {}",
+                format_locations(cells)
+            ),
+            DefnError::Disjoint(cells) => write!(f, "Input grid is incompatible with cube coordinates. This happens because the level is made of at least 2 zones that are completely disjoint and that don't lie on the same hexagon tiling. The misaligned cells are at {}", format_locations(cells)),
+            DefnError::DuplicateCoords(coords) => {
+                write!(f, "Two cells were placed at {}", coords)
+            }
+        }
+    }
+}
+
+fn lex_left(at: &Location) -> Result<TokenLeft, DefnError> {
     type L = TokenLeft;
-    match c {
+    match at.chars.0 {
         '.' => Ok(L::Dot),
         'o' => Ok(L::SmallO),
         'O' => Ok(L::BigO),
@@ -103,18 +267,19 @@ fn lex_left(c: char) -> Result<TokenLeft, Box<dyn Error>> {
         '/' => Ok(L::Slash),
         '\\' => Ok(L::Backslash),
         '|' => Ok(L::Pipe),
-        _ => Err(format!("Unknown left token:'{}'", c).into()),
+        '#' => Ok(L::Hash),
+        _ => Err(DefnError::UnknownToken(*at)),
     }
 }
 
-fn lex_right(c: char) -> Result<TokenRight, Box<dyn Error>> {
+fn lex_right(at: &Location) -> Result<TokenRight, DefnError> {
     type R = TokenRight;
-    match c {
+    match at.chars.1 {
         '.' => Ok(R::Dot),
         '+' => Ok(R::Plus),
         'c' => Ok(R::C),
         'n' => Ok(R::N),
-        _ => Err(format!("Unknown right token:'{}'", c).into()),
+        _ => Err(DefnError::UnknownToken(*at)),
     }
 }
 
@@ -129,14 +294,14 @@ fn parse_modifier(r: TokenRight) -> Modifier {
     }
 }
 
-fn parse_cell(l: TokenLeft, r: TokenRight) -> Result<Cell, Box<dyn Error>> {
+fn parse_cell(l: TokenLeft, r: TokenRight, at: &Location) -> Result<Cell, DefnError> {
     type L = TokenLeft;
     type R = TokenRight;
     type O = Orientation;
     type C = Color;
     match (l, r) {
         (L::Dot, R::Dot) => Ok(Cell::Empty),
-        (L::Dot, _right) => Err("Invalid pair A".into()),
+        (L::Dot, _right) => Err(DefnError::InvalidPair(*at)),
         (L::SmallO, right @ (R::Plus | R::C | R::N)) => Ok(Cell::Zone6 {
             revealed: false,
             m: parse_modifier(right),
@@ -157,15 +322,21 @@ fn parse_cell(l: TokenLeft, r: TokenRight) -> Result<Cell, Box<dyn Error>> {
             revealed: false,
             color: C::Blue,
         }),
-        (L::SmallX, R::Plus) => Ok(Cell::Zone18 { revealed: false }),
-        (L::SmallX, _right @ (R::C | R::N)) => Err("Invalid pair B".into()),
+        (L::SmallX, right @ (R::Plus | R::C | R::N)) => Ok(Cell::Zone18 {
+            revealed: false,
+            m: parse_modifier(right),
+        }),
         (L::BigX, R::Dot) => Ok(Cell::Zone0 {
             revealed: true,
             color: C::Blue,
         }),
-        (L::BigX, R::Plus) => Ok(Cell::Zone18 { revealed: true }),
-        (L::BigX, _right @ (R::C | R::N)) => Err("Invalid pair C".into()),
-        (_left @ (L::Slash | L::Backslash | L::Pipe), R::Dot) => Err("Invalid pair D".into()),
+        (L::BigX, right @ (R::Plus | R::C | R::N)) => Ok(Cell::Zone18 {
+            revealed: true,
+            m: parse_modifier(right),
+        }),
+        (_left @ (L::Slash | L::Backslash | L::Pipe | L::Hash), R::Dot) => {
+            Err(DefnError::InvalidPair(*at))
+        }
         (L::Slash, right @ (R::Plus | R::C | R::N)) => Ok(Cell::Line {
             o: O::BottomLeft,
             m: parse_modifier(right),
@@ -178,18 +349,27 @@ fn parse_cell(l: TokenLeft, r: TokenRight) -> Result<Cell, Box<dyn Error>> {
             o: O::Bottom,
             m: parse_modifier(right),
         }),
+        (L::Hash, R::Plus) => Ok(Cell::Column),
+        (L::Hash, R::C | R::N) => Err(DefnError::InvalidPair(*at)),
     }
 }
 
-fn cell_grid_of_char_grid(src: Grid33<(char, char)>) -> Result<Grid33<Cell>, Box<dyn Error>> {
-    let mut dst = [[Cell::Empty; 33]; 33];
-    for (i, row) in src.iter().enumerate() {
-        for (j, (left, right)) in row.iter().enumerate() {
-            let left = lex_left(*left)?;
-            let right = lex_right(*right)?;
-            let cell = parse_cell(left, right)?;
-            dst[i][j] = cell
+fn cell_grid_of_char_grid(src: &Grid<(char, char)>) -> Result<Grid<Cell>, DefnError> {
+    let mut dst = vec![];
+    for (row, src_row) in src.iter().enumerate() {
+        let mut dst_row = vec![];
+        for (col, chars) in src_row.iter().enumerate() {
+            let at = Location {
+                row,
+                col,
+                chars: *chars,
+            };
+            let left = lex_left(&at)?;
+            let right = lex_right(&at)?;
+            let cell = parse_cell(left, right, &at)?;
+            dst_row.push(cell)
         }
+        dst.push(dst_row);
     }
     Ok(dst)
 }
@@ -203,18 +383,30 @@ enum Alignment {
 /// to cube coordinates.
 /// In the 2d grid representation, half of the element are void, they are placeholders that lie
 /// between two actual puzzle cells. These cells are expected to be `Empty`. `alignment` chooses
-/// which subset of the string definition is void.
-fn of_cell_grid(grid: Grid33<Cell>, alignment: Alignment) -> Result<Defn, Box<dyn Error>> {
+/// which subset of the string definition is void. `chars` is the grid `grid` was parsed from, it
+/// is used to locate the misaligned cells.
+///
+/// The element at row `i` and column `j` (after correction) maps to `q = j`, `r = (i - j) / 2` and
+/// `s = -(i + j) / 2`, it is an actual cell iff `i + j` is even. Only the parity of
+/// `icorrection + jcorrection` matters: shifting the columns by one yields the same tiling as
+/// shifting the rows by one, translated along `q`. Hence the two alignments cover all the column
+/// shifts by whole cells, the shift by half a cell is handled in [parse].
+fn of_cell_grid(
+    grid: &Grid<Cell>,
+    chars: &Grid<(char, char)>,
+    alignment: Alignment,
+) -> Result<Defn, DefnError> {
     let (icorrection, jcorrection) = match alignment {
         Alignment::Even => (1, 0),
         Alignment::Odd => (0, 0),
     };
     let mut map = BTreeMap::new();
-    for (i, row) in grid.iter().enumerate() {
-        let i = i + icorrection;
+    let mut misaligned = vec![];
+    for (row, cells) in grid.iter().enumerate() {
+        let i = row + icorrection;
         let i = i as f64;
-        for (j, cell) in row.iter().enumerate() {
-            let j = j + jcorrection;
+        for (col, cell) in cells.iter().enumerate() {
+            let j = col + jcorrection;
             let j = j as f64;
             let q = 0.0 * i + 1.0 * j;
             let r = 0.5 * i - 0.5 * j;
@@ -229,43 +421,1028 @@ fn of_cell_grid(grid: Grid33<Cell>, alignment: Alignment) -> Result<Defn, Box<dy
                     map.insert(c, *cell);
                 }
                 (false, _) => {
-                    return Err("Bad alignment in hexcells definition".into());
+                    misaligned.push(Location {
+                        row,
+                        col,
+                        chars: chars[row][col],
+                    });
                 }
             }
         }
     }
-    Ok(map)
+    if misaligned.is_empty() {
+        Ok(map)
+    } else {
+        Err(DefnError::BadAlignment(misaligned))
+    }
+}
+
+/// Is there a colored cell of `defn` on the half-line starting after `coords` and going towards
+/// `orientation`.
+fn has_cells_towards(defn: &Defn, coords: Coords, orientation: Orientation) -> bool {
+    let d = orientation.direction();
+    defn.iter().any(|(c, cell)| {
+        let delta = *c - coords;
+        let k = delta.distance(&Coords::new(0, 0, 0)) as isize;
+        color_of_cell(cell).is_some()
+            && k > 0
+            && delta == Coords::new(d.q() * k, d.r() * k, d.s() * k)
+    })
+}
+
+/// Reverse the lines that have no cells downward but some upward, i.e. the hints placed at the
+/// bottom end of their axis.
+fn orient_lines(defn: &mut Defn) {
+    let lines: Vec<_> = defn
+        .iter()
+        .filter_map(|(coords, cell)| match cell {
+            Cell::Line { o, m } => Some((*coords, *o, *m)),
+            _ => None,
+        })
+        .collect();
+    for (coords, o, m) in lines {
+        let reversed = o.reversed();
+        if !has_cells_towards(defn, coords, o) && has_cells_towards(defn, coords, reversed) {
+            defn.insert(coords, Cell::Line { o: reversed, m });
+        }
+    }
+}
+
+/// Split `strdefn` in lines, `\n`, `\r\n` and `\r` line endings are accepted. The trailing
+/// whitespaces of the lines are dropped later on, by [char_grid_of_lines] and [metadata_of_lines].
+fn lines_of_string(strdefn: &str) -> Vec<&str> {
+    strdefn
+        .trim()
+        .split('\n')
+        .flat_map(|line| line.strip_suffix('\r').unwrap_or(line).split('\r'))
+        .collect()
+}
+
+/// Takes a string definition as found on reddit and lex/parse/type it to `Metadata` and `Defn`. If
+/// the result is `Ok` then the grid is a valid Hexcells puzzle.
+pub fn parse(strdefn: &str) -> Result<(Metadata, Defn), DefnError> {
+    // Step 1: Split the header from the body.
+    let lines = lines_of_string(strdefn);
+    if lines.len() <= HEADER_LINE_COUNT {
+        return Err(DefnError::WrongLineCount {
+            got: lines.len(),
+            expected_min: HEADER_LINE_COUNT + 1,
+        });
+    }
+    let (header, body) = lines.split_at(HEADER_LINE_COUNT);
+    let metadata = metadata_of_lines(header);
+
+    // Step 2: Parse the body as is, and shifted by one char for the pastes that lost or gained a
+    // char at the beginning of each row. The errors reported are the ones of the body as is. If
+    // both succeed, keep the one with the most cells.
+    let shifted: Vec<_> = body
+        .iter()
+        .map(|line| format!(".{}.", line.trim_end()))
+        .collect();
+    let shifted: Vec<_> = shifted.iter().map(|line| line.as_str()).collect();
+    let mut defn = match (defn_of_body(body), defn_of_body(&shifted)) {
+        (Ok(x), Ok(y)) if y.len() > x.len() => y,
+        (Ok(x), _) => x,
+        (Err(_), Ok(y)) => y,
+        (Err(err), Err(_)) => return Err(err),
+    };
+
+    // Step 3: Choose the reading direction of the lines.
+    orient_lines(&mut defn);
+    Ok((metadata, defn))
 }
 
-/// Takes a string definition as found on reddit and lex/parse/type it to `Defn`. If the result is
-/// `Ok` then the grid is a valid Hexcells puzzle.
-pub fn of_string(strdefn: &str) -> Result<Defn, Box<dyn Error>> {
-    // Step 1: Turn the string into 33x33 array of (char, char).
-    let grid = char_grid_of_string(strdefn)?;
+/// Turn the body of a string definition into a `Defn`.
+fn defn_of_body(body: &[&str]) -> Result<Defn, DefnError> {
+    // Step 1: Turn the body into a 2d array of (char, char).
+    let grid = char_grid_of_lines(body)?;
 
     // Step 2: Lex and parse the (char, char) to Cell.
     // - The lexing step is a direct translation of the left/right chars to TokenLeft/TokenRight.
     // - The parsing step is an exhaustive pattern matching of the tokens to a final Cell type.
-    let grid = cell_grid_of_char_grid(grid)?;
+    let cells = cell_grid_of_char_grid(&grid)?;
 
-    // Step 3: Turn the 33x33 Cell array to a Defn.
-    match of_cell_grid(grid, Alignment::Even) {
-        Err(_) => (),
-        Ok(x) => return Ok(x),
-    };
-    match of_cell_grid(grid, Alignment::Odd) {
-        Err(_) => (),
-        Ok(x) => return Ok(x),
+    // Step 3: Turn the 2d Cell array to a Defn. If both alignments succeed, keep the one with the
+    // most cells.
+    match (
+        of_cell_grid(&cells, &grid, Alignment::Even),
+        of_cell_grid(&cells, &grid, Alignment::Odd),
+    ) {
+        (Ok(x), Ok(y)) if y.len() > x.len() => Ok(y),
+        (Ok(x), _) => Ok(x),
+        (Err(_), Ok(y)) => Ok(y),
+        (Err(DefnError::BadAlignment(even)), Err(DefnError::BadAlignment(odd))) => {
+            let misaligned = if even.len() <= odd.len() { even } else { odd };
+            Err(DefnError::Disjoint(misaligned))
+        }
+        (Err(_), Err(_)) => panic!("Unreachable"),
+    }
+}
+
+/// Construct a `Defn` in code instead of parsing a string definition.
+/// The cells are all on the same tiling by construction, since they are placed at cube
+/// coordinates. As with [parse], the line hints that only have cells upward are read upward.
+#[derive(Clone, Debug, Default)]
+pub struct DefnBuilder {
+    cells: Vec<(Coords, Cell)>,
+}
+
+impl DefnBuilder {
+    pub fn new() -> DefnBuilder {
+        DefnBuilder::default()
+    }
+
+    pub fn place(mut self, coords: Coords, cell: Cell) -> DefnBuilder {
+        self.cells.push((coords, cell));
+        self
+    }
+
+    /// Fails if two cells were placed at the same coords. The `Empty` cells are dropped.
+    pub fn build(self) -> Result<Defn, DefnError> {
+        let mut defn = BTreeMap::new();
+        for (coords, cell) in self.cells {
+            if defn.insert(coords, cell).is_some() {
+                return Err(DefnError::DuplicateCoords(coords));
+            }
+        }
+        defn.retain(|_, cell| !matches!(cell, Cell::Empty));
+        orient_lines(&mut defn);
+        Ok(defn)
+    }
+}
+
+/// Failure of [overlay], both `Defn`s have a cell at `coords`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Conflict {
+    pub coords: Coords,
+}
+
+impl Error for Conflict {}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Both grids have a cell at {}", self.coords)
+    }
+}
+
+/// The union of the cells of `a` and `b`, e.g. to assemble a level from partial `Defn`s. Fails on
+/// the first coords that hold a cell in both, even if the two cells are the same. As with
+/// [DefnBuilder], the line hints that only have cells upward in the union are read upward.
+pub fn overlay(a: &Defn, b: &Defn) -> Result<Defn, Conflict> {
+    let mut defn = a.clone();
+    for (coords, cell) in b {
+        if defn.insert(*coords, *cell).is_some() {
+            return Err(Conflict { coords: *coords });
+        }
+    }
+    orient_lines(&mut defn);
+    Ok(defn)
+}
+
+/// Only parse the header of a string definition. Contrary to [parse], this succeeds on levels that
+/// have a malformed grid.
+pub fn parse_metadata(strdefn: &str) -> Result<Metadata, DefnError> {
+    let lines = lines_of_string(strdefn);
+    if lines.len() < HEADER_LINE_COUNT {
+        return Err(DefnError::WrongLineCount {
+            got: lines.len(),
+            expected_min: HEADER_LINE_COUNT,
+        });
+    }
+    Ok(metadata_of_lines(&lines[..HEADER_LINE_COUNT]))
+}
+
+/// Same as [parse] but drops the `Metadata`.
+pub fn of_string(strdefn: &str) -> Result<Defn, DefnError> {
+    let (_metadata, defn) = parse(strdefn)?;
+    Ok(defn)
+}
+
+/// Same as [of_string] but the string definition is read from `lines`, which are only consumed up
+/// to the end of the grid. The grid ends after `EDITOR_ROW_COUNT` rows when its first row has the
+/// width of the game's editor, in which case `lines` ending early is an error. Otherwise it ends
+/// at the first empty line or at the end of `lines`.
+pub fn from_lines<I: IntoIterator<Item = String>>(lines: I) -> Result<Defn, DefnError> {
+    let mut lines = lines.into_iter().skip_while(|line| line.trim().is_empty());
+    let mut strdefn: Vec<String> = lines.by_ref().take(HEADER_LINE_COUNT).collect();
+    let mut row_count = 0;
+    let mut width = None;
+    for line in lines {
+        let line_width = line.trim_end().chars().count();
+        if line_width == 0 {
+            break;
+        }
+        let width = *width.get_or_insert(line_width);
+        strdefn.push(line);
+        row_count += 1;
+        if width == EDITOR_ROW_WIDTH && row_count == EDITOR_ROW_COUNT {
+            break;
+        }
+    }
+    if width == Some(EDITOR_ROW_WIDTH) && row_count < EDITOR_ROW_COUNT {
+        return Err(DefnError::WrongLineCount {
+            got: strdefn.len(),
+            expected_min: HEADER_LINE_COUNT + EDITOR_ROW_COUNT,
+        });
+    }
+    of_string(&strdefn.join("\n"))
+}
+
+/// Read a `.hexcells` file, as saved by sixcells. Its content is a string definition.
+pub fn from_hexcells_file(path: &str) -> Result<Defn, Box<dyn Error>> {
+    let strdefn = fs::read_to_string(path)?;
+    Ok(of_string(&strdefn)?)
+}
+
+/// A hash of the cells of `defn` that doesn't depend on how the puzzle was written, e.g. its
+/// metadata, its whitespace or the padding around its grid. The cells are translated so that the
+/// first one lies at the origin, hence the coords of two puzzles with the same hash may differ by
+/// an offset.
+#[cfg(feature = "native")]
+pub fn canonical_hash(defn: &Defn) -> String {
+    let origin = match defn.keys().next() {
+        None => return misc::sha256(&Vec::<(Coords, Cell)>::new()),
+        Some(origin) => *origin,
     };
-    Err("Input grid is incompatible with cube coordinates. This happens because the level is made of at least 2 zones that are completely disjoint and that don't lie on the same hexagon tiling".into())
+    let cells: Vec<_> = defn.iter().map(|(c, cell)| (*c - origin, cell)).collect();
+    misc::sha256(&cells)
 }
 
 pub fn color_of_cell(cell: &Cell) -> Option<Color> {
     match cell {
         Cell::Empty => None,
-        Cell::Line { .. } => None,
+        Cell::Line { .. } | Cell::Column => None,
         Cell::Zone0 { color, .. } => Some(*color),
         Cell::Zone6 { .. } => Some(Color::Black),
         Cell::Zone18 { .. } => Some(Color::Blue),
     }
 }
+
+/// Whether the color of `cell` is shown from the start. `false` for the cells without a color.
+pub fn is_revealed(cell: &Cell) -> bool {
+    match cell {
+        Cell::Empty | Cell::Line { .. } | Cell::Column => false,
+        Cell::Zone0 { revealed, .. }
+        | Cell::Zone6 { revealed, .. }
+        | Cell::Zone18 { revealed, .. } => *revealed,
+    }
+}
+
+/// The cells of `defn` that hold a hint, i.e. the lines, the columns and the zones with a number
+pub fn constraint_cells(defn: &Defn) -> impl Iterator<Item = (Coords, &Cell)> {
+    defn.iter()
+        .filter(|(_, cell)| {
+            matches!(
+                cell,
+                Cell::Line { .. } | Cell::Column | Cell::Zone6 { .. } | Cell::Zone18 { .. }
+            )
+        })
+        .map(|(coords, cell)| (*coords, cell))
+}
+
+/// The cells of `defn` that have a color, i.e. the zones
+pub fn value_cells(defn: &Defn) -> impl Iterator<Item = (Coords, &Cell)> {
+    defn.iter()
+        .filter(|(_, cell)| color_of_cell(cell).is_some())
+        .map(|(coords, cell)| (*coords, cell))
+}
+
+/// The lowest and the highest `(col, row)` offset coordinates of the cells of `defn`, see
+/// `Coords::to_offset` (with `OffsetKind::OddQ`). `None` if `defn` is empty.
+pub fn bounding_box(defn: &Defn) -> Option<((i32, i32), (i32, i32))> {
+    let offsets = defn.keys().map(|c| c.to_offset(OffsetKind::OddQ));
+    let (cols, rows): (Vec<_>, Vec<_>) = offsets.unzip();
+    let (min_col, max_col) = (*cols.iter().min()?, *cols.iter().max()?);
+    let (min_row, max_row) = (*rows.iter().min()?, *rows.iter().max()?);
+    Some(((min_col, min_row), (max_col, max_row)))
+}
+
+/// Statistics of a `Defn`, see [summary]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DefnSummary {
+    /// Number of columns and of rows spanned by the cells, see [bounding_box]
+    pub width: usize,
+    pub height: usize,
+    /// Number of cells with a color
+    pub cell_count: usize,
+    pub blue_count: usize,
+    /// Number of cells whose color is shown from the start
+    pub revealed_count: usize,
+    pub zone0_count: usize,
+    pub zone6_count: usize,
+    pub zone18_count: usize,
+    pub line_count: usize,
+    pub column_count: usize,
+    /// Number of hints with each modifier, the column hints count as `Anywhere`
+    pub anywhere_count: usize,
+    pub together_count: usize,
+    pub separated_count: usize,
+}
+
+pub fn summary(defn: &Defn) -> DefnSummary {
+    let mut summary = DefnSummary::default();
+    if let Some(((min_col, min_row), (max_col, max_row))) = bounding_box(defn) {
+        summary.width = (max_col - min_col + 1) as usize;
+        summary.height = (max_row - min_row + 1) as usize;
+    }
+    for cell in defn.values() {
+        let modifier = match cell {
+            Cell::Empty => None,
+            Cell::Zone0 { .. } => {
+                summary.zone0_count += 1;
+                None
+            }
+            Cell::Zone6 { m, .. } => {
+                summary.zone6_count += 1;
+                Some(*m)
+            }
+            Cell::Zone18 { m, .. } => {
+                summary.zone18_count += 1;
+                Some(*m)
+            }
+            Cell::Line { m, .. } => {
+                summary.line_count += 1;
+                Some(*m)
+            }
+            Cell::Column => {
+                summary.column_count += 1;
+                Some(Modifier::Anywhere)
+            }
+        };
+        match modifier {
+            None => (),
+            Some(Modifier::Anywhere) => summary.anywhere_count += 1,
+            Some(Modifier::Together) => summary.together_count += 1,
+            Some(Modifier::Separated) => summary.separated_count += 1,
+        }
+        match color_of_cell(cell) {
+            None => (),
+            Some(color) => {
+                summary.cell_count += 1;
+                if color == Color::Blue {
+                    summary.blue_count += 1;
+                }
+                if is_revealed(cell) {
+                    summary.revealed_count += 1;
+                }
+            }
+        }
+    }
+    summary
+}
+
+/// The direct neighbors of `coords` that are cells of `defn`
+pub fn neighbors6_in(defn: &Defn, coords: Coords) -> Vec<(Coords, &Cell)> {
+    cells_in(defn, &coords.neighbors6())
+}
+
+/// The cells of `defn` at distance 1 or 2 of `coords`
+pub fn neighbors18_in(defn: &Defn, coords: Coords) -> Vec<(Coords, &Cell)> {
+    cells_in(defn, &coords.neighbors18())
+}
+
+/// The cells of `defn` at distance exactly 2 of `coords`
+pub fn neighbors12_in(defn: &Defn, coords: Coords) -> Vec<(Coords, &Cell)> {
+    cells_in(defn, &coords.ring(2))
+}
+
+fn cells_in<'a>(defn: &'a Defn, coords: &[Coords]) -> Vec<(Coords, &'a Cell)> {
+    coords
+        .iter()
+        .filter_map(|c| defn.get(c).map(|cell| (*c, cell)))
+        .collect()
+}
+
+/// A hint of a `Defn` that doesn't hold for a candidate solution, see [verify]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    /// The solution has no color for the cell at these coords
+    MissingCell(Coords),
+    /// The number displayed by `hint` is `displayed` but its scope has `actual` blues in the
+    /// solution
+    Count {
+        hint: Coords,
+        displayed: usize,
+        actual: usize,
+    },
+    /// The blue count of `hint` matches but the blues of the solution don't satisfy its
+    /// together/separated modifier
+    Modifier { hint: Coords },
+}
+
+impl Error for Mismatch {}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mismatch::MissingCell(coords) => write!(f, "No color for the cell at {}", coords),
+            Mismatch::Count {
+                hint,
+                displayed,
+                actual,
+            } => write!(
+                f,
+                "The hint at {} displays {} but the solution has {} blues",
+                hint, displayed, actual
+            ),
+            Mismatch::Modifier { hint } => write!(
+                f,
+                "The blues of the solution don't satisfy the modifier of the hint at {}",
+                hint
+            ),
+        }
+    }
+}
+
+/// Check that the hints of `defn` (the numbers it displays on its zones and lines, as implied by
+/// the colors of its cells) hold for `solution`, the lines having the gaps of the base game, see
+/// [verify_with_gaps]
+pub fn verify(defn: &Defn, solution: &BTreeMap<Coords, Color>) -> Result<(), Mismatch> {
+    verify_with_gaps(defn, solution, LineGaps::default())
+}
+
+/// Check that the hints of `defn` (the numbers it displays on its zones and lines, as implied by
+/// the colors of its cells) hold for `solution`. Returns the first mismatching hint.
+pub fn verify_with_gaps(
+    defn: &Defn,
+    solution: &BTreeMap<Coords, Color>,
+    gaps: LineGaps,
+) -> Result<(), Mismatch> {
+    for (coords, cell) in defn {
+        let mv = match cell {
+            Cell::Empty | Cell::Zone0 { .. } => continue,
+            Cell::Zone6 { m, .. } => constraint::zone6(defn, *coords, *m),
+            Cell::Zone18 { m, .. } => constraint::zone18(defn, *coords, *m),
+            Cell::Line { o, m } => constraint::line_with_gaps(defn, *coords, *o, *m, gaps),
+            Cell::Column => constraint::column(defn, *coords),
+        };
+        let mut displayed = 0;
+        let mut actual = 0;
+        for c in &mv.scope {
+            if color_of_cell(&defn[c]) == Some(Color::Blue) {
+                displayed += 1;
+            }
+            match solution.get(c) {
+                None => return Err(Mismatch::MissingCell(*c)),
+                Some(Color::Blue) => actual += 1,
+                Some(Color::Black) => (),
+            }
+        }
+        if displayed != actual {
+            return Err(Mismatch::Count {
+                hint: *coords,
+                displayed,
+                actual,
+            });
+        }
+        if !mv.is_consistent(solution) {
+            return Err(Mismatch::Modifier { hint: *coords });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    const THE_TRIAL: &str = include_str!("../extra/the_trial.txt");
+
+    /// Surround the body of `strdefn` with `pad` empty cells on each side
+    fn pad_strdefn(strdefn: &str, pad: usize) -> String {
+        let lines: Vec<_> = strdefn.trim().split('\n').collect();
+        let width = lines[HEADER_LINE_COUNT].len() + 4 * pad;
+        let mut res: Vec<String> = lines[..HEADER_LINE_COUNT]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        for _ in 0..pad {
+            res.push(".".repeat(width));
+        }
+        for line in &lines[HEADER_LINE_COUNT..] {
+            res.push(format!("{}{}{}", "..".repeat(pad), line, "..".repeat(pad)));
+        }
+        for _ in 0..pad {
+            res.push(".".repeat(width));
+        }
+        res.join("\n")
+    }
+
+    #[test]
+    pub fn test_grid_dimensions() {
+        let defn33 = of_string(THE_TRIAL).unwrap();
+
+        // 33 + 2 * 9 rows of 66 + 4 * 9 chars
+        let strdefn = pad_strdefn(THE_TRIAL, 9);
+        assert_eq!(strdefn.split('\n').count(), HEADER_LINE_COUNT + 51);
+        let defn51 = of_string(&strdefn).unwrap();
+        assert_eq!(defn33.len(), defn51.len());
+
+        // Odd row widths are invalid
+        let strdefn = THE_TRIAL.replace("..\n", ".\n");
+        assert_eq!(
+            of_string(&strdefn).unwrap_err(),
+            DefnError::OddLineWidth { row: 0, width: 65 }
+        );
+
+        // Ragged rows are padded with empty cells, the width is counted in chars
+        let strdefn = THE_TRIAL.replacen("..\n", "\n", 7);
+        assert_eq!(
+            format!("{:?}", of_string(&strdefn).unwrap()),
+            format!("{:?}", defn33)
+        );
+        let lines: Vec<_> = THE_TRIAL.trim().split('\n').collect();
+        let mut strdefn: Vec<_> = lines.iter().map(|line| line.to_string()).collect();
+        let last = strdefn.len() - 1;
+        strdefn[last] = format!("{}\u{e9}", &lines[last][..65]);
+        assert_eq!(
+            of_string(&strdefn.join("\n")).unwrap_err(),
+            DefnError::UnknownToken(Location {
+                row: last - HEADER_LINE_COUNT,
+                col: 32,
+                chars: ('.', '\u{e9}'),
+            })
+        );
+
+        // A header alone is invalid (the 2 empty lines of custom text are trimmed)
+        let strdefn = THE_TRIAL
+            .split('\n')
+            .take(HEADER_LINE_COUNT)
+            .collect::<Vec<_>>();
+        let strdefn = strdefn.join("\n");
+        assert_eq!(
+            of_string(&strdefn).unwrap_err(),
+            DefnError::WrongLineCount {
+                got: 3,
+                expected_min: HEADER_LINE_COUNT + 1
+            }
+        );
+    }
+
+    #[test]
+    pub fn test_line_endings() {
+        let expected = of_string(THE_TRIAL).unwrap();
+        let crlf = THE_TRIAL.replace('\n', "  \r\n");
+        let cr = THE_TRIAL.replace('\n', "\r");
+        for strdefn in [crlf, cr] {
+            let (metadata, defn) = parse(&strdefn).unwrap();
+            assert_eq!(metadata.title, "The Trial");
+            assert_eq!(
+                defn.keys().collect::<Vec<_>>(),
+                expected.keys().collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_metadata() {
+        let (metadata, _) = parse(THE_TRIAL).unwrap();
+        assert_eq!(metadata, parse_metadata(THE_TRIAL).unwrap());
+        assert_eq!(
+            metadata,
+            Metadata {
+                version: "v1".to_string(),
+                title: "The Trial".to_string(),
+                author: "Labyrinth Man".to_string(),
+            }
+        );
+
+        // The header stays readable when the grid is not
+        let strdefn = THE_TRIAL.replace("..\n", ".\n");
+        assert!(parse(&strdefn).is_err());
+        assert_eq!(metadata, parse_metadata(&strdefn).unwrap());
+    }
+
+    #[test]
+    pub fn test_orient_lines() {
+        // The column hint is below its 2 cells, the diagonal hint is above its cell
+        const PUZZLE: &str = "Hexcells level v1
+Upward
+Nobody
+
+
+\\+....
+..x...
+x...o.
+......
+|+....";
+        let defn = of_string(PUZZLE).unwrap();
+        let orientations: Vec<_> = defn
+            .values()
+            .filter_map(|cell| match cell {
+                Cell::Line { o, .. } => Some(*o),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(orientations.len(), 2);
+        assert!(orientations.contains(&Orientation::Top));
+        assert!(orientations.contains(&Orientation::BottomRight));
+    }
+
+    #[test]
+    pub fn test_shifted_paste() {
+        // Every row of the grid lost its first char
+        let lines: Vec<_> = THE_TRIAL.trim().split('\n').collect();
+        let mut strdefn: Vec<_> = lines[..HEADER_LINE_COUNT]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        for line in &lines[HEADER_LINE_COUNT..] {
+            assert!(line.starts_with('.'));
+            strdefn.push(format!("{}.", &line[1..]));
+        }
+        let expected = of_string(THE_TRIAL).unwrap();
+        let defn = of_string(&strdefn.join("\n")).unwrap();
+        assert_eq!(
+            defn.keys().collect::<Vec<_>>(),
+            expected.keys().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    pub fn test_verify() {
+        let defn = of_string(THE_TRIAL).unwrap();
+        let mut solution: BTreeMap<_, _> = defn
+            .iter()
+            .filter_map(|(coords, cell)| color_of_cell(cell).map(|color| (*coords, color)))
+            .collect();
+        assert_eq!(verify(&defn, &solution), Ok(()));
+
+        // A blue of the solution becomes black
+        let (coords, _) = solution
+            .iter()
+            .find(|(_, color)| **color == Color::Blue)
+            .map(|(coords, color)| (*coords, *color))
+            .unwrap();
+        solution.insert(coords, Color::Black);
+        assert!(matches!(
+            verify(&defn, &solution),
+            Err(Mismatch::Count { displayed, actual, .. }) if displayed == actual + 1
+        ));
+
+        solution.remove(&coords);
+        assert_eq!(verify(&defn, &solution), Err(Mismatch::MissingCell(coords)));
+
+        // The 2 blues of the line are together
+        const PUZZLE: &str = "Hexcells level v1
+Bugged
+Nobody
+
+
+|n....
+......
+x...o.
+......
+x.....
+......
+o.....";
+        let defn = of_string(PUZZLE).unwrap();
+        let solution: BTreeMap<_, _> = defn
+            .iter()
+            .filter_map(|(coords, cell)| color_of_cell(cell).map(|color| (*coords, color)))
+            .collect();
+        assert!(matches!(
+            verify(&defn, &solution),
+            Err(Mismatch::Modifier { .. })
+        ));
+
+        // The cells of the line swap their colors, the hole hides the swap when skipped
+        let defn = of_string(include_str!("../extra/line_gap.txt")).unwrap();
+        let line = defn
+            .iter()
+            .find(|(_, cell)| matches!(cell, Cell::Line { .. }))
+            .map(|(coords, _)| *coords)
+            .unwrap();
+        let solution: BTreeMap<_, _> = defn
+            .iter()
+            .filter_map(|(coords, cell)| color_of_cell(cell).map(|color| (*coords, color)))
+            .map(|(coords, color)| match color {
+                _ if coords.q() != line.q() => (coords, color),
+                Color::Blue => (coords, Color::Black),
+                Color::Black => (coords, Color::Blue),
+            })
+            .collect();
+        assert!(!matches!(
+            verify(&defn, &solution),
+            Err(Mismatch::Count { hint, .. }) if hint == line
+        ));
+        assert!(matches!(
+            verify_with_gaps(&defn, &solution, LineGaps::Stop),
+            Err(Mismatch::Count { hint, .. }) if hint == line
+        ));
+    }
+
+    #[test]
+    pub fn test_from_hexcells_file() {
+        let path = std::env::temp_dir().join("hexcells_solver_test_from_hexcells_file.hexcells");
+        fs::write(&path, THE_TRIAL.replace('\n', "\r\n")).unwrap();
+        let defn = from_hexcells_file(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(defn.keys().eq(of_string(THE_TRIAL).unwrap().keys()));
+        assert!(from_hexcells_file("does/not/exist.hexcells").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "native")]
+    pub fn test_canonical_hash() {
+        const PUZZLE: &str = "Hexcells level v1
+Title
+Author
+
+
+|+....
+......
+x...o.";
+        let hash = canonical_hash(&of_string(PUZZLE).unwrap());
+        let other = "Hexcells level v1\r\nOther title\r\nOther author\r\n\r\n\r\n\
+                     ....|+....\r\n..........\r\n....x...o.\r\n..........";
+        assert_eq!(canonical_hash(&of_string(other).unwrap()), hash);
+        let different = PUZZLE.replace("x...o.", "x.....");
+        assert_ne!(canonical_hash(&of_string(&different).unwrap()), hash);
+    }
+
+    #[test]
+    pub fn test_neighbors_in() {
+        let blue = Cell::Zone0 {
+            revealed: false,
+            color: Color::Blue,
+        };
+        let center = Coords::new(0, 0, 0);
+        let near = center.neighbors6()[0];
+        let far = Coords::new(2, -2, 0);
+        let defn = DefnBuilder::new()
+            .place(center, blue)
+            .place(near, blue)
+            .place(far, blue)
+            .place(Coords::new(3, -3, 0), blue)
+            .build()
+            .unwrap();
+        let coords6: Vec<_> = neighbors6_in(&defn, center)
+            .into_iter()
+            .map(|(c, _)| c)
+            .collect();
+        assert_eq!(coords6, vec![near]);
+        let coords18: BTreeSet<_> = neighbors18_in(&defn, center)
+            .into_iter()
+            .map(|(c, _)| c)
+            .collect();
+        assert_eq!(coords18, BTreeSet::from([near, far]));
+        assert!(neighbors6_in(&defn, Coords::new(9, -9, 0)).is_empty());
+    }
+
+    #[test]
+    pub fn test_cell_filters() {
+        let [line, zone0, zone6, zone18] = [0, 1, 2, 3].map(|i| Coords::new(0, i, -i));
+        let defn = DefnBuilder::new()
+            .place(
+                line,
+                Cell::Line {
+                    m: Modifier::Anywhere,
+                    o: Orientation::Bottom,
+                },
+            )
+            .place(
+                zone0,
+                Cell::Zone0 {
+                    revealed: false,
+                    color: Color::Blue,
+                },
+            )
+            .place(
+                zone6,
+                Cell::Zone6 {
+                    revealed: true,
+                    m: Modifier::Anywhere,
+                },
+            )
+            .place(
+                zone18,
+                Cell::Zone18 {
+                    revealed: false,
+                    m: Modifier::Anywhere,
+                },
+            )
+            .build()
+            .unwrap();
+        let constraints: Vec<_> = constraint_cells(&defn).map(|(c, _)| c).collect();
+        assert_eq!(constraints, vec![line, zone6, zone18]);
+        let values: Vec<_> = value_cells(&defn).map(|(c, _)| c).collect();
+        assert_eq!(values, vec![zone0, zone6, zone18]);
+        let revealed: Vec<_> = defn
+            .iter()
+            .filter(|(_, cell)| is_revealed(cell))
+            .map(|(c, _)| *c)
+            .collect();
+        assert_eq!(revealed, vec![zone6]);
+    }
+
+    #[test]
+    pub fn test_builder() {
+        let blue = Cell::Zone0 {
+            revealed: false,
+            color: Color::Blue,
+        };
+        let line = Cell::Line {
+            o: Orientation::Bottom,
+            m: Modifier::Anywhere,
+        };
+        let defn = DefnBuilder::new()
+            .place(Coords::new(0, 0, 0), blue)
+            .place(Coords::new(0, 1, -1), blue)
+            .place(Coords::new(0, 2, -2), line)
+            .place(Coords::new(1, 0, -1), Cell::Empty)
+            .build()
+            .unwrap();
+        assert_eq!(defn.len(), 3);
+        assert!(matches!(
+            defn[&Coords::new(0, 2, -2)],
+            Cell::Line {
+                o: Orientation::Top,
+                ..
+            }
+        ));
+
+        let err = DefnBuilder::new()
+            .place(Coords::new(0, 0, 0), blue)
+            .place(Coords::new(0, 0, 0), line)
+            .build()
+            .unwrap_err();
+        assert_eq!(err, DefnError::DuplicateCoords(Coords::new(0, 0, 0)));
+    }
+
+    #[test]
+    pub fn test_from_lines() {
+        // The lines after the grid are left in the stream
+        let mut lines = THE_TRIAL.lines().chain(["Next level"]).map(str::to_string);
+        let defn = from_lines(lines.by_ref()).unwrap();
+        assert_eq!(
+            format!("{:?}", defn),
+            format!("{:?}", of_string(THE_TRIAL).unwrap())
+        );
+        assert_eq!(lines.next().unwrap(), "Next level");
+
+        // A grid of the editor's size is incomplete
+        let lines = THE_TRIAL.lines().take(20).map(str::to_string);
+        assert_eq!(
+            from_lines(lines).unwrap_err(),
+            DefnError::WrongLineCount {
+                got: 20,
+                expected_min: HEADER_LINE_COUNT + EDITOR_ROW_COUNT
+            }
+        );
+        let lines = THE_TRIAL.lines().take(3).map(str::to_string);
+        assert!(matches!(
+            from_lines(lines).unwrap_err(),
+            DefnError::WrongLineCount { got: 3, .. }
+        ));
+
+        // Other grids end at the first empty line
+        let strdefn = include_str!("../extra/line_gap.txt");
+        let mut lines = ["", ""]
+            .iter()
+            .copied()
+            .chain(strdefn.lines())
+            .chain(["", "Next level"])
+            .map(str::to_string);
+        let defn = from_lines(lines.by_ref()).unwrap();
+        assert_eq!(
+            format!("{:?}", defn),
+            format!("{:?}", of_string(strdefn).unwrap())
+        );
+        assert_eq!(lines.next().unwrap(), "Next level");
+    }
+
+    #[test]
+    pub fn test_summary() {
+        let defn = of_string(THE_TRIAL).unwrap();
+        let summary = summary(&defn);
+        assert_eq!(summary.cell_count, value_cells(&defn).count());
+        assert_eq!(
+            summary.zone0_count + summary.zone6_count + summary.zone18_count,
+            summary.cell_count
+        );
+        assert_eq!(
+            summary.anywhere_count + summary.together_count + summary.separated_count,
+            constraint_cells(&defn).count()
+        );
+        assert_eq!(summary.column_count, 0);
+        assert!(summary.line_count > 0 && summary.revealed_count > 0);
+        // The body of The Trial spans columns 8 to 24 of its grid
+        assert_eq!((summary.width, summary.height), (17, 12));
+        assert_eq!(summary.together_count, 1);
+        assert_eq!(
+            summary,
+            self::summary(&overlay(&defn, &Defn::new()).unwrap())
+        );
+        assert_eq!(self::summary(&Defn::new()), DefnSummary::default());
+        assert_eq!(bounding_box(&Defn::new()), None);
+    }
+
+    #[test]
+    pub fn test_overlay() {
+        let defn = of_string(THE_TRIAL).unwrap();
+        let (top, bottom): (Defn, Defn) = defn.iter().partition(|(c, _)| c.r() < 0);
+        assert!(!top.is_empty() && !bottom.is_empty());
+        let both = overlay(&top, &bottom).unwrap();
+        assert_eq!(format!("{:?}", both), format!("{:?}", defn));
+
+        // The line is read upward once the cells above it are added
+        let line = Cell::Line {
+            o: Orientation::Bottom,
+            m: Modifier::Anywhere,
+        };
+        let blue = Cell::Zone0 {
+            revealed: false,
+            color: Color::Blue,
+        };
+        let a = Defn::from([(Coords::new(0, 2, -2), line)]);
+        let b = Defn::from([(Coords::new(0, 0, 0), blue)]);
+        let union = overlay(&a, &b).unwrap();
+        assert!(matches!(
+            union[&Coords::new(0, 2, -2)],
+            Cell::Line {
+                o: Orientation::Top,
+                ..
+            }
+        ));
+
+        let (coords, _) = defn.iter().next().unwrap();
+        let err = overlay(&defn, &Defn::from([(*coords, blue)])).unwrap_err();
+        assert_eq!(err, Conflict { coords: *coords });
+        assert!(format!("{}", err).starts_with("Both grids have a cell at ("));
+    }
+
+    #[test]
+    pub fn test_disjoint() {
+        // The `o.` in the middle doesn't lie on the tiling of its 2 neighbors
+        const PUZZLE: &str = "Hexcells level v1
+Disjoint
+Nobody
+
+
+x.o.x.";
+        let err = of_string(PUZZLE).unwrap_err();
+        let at = Location {
+            row: 0,
+            col: 1,
+            chars: ('o', '.'),
+        };
+        assert_eq!(err, DefnError::Disjoint(vec![at]));
+        assert!(format!("{}", err).ends_with("The misaligned cells are at row 0 col 1"));
+    }
+
+    #[test]
+    pub fn test_column() {
+        const PUZZLE: &str = "Hexcells level v1
+Column
+Nobody
+
+
+x...
+..#+
+o...
+..x.";
+        let defn = of_string(PUZZLE).unwrap();
+        let (coords, _) = defn
+            .iter()
+            .find(|(_, cell)| matches!(cell, Cell::Column))
+            .unwrap();
+        assert_eq!(constraint_cells(&defn).count(), 1);
+        assert!(verify(&defn, &BTreeMap::new()).is_err());
+        let mv = constraint::column(&defn, *coords);
+        assert_eq!(mv.scope.len(), 1);
+        let err = of_string(&PUZZLE.replace("#+", "#c")).unwrap_err();
+        assert!(matches!(err, DefnError::InvalidPair(_)));
+        let err = of_string(&PUZZLE.replace("#+", "#.")).unwrap_err();
+        assert!(matches!(err, DefnError::InvalidPair(_)));
+    }
+
+    #[test]
+    pub fn test_parse_error_location() {
+        // Row 6 of The Trial starts with 10 empty cells and then `o.`, `..`, `o+`
+        let lines: Vec<_> = THE_TRIAL.split('\n').collect();
+        let row6 = lines[HEADER_LINE_COUNT + 6];
+        assert!(row6[20..].starts_with("o...o+"));
+
+        let mut strdefn = lines.clone();
+        let line = format!("{}/.{}", &row6[..22], &row6[24..]);
+        strdefn[HEADER_LINE_COUNT + 6] = &line;
+        let err = of_string(&strdefn.join("\n")).unwrap_err();
+        let at = Location {
+            row: 6,
+            col: 11,
+            chars: ('/', '.'),
+        };
+        assert_eq!(err, DefnError::InvalidPair(at));
+        assert_eq!(format!("{}", err), "invalid cell '/.' at row 6 col 11");
+
+        let line = format!("{}z.{}", &row6[..22], &row6[24..]);
+        strdefn[HEADER_LINE_COUNT + 6] = &line;
+        let err = of_string(&strdefn.join("\n")).unwrap_err();
+        assert_eq!(
+            format!("{}", err),
+            "unknown token in cell 'z.' at row 6 col 11"
+        );
+    }
+}
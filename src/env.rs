@@ -1,5 +1,9 @@
 use std::fmt;
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use std::time::Instant;
 use Error;
 
 #[derive(Debug)]
@@ -13,31 +17,317 @@ impl fmt::Display for Timeout {
     }
 }
 
-/// Represents the runtime environment for the solver, responsible for managing timeouts.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl Error for Cancelled {}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cancelled")
+    }
+}
+
+#[derive(Debug)]
+pub struct BudgetExceeded;
+
+impl Error for BudgetExceeded {}
+
+impl fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Merge budget exceeded")
+    }
+}
+
+#[derive(Debug)]
+pub struct TooManyLayouts {
+    pub cap: usize,
+}
+
+impl Error for TooManyLayouts {}
+
+impl fmt::Display for TooManyLayouts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "More than {} layouts in a merge", self.cap)
+    }
+}
+
+/// Handle to cancel a running solver from another thread, see `Env::cancel_token`.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    flag: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+}
+
+/// The time source of an `Env`. `now` is the time elapsed since an arbitrary origin, which must not
+/// change during the life of the clock.
+/// Any `Fn() -> Duration` is a clock, e.g. a closure reading `Date.now()` in a browser.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Duration;
+}
+
+impl<F> Clock for F
+where
+    F: Fn() -> Duration + Send + Sync,
+{
+    fn now(&self) -> Duration {
+        self()
+    }
+}
+
+/// The default clock, based on `Instant`. `Instant::now` panics on `wasm32-unknown-unknown`, this
+/// clock is frozen there instead, hence the timer never expires unless another clock is given to
+/// `Env::with_clock`.
+pub struct SystemClock {
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    origin: Instant,
+}
+
+impl SystemClock {
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    pub fn new() -> SystemClock {
+        SystemClock {
+            origin: Instant::now(),
+        }
+    }
+
+    #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+    pub fn new() -> SystemClock {
+        SystemClock {}
+    }
+
+    /// The time of `instant` on this clock, zero if it precedes the creation of the clock
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    fn time_of(&self, instant: Instant) -> Duration {
+        instant.saturating_duration_since(self.origin)
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> SystemClock {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    fn now(&self) -> Duration {
+        self.origin.elapsed()
+    }
+
+    #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+    fn now(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+/// Represents the runtime environment for the solver, responsible for managing timeouts,
+/// cancellation and the optional merge budget.
+/// Unlike the timeout, the merge budget doesn't depend on the speed of the machine. It is consumed
+/// by the `Multiverse::merge` calls of the solver (see `charge`) and is never reset.
+/// The layout cap bounds the number of layouts that forking a single layout may produce during a
+/// merge (see `check_layout_count`), the merges of dense constraints would exhaust the memory
+/// otherwise. Like the timeout, exceeding it is sticky.
+/// The timer either expires `max_duration` after the last `reset_timer` (see `new`) or at a fixed
+/// deadline (see `with_deadline`). The time is read from a `Clock`, a `SystemClock` by default.
 pub struct Env {
-    start_time: Instant,
-    max_duration: Duration,
+    clock: Box<dyn Clock>,
+    /// Time of `clock` at which the timer expires
+    deadline: Duration,
+    /// `None` when the deadline is absolute
+    max_duration: Option<Duration>,
+    cancel_token: CancelToken,
+    merge_budget: Option<u64>,
+    merge_count: AtomicU64,
+    layout_cap: Option<usize>,
+    layout_cap_exceeded: AtomicBool,
 }
 
 impl Env {
     pub fn new(max_duration: u64) -> Env {
-        let start_time = Instant::now();
+        Env::with_clock(SystemClock::new(), max_duration)
+    }
+
+    /// Same as [new] but the time is read from `clock`
+    pub fn with_clock<C: Clock + 'static>(clock: C, max_duration: u64) -> Env {
         let max_duration = Duration::from_secs(max_duration);
         Env {
-            start_time,
-            max_duration,
+            deadline: deadline_after(clock.now(), max_duration),
+            clock: Box::new(clock),
+            max_duration: Some(max_duration),
+            cancel_token: CancelToken::default(),
+            merge_budget: None,
+            merge_count: AtomicU64::new(0),
+            layout_cap: None,
+            layout_cap_exceeded: AtomicBool::new(false),
+        }
+    }
+
+    /// An environment whose timer expires at `deadline`, whatever the number of `reset_timer`
+    /// calls. Useful when the time budget is given by a caller as an absolute deadline.
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    pub fn with_deadline(deadline: Instant) -> Env {
+        let clock = SystemClock::new();
+        Env {
+            deadline: clock.time_of(deadline),
+            clock: Box::new(clock),
+            max_duration: None,
+            cancel_token: CancelToken::default(),
+            merge_budget: None,
+            merge_count: AtomicU64::new(0),
+            layout_cap: None,
+            layout_cap_exceeded: AtomicBool::new(false),
+        }
+    }
+
+    pub fn with_merge_budget(mut self, merge_budget: u64) -> Env {
+        self.merge_budget = Some(merge_budget);
+        self
+    }
+
+    pub fn with_layout_cap(mut self, layout_cap: usize) -> Env {
+        self.layout_cap = Some(layout_cap);
+        self
+    }
+
+    pub fn layout_cap(&self) -> Option<usize> {
+        self.layout_cap
+    }
+
+    /// Returns `Err(TooManyLayouts)` if `count` layouts exceed the layout cap, in which case
+    /// `check_abort` fails from then on.
+    pub fn check_layout_count(&self, count: usize) -> Result<(), Box<dyn Error>> {
+        match self.layout_cap {
+            Some(cap) if count > cap => {
+                self.layout_cap_exceeded.store(true, Ordering::Relaxed);
+                Err(Box::new(TooManyLayouts { cap }))
+            }
+            _ => Ok(()),
         }
     }
 
+    /// The current time of the clock of the environment
+    pub fn now(&self) -> Duration {
+        self.clock.now()
+    }
+
+    /// The number of merges charged so far
+    pub fn merge_count(&self) -> u64 {
+        self.merge_count.load(Ordering::Relaxed)
+    }
+
+    /// Account for `merges` merge operations and check if the computations should be aborted.
+    pub fn charge(&self, merges: u64) -> Result<(), Box<dyn Error>> {
+        self.merge_count.fetch_add(merges, Ordering::Relaxed);
+        self.check_abort()
+    }
+
+    /// Restart the timer, the solver calls it before each step. No-op for an environment created
+    /// using `with_deadline`.
     pub fn reset_timer(&mut self) {
-        self.start_time = Instant::now();
+        if let Some(max_duration) = self.max_duration {
+            self.deadline = deadline_after(self.clock.now(), max_duration);
+        }
+    }
+
+    /// The time left before the timer expires, zero once it has. The solver restarts the timer
+    /// right before the compound invariant search of each step (see `reset_timer`), hence this is
+    /// the time left for the current step from then on. Before that, e.g. in
+    /// `SolveOptions::on_step`, it is what the previous step left. Never restarted for an
+    /// environment created using `with_deadline`.
+    pub fn time_remaining(&self) -> Duration {
+        self.deadline.saturating_sub(self.clock.now())
+    }
+
+    /// A token that can be sent to another thread in order to cancel the computations using this
+    /// environment.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel_token.clone()
+    }
+
+    pub fn cancel(&self) {
+        self.cancel_token.cancel()
     }
 
-    pub fn check_timeout(&self) -> Result<(), Box<dyn Error>> {
-        if self.start_time.elapsed() >= self.max_duration {
+    /// Returns `Err(Cancelled)` if the environment was cancelled, `Err(BudgetExceeded)` if more
+    /// merges than the budget were charged, `Err(TooManyLayouts)` if the layout cap was exceeded,
+    /// or `Err(Timeout)` if the timer expired.
+    pub fn check_abort(&self) -> Result<(), Box<dyn Error>> {
+        if self.cancel_token.is_cancelled() {
+            Err(Box::new(Cancelled))
+        } else if self.layout_cap_exceeded.load(Ordering::Relaxed) {
+            Err(Box::new(TooManyLayouts {
+                cap: self.layout_cap.expect("Unreachable"),
+            }))
+        } else if self
+            .merge_budget
+            .is_some_and(|budget| self.merge_count() > budget)
+        {
+            Err(Box::new(BudgetExceeded))
+        } else if self.clock.now() >= self.deadline {
             Err(Box::new(Timeout))
         } else {
             Ok(())
         }
     }
 }
+
+/// `max_duration` after `now`, never for the timeouts too large to be represented
+fn deadline_after(now: Duration, max_duration: Duration) -> Duration {
+    now.checked_add(max_duration).unwrap_or(Duration::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_with_clock() {
+        let millis = Arc::new(AtomicU64::new(0));
+        let clock = {
+            let millis = millis.clone();
+            move || Duration::from_millis(millis.load(Ordering::Relaxed))
+        };
+        let mut env = Env::with_clock(clock, 2);
+        assert!(env.check_abort().is_ok());
+        assert_eq!(env.time_remaining(), Duration::from_secs(2));
+        millis.store(1_999, Ordering::Relaxed);
+        assert!(env.check_abort().is_ok());
+        assert_eq!(env.time_remaining(), Duration::from_millis(1));
+        millis.store(2_000, Ordering::Relaxed);
+        assert!(env.check_abort().unwrap_err().is::<Timeout>());
+        millis.store(3_000, Ordering::Relaxed);
+        assert_eq!(env.time_remaining(), Duration::ZERO);
+        // The timer restarts from the current time of the clock
+        env.reset_timer();
+        assert!(env.check_abort().is_ok());
+        assert_eq!(env.time_remaining(), Duration::from_secs(2));
+        millis.store(5_000, Ordering::Relaxed);
+        assert!(env.check_abort().unwrap_err().is::<Timeout>());
+    }
+
+    #[test]
+    pub fn test_huge_timeout() {
+        let millis = Arc::new(AtomicU64::new(1_000));
+        let clock = {
+            let millis = millis.clone();
+            move || Duration::from_millis(millis.load(Ordering::Relaxed))
+        };
+        let mut env = Env::with_clock(clock, u64::MAX);
+        assert!(env.check_abort().is_ok());
+        assert_eq!(env.time_remaining(), Duration::MAX - Duration::from_secs(1));
+        millis.store(2_000, Ordering::Relaxed);
+        env.reset_timer();
+        assert!(env.check_abort().is_ok());
+        assert!(Env::new(u64::MAX).check_abort().is_ok());
+    }
+}
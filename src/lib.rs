@@ -0,0 +1,74 @@
+extern crate itertools;
+extern crate log;
+extern crate once_cell;
+extern crate rayon;
+extern crate regex;
+extern crate serde;
+#[cfg(target_arch = "wasm32")]
+extern crate wasm_bindgen;
+
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
+
+pub mod constraint;
+pub mod defn;
+pub mod env;
+pub mod misc;
+pub mod multiverse;
+#[cfg(feature = "native")]
+pub mod reddit_post;
+pub mod render;
+#[cfg(feature = "native")]
+pub mod reporting;
+pub mod solver;
+
+use std::error::Error;
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::prelude::*;
+
+/// Parse the string definition of a puzzle and solve it with the default options. The only entry
+/// point needed by the embedders that don't build the `native` feature (e.g. for wasm).
+pub fn solve_string(strdefn: &str, timeout_secs: u64) -> Result<solver::Outcome, Box<dyn Error>> {
+    solve_string_with(&mut env::Env::new(timeout_secs), strdefn)
+}
+
+/// Same as [solve_string] but within `env`, e.g. one built with `env::Env::with_clock`
+pub fn solve_string_with(
+    env: &mut env::Env,
+    strdefn: &str,
+) -> Result<solver::Outcome, Box<dyn Error>> {
+    let defn = defn::of_string(strdefn)?;
+    Ok(solver::solve(env, &defn, solver::SolveOptions::default()))
+}
+
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_namespace = Date, js_name = now)]
+    fn date_now() -> f64;
+}
+
+/// The wasm export of `solve_string`: the outcome serialized to JSON, or the error message thrown
+/// as a string. The `SystemClock` is frozen on wasm, the timeout is measured with `Date.now()`.
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen(js_name = solveString)]
+pub fn solve_string_json(strdefn: &str, timeout_secs: u32) -> Result<String, JsValue> {
+    let clock = || std::time::Duration::from_secs_f64(date_now() / 1000.);
+    let mut env = env::Env::with_clock(clock, u64::from(timeout_secs));
+    let outcome =
+        solve_string_with(&mut env, strdefn).map_err(|err| JsValue::from_str(&err.to_string()))?;
+    serde_json::to_string(&outcome).map_err(|err| JsValue::from_str(&err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_solve_string() {
+        let outcome = solve_string(include_str!("../extra/the_trial.txt"), 60).unwrap();
+        assert_eq!(outcome.step_count(), Some(44));
+        assert!(solve_string("Hexcells level v1", 60).is_err());
+    }
+}
@@ -1,96 +1,394 @@
-extern crate itertools;
-extern crate once_cell;
-extern crate regex;
+extern crate env_logger;
+extern crate hexcells_solver;
+extern crate log;
 extern crate serde;
+extern crate serde_json;
 
-mod constraint;
-mod defn;
-mod env;
-mod misc;
-mod multiverse;
-mod reddit_post;
-mod reporting;
-mod solver;
-
+use hexcells_solver::defn;
+use hexcells_solver::env;
+use hexcells_solver::misc;
+use hexcells_solver::reddit_post;
+use hexcells_solver::reporting;
+use hexcells_solver::solver;
+use serde::Serialize;
+use std::collections::HashMap;
 use std::env::args;
 use std::error::Error;
+use std::fmt;
+use std::fs;
 use std::io;
+use std::io::BufRead;
+use std::path::Path;
+
+const USAGE: &str = "Usage: hexcells-solver [-q | -v | -vv] [--format (text | json)] \
+                     [--out-dir <dir>] [--user-agent <string>] [--timeout <secs>] \
+                     (- | solve <path> | batch <dir> | reddit-posts)";
+
+/// Timeout of `-` and `solve` when `--timeout` is not passed, in seconds
+const SOLVE_TIMEOUT: u64 = 3600 * 24 * 30;
+
+/// Timeout of `batch` and `reddit-posts` when `--timeout` is not passed, in seconds
+const BATCH_TIMEOUT: u64 = 60 * 20;
+
+/// Environment variable read when `--user-agent` is not passed
+const USER_AGENT_VAR: &str = "HEXCELLS_USER_AGENT";
+
+#[derive(Clone, Copy)]
+enum Format {
+    Text,
+    Json,
+}
+
+/// A cell of the solved grid in the JSON output
+#[derive(Serialize)]
+struct JsonCell {
+    q: isize,
+    r: isize,
+    s: isize,
+    color: defn::Color,
+}
+
+#[derive(Serialize)]
+struct JsonOutput<'a> {
+    outcome: &'a solver::Outcome,
+    /// Only set if the puzzle was solved
+    grid: Option<Vec<JsonCell>>,
+}
+
+fn main_stdin(format: Format, timeout: u64) -> Result<(), Box<dyn Error>> {
+    // Stop reading at the end of the grid, an I/O error ends the lines and is reported instead of
+    // the parsing error
+    let mut io_error = None;
+    let lines = io::stdin()
+        .lock()
+        .lines()
+        .map_while(|line| line.map_err(|err| io_error = Some(err)).ok());
+    let defn = defn::from_lines(lines);
+    if let Some(err) = io_error {
+        return Err(err.into());
+    }
+    solve_and_print(&defn?, format, timeout)
+}
 
-fn main_stdin() -> Result<(), Box<dyn Error>> {
-    let mut strdefn = String::new();
-    let stdin = io::stdin();
-    for _ in 0..38 {
-        let mut line = String::new();
-        stdin.read_line(&mut line)?;
-        strdefn.push_str(&line);
+fn main_file(path: &str, format: Format, timeout: u64) -> Result<(), Box<dyn Error>> {
+    let strdefn =
+        fs::read_to_string(path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+    let defn =
+        defn::of_string(&strdefn).map_err(|err| format!("Failed to parse {}: {}", path, err))?;
+    solve_and_print(&defn, format, timeout)
+}
+
+fn solve_and_print(defn: &defn::Defn, format: Format, timeout: u64) -> Result<(), Box<dyn Error>> {
+    let mut env = env::Env::new(timeout);
+    let outcome = solver::solve(&mut env, defn, solver::SolveOptions::default());
+    match format {
+        Format::Text => {
+            println!("{}", outcome);
+            log::debug!("{:?}", outcome);
+        }
+        Format::Json => {
+            let grid = match outcome {
+                solver::Outcome::Solved { .. } => Some(
+                    defn.iter()
+                        .filter_map(|(coords, cell)| {
+                            defn::color_of_cell(cell).map(|color| JsonCell {
+                                q: coords.q(),
+                                r: coords.r(),
+                                s: coords.s(),
+                                color,
+                            })
+                        })
+                        .collect(),
+                ),
+                _ => None,
+            };
+            let output = JsonOutput {
+                outcome: &outcome,
+                grid,
+            };
+            println!("{}", serde_json::to_string(&output)?);
+        }
     }
-    let defn = defn::of_string(&strdefn)?;
-    let mut env = env::Env::new(3600 * 24 * 30);
-    let outcome = solver::solve(&mut env, &defn, false);
-    println!("{}", outcome);
-    println!("{:?}", outcome);
     Ok(())
 }
 
-fn main_reddit_posts() -> Result<(), Box<dyn Error>> {
+fn main_batch(dir: &str, out_dir: &Path, timeout: u64) -> Result<(), Box<dyn Error>> {
     let mut reporting = vec![];
-    let mut env = env::Env::new(60 * 20);
+    let mut env = env::Env::new(timeout);
+
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir).map_err(|err| format!("Failed to read {}: {}", dir, err))? {
+        let path = entry?.path();
+        if path.is_file()
+            && path
+                .extension()
+                .is_some_and(|ext| ext == "txt" || ext == "hexcells")
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    for path in paths {
+        log::info!("> {}", path.display());
+        let strdefn = match fs::read_to_string(&path) {
+            Err(err) => {
+                reporting.push(reporting::FileLine {
+                    path: path.display().to_string(),
+                    level_name: String::new(),
+                    outcome: reporting::Outcome::ParseFail,
+                });
+                log::warn!("  Skip because {:?}", err);
+                continue;
+            }
+            Ok(strdefn) => strdefn,
+        };
+        let level_name = defn::parse_metadata(&strdefn)
+            .map(|metadata| metadata.title)
+            .unwrap_or_default();
+        let defn = match defn::of_string(&strdefn) {
+            Err(err) => {
+                reporting.push(reporting::FileLine {
+                    path: path.display().to_string(),
+                    level_name,
+                    outcome: reporting::Outcome::ParseFail,
+                });
+                log::warn!("  Skip because {:?}", err);
+                continue;
+            }
+            Ok(defn) => defn,
+        };
+        let outcome = solve_with_cache(&mut env, &defn)?;
+        log::info!("  Outcome: {}", outcome);
+        reporting.push(reporting::FileLine {
+            path: path.display().to_string(),
+            level_name,
+            outcome: reporting::Outcome::Solver(outcome),
+        });
+    }
+    reporting::report_files(&reporting, out_dir)?;
+    Ok(())
+}
+
+/// The options of `batch` and `reddit-posts`. The puzzles that the deductions alone can't solve are
+/// guessed through, the reports rank them by their number of guesses.
+fn batch_options() -> solver::SolveOptions {
+    solver::SolveOptions {
+        guess: true,
+        ..solver::SolveOptions::default()
+    }
+}
+
+/// The key of the cached outcome of `defn`. `defn::canonical_hash` ignores where the grid lies but
+/// the outcomes hold absolute coords, hence the coords of its first cell complete it. The outcomes
+/// computed with and without guesses are cached apart.
+fn outcome_key(
+    defn: &defn::Defn,
+    options: &solver::SolveOptions,
+) -> (String, Option<misc::Coords>, bool) {
+    (
+        defn::canonical_hash(defn),
+        defn.keys().next().copied(),
+        options.guess,
+    )
+}
+
+/// An outcome that the solver didn't reach the end of, kept out of `./cache_solver`
+#[derive(Debug)]
+struct Aborted(solver::Outcome);
+
+impl fmt::Display for Aborted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Aborted: {}", self.0)
+    }
+}
+
+impl Error for Aborted {}
+
+/// Solve `defn` with [batch_options] through the `./cache_solver` cache. The timeouts, the
+/// cancellations and the exhausted budgets are not cached, a later run (e.g. with a longer
+/// `--timeout`) solves these puzzles again.
+fn solve_with_cache(
+    env: &mut env::Env,
+    defn: &defn::Defn,
+) -> Result<solver::Outcome, Box<dyn Error>> {
+    let options = batch_options();
+    let key = outcome_key(defn, &options);
+    let res = misc::with_cache(
+        &key,
+        || match solver::solve(env, defn, options) {
+            outcome @ (solver::Outcome::Timeout(_)
+            | solver::Outcome::Cancelled
+            | solver::Outcome::BudgetExceeded) => Err(Box::new(Aborted(outcome))),
+            outcome => Ok(outcome),
+        },
+        "./cache_solver",
+    );
+    match res {
+        Err(err) => err.downcast::<Aborted>().map(|aborted| aborted.0),
+        Ok(outcome) => Ok(outcome),
+    }
+}
+
+/// The number of solutions of `defn`, `None` for the outcomes that say nothing about it. A puzzle
+/// that the solver solved without guessing has a single solution, the other ones are counted from
+/// the deductions of `outcome` with `solver::count_solutions_from` and cached like the outcomes.
+/// The counts that fail, e.g. on a timeout, are not cached.
+fn solution_count(
+    env: &mut env::Env,
+    defn: &defn::Defn,
+    outcome: &solver::Outcome,
+) -> Result<Option<solver::SolutionCount>, Box<dyn Error>> {
+    match outcome {
+        solver::Outcome::Solved { guesses: 0, .. } => Ok(Some(solver::SolutionCount::One)),
+        solver::Outcome::Solved { .. } | solver::Outcome::Unsolvable { .. } => misc::with_cache(
+            &defn::canonical_hash(defn),
+            || {
+                let found = outcome.deductions(defn);
+                solver::count_solutions_from(env, defn, &found, &batch_options()).map(Some)
+            },
+            "./cache_solution_count",
+        ),
+        _ => Ok(None),
+    }
+}
+
+fn main_reddit_posts(out_dir: &Path, user_agent: &str, timeout: u64) -> Result<(), Box<dyn Error>> {
+    let mut report = reporting::ReportWriter::create(out_dir)?;
+    let mut env = env::Env::new(timeout);
+    let fetch_options = misc::FetchOptions {
+        user_agent: user_agent.to_string(),
+        ..misc::FetchOptions::default()
+    };
+
+    // The index in `report` and the url of each puzzle seen so far, keyed by hash
+    let mut seen: HashMap<String, (usize, String)> = HashMap::new();
+    let mut repost_counts: HashMap<usize, u32> = HashMap::new();
 
     let reddit_posts = reddit_post::list_levels("./reddit_posts.json")?;
     for post in reddit_posts {
-        println!("> {:?}", post);
-        let strdefns = reddit_post::strdefns_of_post(&post, "./cache_reqwest")?;
-        println!("  {} puzzles(s)", strdefns.len());
+        log::info!("> {:?}", post);
+        let strdefns = reddit_post::strdefns_of_post(&post, "./cache_reqwest", &fetch_options)?;
+        log::info!("  {} puzzles(s)", strdefns.len());
         for (idx_in_post, strdefn) in strdefns.iter().enumerate() {
             let idx_in_post = idx_in_post as u32;
-            let level_name = strdefn
-                .split('\n')
-                .nth(1)
-                .unwrap()
-                .replace("&#39;", "'")
-                .trim()
-                .to_string();
+            let key = misc::sha256(&strdefn.trim());
+            if let Some((i, url)) = seen.get(&key) {
+                *repost_counts.entry(*i).or_insert(0) += 1;
+                log::info!("  Skip repost of {}", url);
+                continue;
+            }
+            let level_name = defn::parse_metadata(strdefn)
+                .map(|metadata| metadata.title.replace("&#39;", "'"))
+                .unwrap_or_default();
             let defn = match defn::of_string(strdefn) {
                 Err(err) => {
-                    reporting.push(reporting::Line {
+                    let i = report.append(&reporting::Line {
                         post: post.clone(),
                         idx_in_post,
                         level_name,
+                        defn: None,
                         outcome: reporting::Outcome::ParseFail,
-                    });
-                    println!("  Skip because {:?}", err);
+                        solution_count: None,
+                        repost_count: 0,
+                    })?;
+                    seen.insert(key, (i, post.url.clone()));
+                    log::warn!("  Skip because {:?}", err);
                     continue;
                 }
                 Ok(defn) => defn,
             };
-            let outcome = misc::with_cache(
-                &strdefn.trim(),
-                || Ok(solver::solve(&mut env, &defn, false)),
-                "./cache_solver",
-            )?;
-            println!("  Outcome: {}", outcome);
-            reporting.push(reporting::Line {
+            let outcome = solve_with_cache(&mut env, &defn)?;
+            log::info!("  Outcome: {}", outcome);
+            let solution_count = match solution_count(&mut env, &defn, &outcome) {
+                Ok(count) => count,
+                Err(err) => {
+                    log::warn!("  Failed to count the solutions because {}", err);
+                    None
+                }
+            };
+            let i = report.append(&reporting::Line {
                 post: post.clone(),
                 idx_in_post,
                 level_name,
+                defn: Some(defn),
                 outcome: reporting::Outcome::Solver(outcome),
-            });
+                solution_count,
+                repost_count: 0,
+            })?;
+            seen.insert(key, (i, post.url.clone()));
         }
     }
-    reporting::report_ranked(&reporting);
-    reporting::report_all(&reporting);
+    print!("{}", report.finish(&repost_counts)?);
     Ok(())
 }
 
+/// Remove `name` and its value from `args`
+fn take_option<'a>(args: &mut Vec<&'a str>, name: &str) -> Result<Option<&'a str>, Box<dyn Error>> {
+    match args.iter().position(|arg| *arg == name) {
+        None => Ok(None),
+        Some(i) => match args.get(i + 1) {
+            None => Err(USAGE.into()),
+            Some(value) => {
+                let value = *value;
+                args.drain(i..i + 2);
+                Ok(Some(value))
+            }
+        },
+    }
+}
+
+/// Remove the `-q`, `-v` and `-vv` flags from `args` and return the default log filter they select
+fn take_verbosity(args: &mut Vec<&str>) -> &'static str {
+    let mut verbosity = 0;
+    args.retain(|arg| {
+        match *arg {
+            "-q" => verbosity -= 1,
+            "-v" => verbosity += 1,
+            "-vv" => verbosity += 2,
+            _ => return true,
+        }
+        false
+    });
+    match verbosity {
+        i32::MIN..=-1 => "error",
+        0 => "info",
+        1 => "info,hexcells_solver=debug",
+        _ => "info,hexcells_solver=trace",
+    }
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
-    let args: Vec<_> = args().collect();
-    if args.len() != 2 {
-        Err("Wrong number of arguments to program".into())
-    } else if args[1] == "reddit-posts" {
-        main_reddit_posts()
-    } else if args[1] == "-" {
-        main_stdin()
-    } else {
-        Err("Wrong argument to program".into())
+    let args: Vec<_> = args().skip(1).collect();
+    let mut args: Vec<_> = args.iter().map(String::as_str).collect();
+    let filter = take_verbosity(&mut args);
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(filter))
+        .format_target(false)
+        .format_timestamp(None)
+        .init();
+    let format = match take_option(&mut args, "--format")? {
+        None | Some("text") => Format::Text,
+        Some("json") => Format::Json,
+        Some(_) => return Err(USAGE.into()),
+    };
+    let out_dir = Path::new(take_option(&mut args, "--out-dir")?.unwrap_or("."));
+    let user_agent_var = std::env::var(USER_AGENT_VAR).ok();
+    let user_agent = match take_option(&mut args, "--user-agent")? {
+        Some(user_agent) => user_agent,
+        None => user_agent_var
+            .as_deref()
+            .unwrap_or(misc::DEFAULT_USER_AGENT),
+    };
+    let timeout = match take_option(&mut args, "--timeout")? {
+        None => None,
+        Some(secs) => Some(secs.parse::<u64>().map_err(|_| USAGE)?),
+    };
+    match args[..] {
+        ["reddit-posts"] => {
+            main_reddit_posts(out_dir, user_agent, timeout.unwrap_or(BATCH_TIMEOUT))
+        }
+        ["-"] => main_stdin(format, timeout.unwrap_or(SOLVE_TIMEOUT)),
+        ["solve", path] => main_file(path, format, timeout.unwrap_or(SOLVE_TIMEOUT)),
+        ["batch", dir] => main_batch(dir, out_dir, timeout.unwrap_or(BATCH_TIMEOUT)),
+        _ => Err(USAGE.into()),
     }
 }
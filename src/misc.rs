@@ -1,17 +1,39 @@
+#[cfg(feature = "native")]
 use misc::fs::File;
+#[cfg(feature = "native")]
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde::Serialize;
+use std::convert::TryInto;
 use std::error::Error;
+use std::fmt;
+#[cfg(feature = "native")]
 use std::fs;
+#[cfg(feature = "native")]
+use std::io::BufWriter;
+#[cfg(feature = "native")]
 use std::io::ErrorKind;
+#[cfg(feature = "native")]
 use std::io::Read;
+#[cfg(feature = "native")]
 use std::io::Write;
+#[cfg(feature = "native")]
 use std::path::PathBuf;
+#[cfg(feature = "native")]
+use std::process;
+use std::str::FromStr;
+#[cfg(feature = "native")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "native")]
+use std::thread;
+#[cfg(feature = "native")]
+use std::time::Duration;
 
 /// Cube coordinates for hexagon tiling.
 /// https://www.redblobgames.com/grids/hexagons/#conversions (use "flat" mode, not "pointy").
-#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Eq, Ord, Serialize, Deserialize)]
+/// Only `q` and `r` are stored (and serialized), `s` is implied by `q + r + s == 0`. This is
+/// intentional and keeps the cache files compact.
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Eq, Ord, Hash, Serialize, Deserialize)]
 pub struct Coords {
     /// Grows towards right
     q: i16,
@@ -22,15 +44,42 @@ pub struct Coords {
 }
 
 impl Coords {
+    /// Panics if `q + r + s != 0` or if `q` or `r` doesn't fit the storage, see [checked_new]
     pub fn new(q: isize, r: isize, s: isize) -> Coords {
-        if q + r + s != 0 {
-            panic!("Constructing an invalid Coords")
+        match Coords::checked_new(q, r, s) {
+            Some(coords) => coords,
+            None => panic!("Constructing an invalid Coords ({},{},{})", q, r, s),
         }
-        use std::convert::TryInto;
-        Coords {
-            q: q.try_into().unwrap(),
-            r: r.try_into().unwrap(),
+    }
+
+    /// `None` if `q + r + s != 0` or if `q` or `r` is out of the `i16` range. `s` isn't stored,
+    /// it may lie outside of that range.
+    pub fn checked_new(q: isize, r: isize, s: isize) -> Option<Coords> {
+        if q.checked_add(r)?.checked_add(s)? != 0 {
+            return None;
         }
+        Some(Coords {
+            q: q.try_into().ok()?,
+            r: r.try_into().ok()?,
+        })
+    }
+
+    /// `None` if the sum leaves the range of the coords, see [checked_new]
+    pub fn checked_add(self, other: Coords) -> Option<Coords> {
+        Coords::checked_new(
+            self.q() + other.q(),
+            self.r() + other.r(),
+            self.s() + other.s(),
+        )
+    }
+
+    /// `None` if the difference leaves the range of the coords, see [checked_new]
+    pub fn checked_sub(self, other: Coords) -> Option<Coords> {
+        Coords::checked_new(
+            self.q() - other.q(),
+            self.r() - other.r(),
+            self.s() - other.s(),
+        )
     }
 
     pub fn q(&self) -> isize {
@@ -43,91 +92,325 @@ impl Coords {
         -self.q() - self.r()
     }
 
-    /// Returns the coordinates of the 6 direct neighbors, ordered clockwise starting from top.
-    pub fn neighbors6(&self) -> [Coords; 6] {
-        let (q, r, s) = (self.q(), self.r(), self.s());
-        [
-            Self::new(q + 0, r - 1, s + 1), // top
-            Self::new(q + 1, r - 1, s + 0), // top-right
-            Self::new(q + 1, r + 0, s - 1), // bot-right
-            Self::new(q + 0, r + 1, s - 1), // bot
-            Self::new(q - 1, r + 1, s + 0), // bot-left
-            Self::new(q - 1, r + 0, s + 1), // top-left
-        ]
+    /// Number of steps between `self` and `other`.
+    pub fn distance(&self, other: &Coords) -> usize {
+        let dq = (self.q() - other.q()).unsigned_abs();
+        let dr = (self.r() - other.r()).unsigned_abs();
+        let ds = (self.s() - other.s()).unsigned_abs();
+        (dq + dr + ds) / 2
     }
 
-    /// Returns the coordinates of the 18 closest neighbors in undefined ordered
-    pub fn neighbors18(&self) -> [Coords; 18] {
-        let (q, r, s) = (self.q(), self.r(), self.s());
-        [
-            Self::new(q + 0, r - 1, s + 1),
-            Self::new(q + 1, r - 1, s + 0),
-            Self::new(q + 1, r + 0, s - 1),
-            Self::new(q + 0, r + 1, s - 1),
-            Self::new(q - 1, r + 1, s + 0),
-            Self::new(q - 1, r + 0, s + 1),
-            Self::new(q + 0, r - 2, s + 2),
-            Self::new(q + 1, r - 2, s + 1),
-            Self::new(q + 2, r - 2, s + 0),
-            Self::new(q + 2, r - 1, s - 1),
-            Self::new(q + 2, r + 0, s - 2),
-            Self::new(q + 1, r + 1, s - 2),
-            Self::new(q + 0, r + 2, s - 2),
-            Self::new(q - 1, r + 2, s - 1),
-            Self::new(q - 2, r + 2, s + 0),
-            Self::new(q - 2, r + 1, s + 1),
-            Self::new(q - 2, r + 0, s + 2),
-            Self::new(q - 1, r - 1, s + 2),
-        ]
+    /// Returns the coordinates that are exactly `radius` steps away, ordered clockwise starting
+    /// from top. The ones out of the range of the coords are left out, see [checked_new].
+    pub fn ring(&self, radius: usize) -> Vec<Coords> {
+        if radius == 0 {
+            return vec![*self];
+        }
+        let radius = radius as isize;
+        // The 6 sides of the ring, each one being walked `radius` times
+        let directions = [
+            Direction::BottomRight,
+            Direction::Bottom,
+            Direction::BottomLeft,
+            Direction::TopLeft,
+            Direction::Top,
+            Direction::TopRight,
+        ];
+        let mut res = Vec::with_capacity(6 * radius as usize);
+        // Walked in `isize` since the corners of the ring may be out of range
+        let (mut q, mut r) = (self.q(), self.r() - radius);
+        for direction in directions {
+            let d = direction.offset();
+            for _ in 0..radius {
+                res.extend(Coords::checked_new(q, r, -q - r));
+                (q, r) = (q + d.q(), r + d.r());
+            }
+        }
+        res
+    }
+
+    /// Returns the coordinates of the 6 direct neighbors, ordered clockwise starting from top, as
+    /// `Direction::ALL`. `None` for the ones out of the range of the coords.
+    pub fn checked_neighbors6(&self) -> [Option<Coords>; 6] {
+        Direction::ALL.map(|direction| self.checked_add(direction.offset()))
+    }
+
+    /// Returns the coordinates of the direct neighbors, ordered clockwise starting from top. The
+    /// ones out of the range of the coords are left out, see [checked_neighbors6].
+    pub fn neighbors6(&self) -> Vec<Coords> {
+        self.checked_neighbors6()
+            .iter()
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    /// Same as [neighbors6] but each neighbor comes with its direction from `self`
+    pub fn neighbors6_labeled(&self) -> Vec<(Direction, Coords)> {
+        Direction::ALL
+            .iter()
+            .filter_map(|direction| Some((*direction, self.checked_add(direction.offset())?)))
+            .collect()
+    }
+
+    /// Returns the coordinates of the 18 closest neighbors in undefined ordered. The ones out of
+    /// the range of the coords are left out.
+    pub fn neighbors18(&self) -> Vec<Coords> {
+        let mut res = self.ring(1);
+        res.extend(self.ring(2));
+        res
+    }
+
+    /// The `(col, row)` offset coordinates of `self` in a flat-top layout, see
+    /// https://www.redblobgames.com/grids/hexagons/#conversions-offset
+    pub fn to_offset(&self, kind: OffsetKind) -> (i32, i32) {
+        let (q, r) = (self.q, self.r);
+        let (q, r) = (i32::from(q), i32::from(r));
+        let row = match kind {
+            OffsetKind::OddQ => r + (q - (q & 1)) / 2,
+            OffsetKind::EvenQ => r + (q + (q & 1)) / 2,
+        };
+        (q, row)
+    }
+
+    /// The inverse of [to_offset]
+    pub fn from_offset(kind: OffsetKind, col: i32, row: i32) -> Coords {
+        let r = match kind {
+            OffsetKind::OddQ => row - (col - (col & 1)) / 2,
+            OffsetKind::EvenQ => row - (col + (col & 1)) / 2,
+        };
+        let (q, r) = (col as isize, r as isize);
+        Coords::new(q, r, -q - r)
+    }
+}
+
+/// The offset coordinates layouts of flat-top hexagons: either the odd or the even columns are
+/// shoved down by half a cell.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OffsetKind {
+    OddQ,
+    EvenQ,
+}
+
+/// The directions of the 6 neighbors of a cell, see `Coords::neighbors6_labeled`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Direction {
+    Top,
+    TopRight,
+    BottomRight,
+    Bottom,
+    BottomLeft,
+    TopLeft,
+}
+
+impl Direction {
+    /// All the directions, ordered clockwise starting from top like `Coords::neighbors6`
+    pub const ALL: [Direction; 6] = [
+        Direction::Top,
+        Direction::TopRight,
+        Direction::BottomRight,
+        Direction::Bottom,
+        Direction::BottomLeft,
+        Direction::TopLeft,
+    ];
+
+    /// The offset from a cell to its neighbor in that direction
+    pub fn offset(self) -> Coords {
+        match self {
+            Direction::Top => Coords::new(0, -1, 1),
+            Direction::TopRight => Coords::new(1, -1, 0),
+            Direction::BottomRight => Coords::new(1, 0, -1),
+            Direction::Bottom => Coords::new(0, 1, -1),
+            Direction::BottomLeft => Coords::new(-1, 1, 0),
+            Direction::TopLeft => Coords::new(-1, 0, 1),
+        }
+    }
+
+    pub fn opposite(self) -> Direction {
+        Direction::ALL[(self as usize + 3) % 6]
+    }
+}
+
+impl fmt::Display for Coords {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({},{},{})", self.q(), self.r(), self.s())
+    }
+}
+
+/// Failure to parse a `Coords` from its `Display` form
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CoordsParseError {
+    /// The string is not of the form `(q,r,s)` with `q`, `r` and `s` integers
+    BadFormat(String),
+    /// `q + r + s != 0`
+    OffPlane { q: isize, r: isize, s: isize },
+}
+
+impl Error for CoordsParseError {}
+
+impl fmt::Display for CoordsParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CoordsParseError::BadFormat(s) => write!(f, "Expected coords as (q,r,s), got {:?}", s),
+            CoordsParseError::OffPlane { q, r, s } => write!(
+                f,
+                "Invalid coords ({},{},{}), q + r + s should be 0",
+                q, r, s
+            ),
+        }
+    }
+}
+
+impl FromStr for Coords {
+    type Err = CoordsParseError;
+
+    fn from_str(src: &str) -> Result<Coords, CoordsParseError> {
+        let bad_format = || CoordsParseError::BadFormat(src.to_string());
+        let inner = src
+            .trim()
+            .strip_prefix('(')
+            .and_then(|s| s.strip_suffix(')'))
+            .ok_or_else(bad_format)?;
+        let values = inner
+            .split(',')
+            .map(|s| s.trim().parse::<i16>().map(isize::from))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| bad_format())?;
+        let [q, r, s]: [isize; 3] = values.try_into().map_err(|_| bad_format())?;
+        if q + r + s != 0 {
+            return Err(CoordsParseError::OffPlane { q, r, s });
+        }
+        Ok(Coords::new(q, r, s))
     }
 }
 
+/// Panics on overflow like the integer operators, see [Coords::checked_add]
 impl std::ops::Add for Coords {
     type Output = Coords;
     fn add(self, other: Coords) -> Coords {
-        Coords::new(
-            self.q() + other.q(),
-            self.r() + other.r(),
-            self.s() + other.s(),
-        )
+        match self.checked_add(other) {
+            Some(coords) => coords,
+            None => panic!("Coords overflow in {} + {}", self, other),
+        }
     }
 }
 
+/// Panics on overflow like the integer operators, see [Coords::checked_sub]
 impl std::ops::Sub for Coords {
     type Output = Coords;
     fn sub(self, other: Coords) -> Coords {
-        Coords::new(
-            self.q() - other.q(),
-            self.r() - other.r(),
-            self.s() - other.s(),
-        )
+        match self.checked_sub(other) {
+            Some(coords) => coords,
+            None => panic!("Coords overflow in {} - {}", self, other),
+        }
     }
 }
 
+#[cfg(feature = "native")]
+/// The hex SHA-256 digest of the JSON serialization of `data`. The JSON is streamed into the
+/// hasher instead of being built as a `String` first, the digest is the same.
 pub fn sha256<T: Serialize>(data: &T) -> String {
-    let serialized_data = serde_json::to_string(data).expect("Failed to serialize the struct");
-    let digest =
-        crypto_hash::hex_digest(crypto_hash::Algorithm::SHA256, serialized_data.as_bytes());
+    let mut hasher = crypto_hash::Hasher::new(crypto_hash::Algorithm::SHA256);
+    {
+        let mut writer = BufWriter::new(&mut hasher);
+        serde_json::to_writer(&mut writer, data).expect("Failed to serialize the struct");
+        writer.flush().expect("Failed to hash the struct");
+    }
+    let mut digest = String::with_capacity(64);
+    for byte in hasher.finish() {
+        digest.push(char::from_digit((byte >> 4).into(), 16).expect("Unreachable"));
+        digest.push(char::from_digit((byte & 0xf).into(), 16).expect("Unreachable"));
+    }
     digest
 }
 
-pub fn get_url(url: &str) -> Result<String, Box<dyn Error>> {
+#[cfg(feature = "native")]
+/// How [get_url] performs its requests
+#[derive(Clone, Debug)]
+pub struct FetchOptions {
+    /// Number of requests sent before giving up, at least 1
+    pub max_attempts: u32,
+    /// Delay before the first retry, it doubles after each retry. A `Retry-After` header in the
+    /// response takes precedence.
+    pub initial_backoff: Duration,
+    pub user_agent: String,
+}
+
+#[cfg(feature = "native")]
+pub const DEFAULT_USER_AGENT: &str = "hexcells_solver/0.1";
+
+#[cfg(feature = "native")]
+impl Default for FetchOptions {
+    fn default() -> FetchOptions {
+        FetchOptions {
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+        }
+    }
+}
+
+#[cfg(feature = "native")]
+/// The `Retry-After` header of `response`, only the delay-seconds form is supported
+fn retry_after(response: &reqwest::blocking::Response) -> Option<Duration> {
+    let value = response.headers().get(reqwest::header::RETRY_AFTER)?;
+    let seconds = value.to_str().ok()?.trim().parse().ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+#[cfg(feature = "native")]
+/// Fetch `url`. The network errors, the `429 Too Many Requests` and the server errors are retried
+/// with an exponential backoff, the last error is returned once `options.max_attempts` is reached.
+pub fn get_url(url: &str, options: &FetchOptions) -> Result<String, Box<dyn Error>> {
     let client = reqwest::blocking::Client::new();
     let mut headers = reqwest::header::HeaderMap::new();
     headers.insert(
-        "User-Agent",
-        reqwest::header::HeaderValue::from_static("MyUserAgent"),
+        reqwest::header::USER_AGENT,
+        reqwest::header::HeaderValue::from_str(&options.user_agent)?,
     );
-    let html = client.get(url).headers(headers).send()?.text()?;
-    Ok(html)
+    let mut backoff = options.initial_backoff;
+    let mut attempt = 1;
+    loop {
+        let (err, delay): (Box<dyn Error>, _) =
+            match client.get(url).headers(headers.clone()).send() {
+                Ok(response) if response.status().is_success() => return Ok(response.text()?),
+                Ok(response) => {
+                    let status = response.status();
+                    let delay = retry_after(&response);
+                    let err = response.error_for_status().expect_err("Unreachable");
+                    if status != reqwest::StatusCode::TOO_MANY_REQUESTS && !status.is_server_error()
+                    {
+                        return Err(Box::new(err));
+                    }
+                    (Box::new(err), delay)
+                }
+                Err(err) => (Box::new(err), None),
+            };
+        if attempt >= options.max_attempts {
+            return Err(err);
+        }
+        thread::sleep(delay.unwrap_or(backoff));
+        backoff *= 2;
+        attempt += 1;
+    }
 }
 
-pub fn get_url_with_cache(url: &String, cache_dir: &str) -> Result<String, Box<dyn Error>> {
-    with_cache(url, || get_url(url), cache_dir)
+#[cfg(feature = "native")]
+pub fn get_url_with_cache(
+    url: &String,
+    cache_dir: &str,
+    options: &FetchOptions,
+) -> Result<String, Box<dyn Error>> {
+    with_cache(url, || get_url(url, options), cache_dir)
 }
 
+#[cfg(feature = "native")]
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(feature = "native")]
 /// Either call `compute` and cache on `Ok` or fetch from cache
-/// Not thread safe
+/// Thread safe: concurrent calls with the same key may all call `compute`, the last one to finish
+/// wins the cache entry.
 pub fn with_cache<F, T, U>(key: &T, compute: F, cache_dir: &str) -> Result<U, Box<dyn Error>>
 where
     F: FnOnce() -> Result<U, Box<dyn Error>>,
@@ -153,10 +436,15 @@ where
     };
     let res = compute()?;
     let json = serde_json::to_string(&res)?;
-    // Write file to a tmp-named file (not thread safe), and then perform an atomic rename. It's
-    // necessary in order to avoid cache corruption in case of ctrl-c during cache writing.
+    // Write file to a tmp-named file, and then perform an atomic rename. It's necessary in order to
+    // avoid cache corruption in case of ctrl-c during cache writing. The tmp name is unique to this
+    // call so that concurrent writers (threads or processes) don't step on each other.
     let mut tmppath = PathBuf::from(cache_dir);
-    tmppath.push("tmp");
+    tmppath.push(format!(
+        "tmp-{}-{}",
+        process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
     {
         let mut file = File::create(&tmppath)?;
         file.write_all(json.as_bytes())?;
@@ -165,14 +453,17 @@ where
     Ok(res)
 }
 
-pub fn n_choose_k(n: u64, mut k: u64) -> Option<u64> {
+/// The binomial coefficient. Computed over `u128` so that it doesn't overflow for the biggest
+/// scopes found in actual puzzles, `None` is still returned if it does.
+pub fn n_choose_k(n: u64, k: u64) -> Option<u128> {
     if k > n {
         panic!("Bad call to n_choose_k")
     };
+    let (n, mut k) = (n as u128, k as u128);
     if k > n - k {
         k = n - k;
     }
-    let mut result: u64 = 1;
+    let mut result: u128 = 1;
     for i in 0..k {
         let fact = n - i;
         let quot = i + 1;
@@ -186,7 +477,29 @@ pub fn n_choose_k(n: u64, mut k: u64) -> Option<u64> {
 
 #[cfg(test)]
 mod tests {
+    #[cfg(feature = "native")]
+    use misc::get_url;
     use misc::n_choose_k;
+    #[cfg(feature = "native")]
+    use misc::sha256;
+    #[cfg(feature = "native")]
+    use misc::with_cache;
+    use misc::Coords;
+    use misc::CoordsParseError;
+    #[cfg(feature = "native")]
+    use misc::FetchOptions;
+    use misc::OffsetKind;
+    use std::convert::TryFrom;
+    #[cfg(feature = "native")]
+    use std::fs;
+    #[cfg(feature = "native")]
+    use std::io::Read;
+    #[cfg(feature = "native")]
+    use std::io::Write;
+    #[cfg(feature = "native")]
+    use std::thread;
+    #[cfg(feature = "native")]
+    use std::time::Duration;
 
     #[test]
     pub fn test_n_choose_k() {
@@ -203,5 +516,270 @@ mod tests {
         assert_eq!(n_choose_k(7, 5).unwrap(), 21);
         assert_eq!(n_choose_k(7, 6).unwrap(), 7);
         assert_eq!(n_choose_k(7, 7).unwrap(), 1);
+        assert_eq!(n_choose_k(30, 15).unwrap(), 155117520);
+        assert_eq!(n_choose_k(68, 34).unwrap(), 28453041475240576740);
+        assert_eq!(n_choose_k(100, 50).unwrap(), 100891344545564193334812497256);
+    }
+
+    #[test]
+    pub fn test_distance() {
+        let c = Coords::new(0, 0, 0);
+        assert_eq!(c.distance(&c), 0);
+        assert_eq!(c.distance(&Coords::new(0, -1, 1)), 1);
+        assert_eq!(c.distance(&Coords::new(2, -1, -1)), 2);
+        assert_eq!(c.distance(&Coords::new(3, -3, 0)), 3);
+        assert_eq!(Coords::new(-2, 5, -3).distance(&Coords::new(4, -1, -3)), 6);
+        assert_eq!(Coords::new(1, 2, -3).distance(&Coords::new(-3, 1, 2)), 5);
+    }
+
+    #[test]
+    pub fn test_ring() {
+        let c = Coords::new(1, -3, 2);
+        assert_eq!(c.ring(0), vec![c]);
+        for radius in 1..5 {
+            let ring = c.ring(radius);
+            assert_eq!(ring.len(), 6 * radius);
+            assert_eq!(
+                ring[0],
+                c + Coords::new(0, -(radius as isize), radius as isize)
+            );
+            for (i, c1) in ring.iter().enumerate() {
+                assert_eq!(c.distance(c1), radius);
+                // Clockwise and contiguous
+                assert_eq!(c1.distance(&ring[(i + 1) % ring.len()]), 1);
+            }
+        }
+        let top_right = c + Coords::new(1, -1, 0);
+        assert_eq!(c.neighbors6()[1], top_right);
+        for (i, (direction, c1)) in c.neighbors6_labeled().iter().enumerate() {
+            assert_eq!(*c1, c.neighbors6()[i]);
+            assert_eq!(c1.ring(1)[(i + 3) % 6], c);
+            assert_eq!(*c1 + direction.opposite().offset(), c);
+        }
+        assert_eq!(c.neighbors18().len(), 18);
+    }
+
+    #[test]
+    pub fn test_offset() {
+        let c = Coords::new(0, 0, 0);
+        let bottom_right = Coords::new(1, 0, -1);
+        assert_eq!(c.to_offset(OffsetKind::OddQ), (0, 0));
+        assert_eq!(bottom_right.to_offset(OffsetKind::OddQ), (1, 0));
+        assert_eq!(bottom_right.to_offset(OffsetKind::EvenQ), (1, 1));
+        assert_eq!(Coords::new(-1, 0, 1).to_offset(OffsetKind::OddQ), (-1, -1));
+        for kind in [OffsetKind::OddQ, OffsetKind::EvenQ] {
+            for q in -5..5 {
+                for r in -5..5 {
+                    let c = Coords::new(q, r, -q - r);
+                    let (col, row) = c.to_offset(kind);
+                    assert_eq!(Coords::from_offset(kind, col, row), c);
+                }
+            }
+            // Within a column, the rows are the cells towards bottom
+            let (col, row) = c.to_offset(kind);
+            assert_eq!(
+                Coords::from_offset(kind, col, row + 1),
+                c + Coords::new(0, 1, -1)
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_checked_coords() {
+        assert_eq!(Coords::checked_new(1, 2, -3), Some(Coords::new(1, 2, -3)));
+        assert_eq!(Coords::checked_new(1, 2, 3), None);
+        assert_eq!(Coords::checked_new(isize::MAX, 1, isize::MIN), None);
+        let max = i16::MAX as isize;
+        assert_eq!(Coords::checked_new(max + 1, -max, -1), None);
+        // `s` isn't stored
+        let corner = Coords::new(max, max, -2 * max);
+        assert_eq!(corner.s(), -2 * max);
+        let bottom = Coords::new(0, 1, -1);
+        assert_eq!(corner.checked_add(bottom), None);
+        assert_eq!(
+            corner.checked_sub(bottom),
+            Some(Coords::new(max, max - 1, 1 - 2 * max))
+        );
+        let result = std::panic::catch_unwind(|| corner + bottom);
+        assert!(result.is_err());
+    }
+
+    /// Any `i16`, with a bias towards both ends of the range
+    fn near_edges() -> impl proptest::strategy::Strategy<Value = i16> {
+        prop_oneof![
+            proptest::num::i16::ANY,
+            (i16::MIN..i16::MIN + 3),
+            (i16::MAX - 2..=i16::MAX),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn test_checked_coords_arithmetic(q0: i16, r0: i16, q1: i16, r1: i16) {
+            let (q0, r0, q1, r1) = (q0 as isize, r0 as isize, q1 as isize, r1 as isize);
+            let c0 = Coords::new(q0, r0, -q0 - r0);
+            let c1 = Coords::new(q1, r1, -q1 - r1);
+            let in_range = |x: isize| i16::try_from(x).is_ok();
+            let sum = c0.checked_add(c1);
+            prop_assert_eq!(sum.is_some(), in_range(q0 + q1) && in_range(r0 + r1));
+            if let Some(sum) = sum {
+                prop_assert_eq!(sum.s(), c0.s() + c1.s());
+                prop_assert_eq!(sum - c1, c0);
+                prop_assert_eq!(sum, c0 + c1);
+            }
+            let diff = c0.checked_sub(c1);
+            prop_assert_eq!(diff.is_some(), in_range(q0 - q1) && in_range(r0 - r1));
+            if let Some(diff) = diff {
+                prop_assert_eq!(diff + c1, c0);
+                prop_assert_eq!(c0.distance(&c1), diff.distance(&Coords::new(0, 0, 0)));
+            }
+        }
+
+        #[test]
+        fn test_checked_neighbors(q in near_edges(), r in near_edges()) {
+            let (q, r) = (q as isize, r as isize);
+            let c = Coords::new(q, r, -q - r);
+            let in_range = |c1: &(isize, isize)| {
+                i16::try_from(c1.0).is_ok() && i16::try_from(c1.1).is_ok()
+            };
+            let around = |radius: isize| {
+                let mut around = vec![];
+                for dq in -radius..=radius {
+                    for dr in -radius..=radius {
+                        if (dq.abs() + dr.abs() + (dq + dr).abs()) / 2 == radius {
+                            around.push((q + dq, r + dr));
+                        }
+                    }
+                }
+                around.iter().filter(|c1| in_range(c1)).count()
+            };
+            let neighbors6 = c.neighbors6();
+            prop_assert_eq!(neighbors6.len(), around(1));
+            prop_assert!(neighbors6.iter().all(|c1| c.distance(c1) == 1));
+            prop_assert_eq!(c.neighbors6_labeled().len(), around(1));
+            prop_assert_eq!(c.checked_neighbors6().iter().flatten().count(), around(1));
+            let neighbors18 = c.neighbors18();
+            prop_assert_eq!(neighbors18.len(), around(1) + around(2));
+            prop_assert!(neighbors18.iter().all(|c1| (1..=2).contains(&c.distance(c1))));
+        }
+    }
+
+    #[test]
+    pub fn test_coords_of_string() {
+        let c = Coords::new(3, -5, 2);
+        assert_eq!(c.to_string(), "(3,-5,2)");
+        assert_eq!("(3,-5,2)".parse::<Coords>(), Ok(c));
+        assert_eq!(" ( 3, -5 ,2 ) ".parse::<Coords>(), Ok(c));
+        for c in Coords::new(0, 0, 0).neighbors18() {
+            assert_eq!(c.to_string().parse::<Coords>(), Ok(c));
+        }
+        assert_eq!(
+            "(1,1,1)".parse::<Coords>(),
+            Err(CoordsParseError::OffPlane { q: 1, r: 1, s: 1 })
+        );
+        for src in [
+            "",
+            "()",
+            "(1,-1)",
+            "1,-1,0",
+            "(1,-1,0,0)",
+            "(a,b,c)",
+            "(99999,0,-99999)",
+        ] {
+            assert_eq!(
+                src.parse::<Coords>(),
+                Err(CoordsParseError::BadFormat(src.to_string()))
+            );
+        }
+    }
+
+    /// Serve `responses` to the successive connections on a local port, return the url
+    #[cfg(feature = "native")]
+    fn serve(responses: Vec<&'static str>) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        url
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    pub fn test_get_url_retry() {
+        const TOO_MANY: &str = "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 0\r\n\
+                                Content-Length: 0\r\nConnection: close\r\n\r\n";
+        const NOT_FOUND: &str =
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        const OK: &str = "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello";
+        let options = FetchOptions {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            ..FetchOptions::default()
+        };
+
+        let url = serve(vec![TOO_MANY, TOO_MANY, OK]);
+        assert_eq!(get_url(&url, &options).unwrap(), "hello");
+
+        let url = serve(vec![TOO_MANY, TOO_MANY, TOO_MANY]);
+        assert!(get_url(&url, &options).is_err());
+
+        // Not retried
+        let url = serve(vec![NOT_FOUND, OK]);
+        assert!(get_url(&url, &options).is_err());
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    pub fn test_sha256() {
+        // The cache keys don't change with the streaming
+        let data = (vec![Coords::new(1, -1, 0); 5000], "key");
+        let json = serde_json::to_string(&data).unwrap();
+        assert_eq!(
+            sha256(&data),
+            crypto_hash::hex_digest(crypto_hash::Algorithm::SHA256, json.as_bytes())
+        );
+        assert_eq!(
+            sha256(&Vec::<Coords>::new()),
+            "4f53cda18c2baa0c0354bb5f9a3ecbe5ed12ab4d8e11ba873c2f11161202b945"
+        );
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    pub fn test_with_cache_concurrent() {
+        let mut cache_dir = std::env::temp_dir();
+        cache_dir.push(format!("hexcells-solver-test-cache-{}", std::process::id()));
+        let cache_dir = cache_dir.to_str().unwrap().to_string();
+        let _ = fs::remove_dir_all(&cache_dir);
+
+        let handles: Vec<_> = (0..16)
+            .map(|_| {
+                let cache_dir = cache_dir.clone();
+                thread::spawn(move || {
+                    (0..20)
+                        .map(|_| with_cache(&"key", || Ok(vec![42u32; 1000]), &cache_dir).unwrap())
+                        .collect::<Vec<Vec<u32>>>()
+                })
+            })
+            .collect();
+        for handle in handles {
+            for res in handle.join().unwrap() {
+                assert_eq!(res, vec![42u32; 1000]);
+            }
+        }
+
+        // A single entry and no leftover tmp files
+        assert_eq!(fs::read_dir(&cache_dir).unwrap().count(), 1);
+        fs::remove_dir_all(&cache_dir).unwrap();
     }
 }
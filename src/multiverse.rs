@@ -1,8 +1,17 @@
+use itertools::Itertools;
+use serde::Deserialize;
+use serde::Serialize;
+use std::borrow::Borrow;
+use std::cmp::Reverse;
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
+use std::collections::HashSet;
+use std::convert::TryFrom;
 use std::convert::TryInto;
+use std::error::Error;
 
 use defn::Color;
+use env::Env;
 use misc;
 use misc::Coords;
 
@@ -17,27 +26,84 @@ use misc::Coords;
 /// {e, f}: 2  // Both `e, f` are blue
 /// n: k       // `k` of the `n` coordinates are blue.
 ///               (i.e. n.len() choose k combinations)
-#[derive(Debug, Clone)]
+/// Serialized as an array of `{cells, blue_count}` objects, JSON object keys can't be sets.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "Vec<BinomialCoef>", try_from = "Vec<BinomialCoef>")]
 pub struct Layout {
     pub binomial_coefs: BTreeMap<BTreeSet<Coords>, u16>,
 }
 
+/// Serialized form of one entry of `Layout::binomial_coefs`
+#[derive(Serialize, Deserialize)]
+struct BinomialCoef {
+    cells: BTreeSet<Coords>,
+    blue_count: u16,
+}
+
+impl From<Layout> for Vec<BinomialCoef> {
+    fn from(layout: Layout) -> Vec<BinomialCoef> {
+        layout
+            .binomial_coefs
+            .into_iter()
+            .map(|(cells, blue_count)| BinomialCoef { cells, blue_count })
+            .collect()
+    }
+}
+
+impl TryFrom<Vec<BinomialCoef>> for Layout {
+    type Error = String;
+
+    fn try_from(coefs: Vec<BinomialCoef>) -> Result<Layout, String> {
+        let len = coefs.len();
+        let binomial_coefs: BTreeMap<_, _> = coefs
+            .into_iter()
+            .map(|coef| (coef.cells, coef.blue_count))
+            .collect();
+        if binomial_coefs.len() != len {
+            return Err("duplicate coords_set in input layout".to_string());
+        }
+        Layout::check(&binomial_coefs)?;
+        Ok(Layout { binomial_coefs })
+    }
+}
+
 impl Layout {
     pub fn new(binomial_coefs: BTreeMap<BTreeSet<Coords>, u16>) -> Layout {
+        if let Err(err) = Layout::check(&binomial_coefs) {
+            panic!("{}", err)
+        }
+        Layout { binomial_coefs }
+    }
+
+    fn check(binomial_coefs: &BTreeMap<BTreeSet<Coords>, u16>) -> Result<(), String> {
         let mut seen = BTreeSet::new();
-        for (coords_set, blue_count) in &binomial_coefs {
-            assert_ne!(coords_set.len(), 0, "empty coords_set in input layout");
-            assert!((*blue_count) as usize <= coords_set.len());
+        for (coords_set, blue_count) in binomial_coefs {
+            if coords_set.is_empty() {
+                return Err("empty coords_set in input layout".to_string());
+            }
+            if *blue_count as usize > coords_set.len() {
+                return Err("blue_count larger than its coords_set in input layout".to_string());
+            }
             for coords in coords_set {
-                assert!(!seen.contains(coords), "duplicate coords in input layout");
-                seen.insert(coords);
+                if !seen.insert(coords) {
+                    return Err("duplicate coords in input layout".to_string());
+                }
             }
         }
-        Layout { binomial_coefs }
+        Ok(())
     }
 
-    pub fn solution_count(&self) -> Option<u64> {
-        let mut i: u64 = 1;
+    /// The union of the keys
+    fn coords(&self) -> BTreeSet<Coords> {
+        self.binomial_coefs
+            .keys()
+            .fold(BTreeSet::new(), |acc, set| {
+                acc.union(set).cloned().collect()
+            })
+    }
+
+    pub fn solution_count(&self) -> Option<u128> {
+        let mut i: u128 = 1;
         for (coords_set, blue_count) in &self.binomial_coefs {
             let fact = misc::n_choose_k(coords_set.len().try_into().unwrap(), *blue_count as u64);
             match fact.and_then(|fact| i.checked_mul(fact)) {
@@ -48,6 +114,37 @@ impl Layout {
         Some(i)
     }
 
+    /// Same as [solution_count] but using floats, which can't overflow
+    fn solution_count_f64(&self) -> f64 {
+        let mut res = 1.0;
+        for (coords_set, blue_count) in &self.binomial_coefs {
+            let n = coords_set.len() as f64;
+            for i in 0..(*blue_count as u32) {
+                let i = i as f64;
+                res = res * (n - i) / (i + 1.0);
+            }
+        }
+        res
+    }
+
+    /// Enumerate the solutions of the layout, each one being represented by its set of blue
+    /// coords.
+    fn blue_sets(&self) -> Vec<BTreeSet<Coords>> {
+        let mut res = vec![BTreeSet::new()];
+        for (coords_set, blue_count) in &self.binomial_coefs {
+            let mut res2 = vec![];
+            for blues in coords_set.iter().combinations(*blue_count as usize) {
+                for acc in &res {
+                    let mut acc = acc.clone();
+                    acc.extend(blues.iter().cloned());
+                    res2.push(acc);
+                }
+            }
+            res = res2;
+        }
+        res
+    }
+
     /// Test if two Layouts share the same keys on their intersection
     fn aligned_with(&self, other: &Layout) -> bool {
         let mut left_key_per_coords = BTreeMap::new();
@@ -59,12 +156,8 @@ impl Layout {
         for kright in other.binomial_coefs.keys() {
             for c in kright {
                 match left_key_per_coords.get(c) {
-                    None => (),
-                    Some(kleft) => {
-                        if kleft != &kright {
-                            return false;
-                        }
-                    }
+                    Some(kleft) if kleft != &kright => return false,
+                    _ => (),
                 }
             }
         }
@@ -106,7 +199,13 @@ impl Layout {
     }
 
     /// Fork all the same-keyed Layouts in the input Vec<Layout> so that they contain new_key.
-    fn split(layouts: &Vec<Layout>, new_key: &BTreeSet<Coords>) -> Vec<Layout> {
+    /// `check_count` is called with the number of layouts produced so far, the fork stops as soon
+    /// as it fails.
+    fn split(
+        layouts: &Vec<Layout>,
+        new_key: &BTreeSet<Coords>,
+        check_count: &dyn Fn(usize) -> Result<(), Box<dyn Error>>,
+    ) -> Result<Vec<Layout>, Box<dyn Error>> {
         let mut res = vec![];
         for lay in layouts {
             let old_key = lay
@@ -123,25 +222,30 @@ impl Layout {
             assert!(!new_key2.is_empty());
             let mut bc = lay.binomial_coefs.clone();
             let blue_count = bc.remove(old_key).expect("Unreachable");
-            let mut pushed = 0;
-            for i in 0..=blue_count {
-                let j = blue_count - i;
-                if i as usize <= new_key.len() && j as usize <= new_key2.len() {
-                    let mut bc = bc.clone();
-                    bc.insert(new_key.clone(), i);
-                    bc.insert(new_key2.clone(), j);
-                    res.push(Layout::new(bc));
-                    pushed += 1;
-                }
+            // `i` blues in `new_key` and the others in `new_key2`, which both bound `i`
+            let new_len = new_key.len() as u16;
+            let new_len2 = new_key2.len() as u16;
+            let i_min = blue_count.saturating_sub(new_len2);
+            let i_max = blue_count.min(new_len);
+            assert!(i_min <= i_max);
+            for i in i_min..=i_max {
+                let mut bc = bc.clone();
+                bc.insert(new_key.clone(), i);
+                bc.insert(new_key2.clone(), blue_count - i);
+                res.push(Layout::new(bc));
             }
-            assert!(pushed != 0);
+            check_count(res.len())?;
         }
-        res
+        Ok(res)
     }
 
     /// Fork a layout to make it compatible with the keys of another Layout. That other Layout will
     /// need to undergo the symmetrical operation.
-    fn align_with_keys(&self, right_keys: &BTreeSet<BTreeSet<Coords>>) -> Vec<Layout> {
+    fn align_with_keys(
+        &self,
+        right_keys: &BTreeSet<BTreeSet<Coords>>,
+        check_count: &dyn Fn(usize) -> Result<(), Box<dyn Error>>,
+    ) -> Result<Vec<Layout>, Box<dyn Error>> {
         let mut res = vec![(*self).clone()];
         for left_key in self.binomial_coefs.keys() {
             for right_key in right_keys {
@@ -152,10 +256,10 @@ impl Layout {
                 if left_key == &inter {
                     continue;
                 }
-                res = Self::split(&res, &inter);
+                res = Self::split(&res, &inter, check_count)?;
             }
         }
-        res
+        Ok(res)
     }
 
     /// Reshape two layouts to give them the same keys on their intersection.
@@ -165,11 +269,15 @@ impl Layout {
     /// - If `a` and `b` are already aligned, `va = vec![a]` and `vb = vec![vb]`.
     /// - All the Layouts in `va` have the same keys (the same goes for `vb`).
     /// - The number of solutions is identical in `a` and `va` (the same foes for `b` and `vb`).
-    fn align(&self, other: &Layout) -> (Vec<Layout>, Vec<Layout>) {
+    fn align(
+        &self,
+        other: &Layout,
+        check_count: &dyn Fn(usize) -> Result<(), Box<dyn Error>>,
+    ) -> Result<(Vec<Layout>, Vec<Layout>), Box<dyn Error>> {
         let left_keys: BTreeSet<_> = self.binomial_coefs.keys().cloned().collect();
         let right_keys: BTreeSet<_> = other.binomial_coefs.keys().cloned().collect();
-        let left = self.align_with_keys(&right_keys);
-        let right = other.align_with_keys(&left_keys);
+        let left = self.align_with_keys(&right_keys, check_count)?;
+        let right = other.align_with_keys(&left_keys, check_count)?;
         assert!(Self::are_aligned(&left, &right));
 
         // The following assert crashes because of https://www.reddit.com/r/hexcellslevels/comments/pnhjef/level_divided_easy/
@@ -189,20 +297,24 @@ impl Layout {
         //             .and_then(|a: u64| b.and_then(|b: u64| a.checked_add(b))))
         // );
 
-        (left, right)
+        Ok((left, right))
     }
 
-    fn merge(&self, other: &Layout) -> Vec<Layout> {
+    fn merge(
+        &self,
+        other: &Layout,
+        check_count: &dyn Fn(usize) -> Result<(), Box<dyn Error>>,
+    ) -> Result<Vec<Layout>, Box<dyn Error>> {
         let mut res = vec![];
-        let (left_lays, right_lays) = self.align(other);
+        let (left_lays, right_lays) = self.align(other, check_count)?;
         let left_keys: BTreeSet<_> = left_lays
-            .get(0)
+            .first()
             .expect("Left can't be empty here")
             .binomial_coefs
             .keys()
             .collect();
         let right_keys: BTreeSet<_> = right_lays
-            .get(0)
+            .first()
             .expect("Right can't be empty here")
             .binomial_coefs
             .keys()
@@ -222,7 +334,7 @@ impl Layout {
                 }
             }
         }
-        res
+        Ok(res)
     }
 }
 
@@ -233,26 +345,51 @@ pub enum State {
     Empty,
 }
 
+/// Above this number of solutions, a multiverse is considered too big to be enumerated
+const ENUMERATION_LIMIT: u128 = 1 << 20;
+
 /// A Multiverse gathers all the possible permutations that a given set of coords (i.e. scope) may take.
 /// If `mv.solution_count_upper_bound() == 1`, there is no uncertainty within `mv`.
 /// If `mv.invariants().is_empty()`, there is no certainty within `mv`.
 /// Two differents layout in a multiverse are two ways to describe permutations of the same set of coords (i.e. the scope).
 /// Two layouts in a multiverse may describe overlapping sets of results, hence the fact that [solution_count_upper_bound] doesn't give the exact number of solutions.
 /// A multiverse may have no solutions (i.e. `State::Stuck`)
-#[derive(Debug, Clone)]
+/// The layouts of a multiverse are all different, the duplicates (that the merges may produce)
+/// are dropped by [new].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "MultiverseRepr")]
 pub struct Multiverse {
     pub scope: BTreeSet<Coords>,
     pub layouts: Vec<Layout>,
 }
 
+/// Unchecked deserialized form of `Multiverse`
+#[derive(Deserialize)]
+struct MultiverseRepr {
+    scope: BTreeSet<Coords>,
+    layouts: Vec<Layout>,
+}
+
+impl TryFrom<MultiverseRepr> for Multiverse {
+    type Error = String;
+
+    fn try_from(repr: MultiverseRepr) -> Result<Multiverse, String> {
+        if repr.layouts.iter().any(|lay| lay.coords() != repr.scope) {
+            return Err("layout not covering the scope of the multiverse".to_string());
+        }
+        Ok(Multiverse::new(repr.scope, repr.layouts))
+    }
+}
+
 impl Multiverse {
-    pub fn new(scope: BTreeSet<Coords>, layouts: Vec<Layout>) -> Multiverse {
+    pub fn new(scope: BTreeSet<Coords>, mut layouts: Vec<Layout>) -> Multiverse {
         for lay in &layouts {
-            let lay_coords = lay.binomial_coefs.keys().fold(BTreeSet::new(), |acc, set| {
-                acc.union(set).cloned().collect()
-            });
-            assert_eq!(lay_coords, scope);
+            assert_eq!(lay.coords(), scope);
         }
+        let mut seen = HashSet::new();
+        let is_first: Vec<bool> = layouts.iter().map(|lay| seen.insert(lay)).collect();
+        let mut is_first = is_first.into_iter();
+        layouts.retain(|_| is_first.next().expect("Unreachable"));
         Multiverse { scope, layouts }
     }
 
@@ -260,8 +397,32 @@ impl Multiverse {
         Multiverse::new(BTreeSet::new(), vec![])
     }
 
-    pub fn solution_count_upper_bound(&self) -> Option<u64> {
-        let mut i: u64 = 0;
+    /// The cells of the scope, sorted by `q` and then by `r` (the order of `Coords`). This order
+    /// doesn't depend on how the multiverse was built.
+    pub fn scope_iter(&self) -> impl Iterator<Item = &Coords> {
+        self.scope.iter()
+    }
+
+    /// The corners of the bounding box of the scope: the lowest `q` and `r`, and the highest `q`
+    /// and `r`. `None` if the scope is empty.
+    pub fn bounds(&self) -> Option<(Coords, Coords)> {
+        let (first, last) = (self.scope.first()?, self.scope.last()?);
+        let (min_q, max_q) = (first.q(), last.q());
+        let (min_r, max_r) = self
+            .scope
+            .iter()
+            .map(|coords| coords.r())
+            .minmax()
+            .into_option()
+            .expect("Unreachable");
+        Some((
+            Coords::new(min_q, min_r, -min_q - min_r),
+            Coords::new(max_q, max_r, -max_q - max_r),
+        ))
+    }
+
+    pub fn solution_count_upper_bound(&self) -> Option<u128> {
+        let mut i: u128 = 0;
         for lay in &self.layouts {
             match lay.solution_count().and_then(|x| i.checked_add(x)) {
                 None => return None,
@@ -273,6 +434,101 @@ impl Multiverse {
         Some(i)
     }
 
+    /// The distinct solutions of the multiverse, each one being represented by its set of blue
+    /// coords. Returns `None` if the multiverse is too big for its solutions to be enumerated (see
+    /// `ENUMERATION_LIMIT`).
+    fn blue_sets(&self) -> Option<BTreeSet<BTreeSet<Coords>>> {
+        match self.solution_count_upper_bound() {
+            Some(count) if count <= ENUMERATION_LIMIT => (),
+            _ => return None,
+        }
+        let mut seen = BTreeSet::new();
+        for lay in &self.layouts {
+            seen.extend(lay.blue_sets());
+        }
+        Some(seen)
+    }
+
+    /// Same as [solution_count_upper_bound] but doesn't count twice the solutions that are
+    /// shared by several layouts. Returns `None` if the multiverse is too big for its solutions to
+    /// be enumerated (see `ENUMERATION_LIMIT`).
+    pub fn solution_count_exact(&self) -> Option<u128> {
+        if self.layouts.len() <= 1 {
+            return self.solution_count_upper_bound();
+        }
+        self.blue_sets().map(|blue_sets| blue_sets.len() as u128)
+    }
+
+    /// Same as [solution_count_exact] but stops counting at `cap`. It never gives up: the solutions
+    /// are only enumerated for the layouts that have fewer than `cap` of them, a bigger layout
+    /// alone reaches the cap.
+    pub fn solution_count_capped(&self, cap: u128) -> u128 {
+        if self.scope.is_empty() {
+            return cap.min(1);
+        }
+        let mut seen = BTreeSet::new();
+        for lay in &self.layouts {
+            match lay.solution_count() {
+                Some(count) if count < cap => (),
+                _ => return cap,
+            }
+            for blues in lay.blue_sets() {
+                seen.insert(blues);
+                if seen.len() as u128 >= cap {
+                    return cap;
+                }
+            }
+        }
+        seen.len() as u128
+    }
+
+    /// Enumerate the distinct solutions of the multiverse. Returns `None` if the multiverse is too
+    /// big for its solutions to be enumerated (see `ENUMERATION_LIMIT`).
+    pub fn solutions(&self) -> Option<impl Iterator<Item = BTreeMap<Coords, Color>>> {
+        let scope = self.scope.clone();
+        let blue_sets = self.blue_sets()?;
+        Some(blue_sets.into_iter().map(move |blues| {
+            scope
+                .iter()
+                .map(|coords| {
+                    let color = if blues.contains(coords) {
+                        Color::Blue
+                    } else {
+                        Color::Black
+                    };
+                    (*coords, color)
+                })
+                .collect()
+        }))
+    }
+
+    /// For each coords of the scope, the fraction of the solutions in which it is blue. The
+    /// invariants of the multiverse are exactly 0.0 or 1.0. Layouts are assumed to not overlap.
+    /// The result is empty if the multiverse is stuck (i.e. empty layouts).
+    pub fn marginals(&self) -> BTreeMap<Coords, f64> {
+        if self.layouts.is_empty() {
+            return BTreeMap::new();
+        }
+        let mut blue_weights: BTreeMap<Coords, f64> = BTreeMap::new();
+        let mut total_weight = 0.0;
+        for lay in &self.layouts {
+            // Each coords of a `coords_set` is blue in `blue_count / coords_set.len()` of the
+            // solutions of `lay`.
+            let weight = lay.solution_count_f64();
+            for (coords_set, blue_count) in &lay.binomial_coefs {
+                let ratio = *blue_count as f64 / coords_set.len() as f64;
+                for coords in coords_set {
+                    *blue_weights.entry(*coords).or_default() += weight * ratio;
+                }
+            }
+            total_weight += weight;
+        }
+        blue_weights
+            .into_iter()
+            .map(|(coords, blue_weight)| (coords, blue_weight / total_weight))
+            .collect()
+    }
+
     pub fn state(&self) -> State {
         match (self.scope.is_empty(), self.layouts.is_empty()) {
             (true, true) => State::Empty,
@@ -330,49 +586,312 @@ impl Multiverse {
     }
 
     pub fn merge(&self, other: &Multiverse) -> Multiverse {
+        self.merge_polling(other, || Ok(()), &|_| Ok(()))
+            .expect("Unreachable")
+    }
+
+    /// Same as [merge] but checks `env` while building the cross-product of the layouts, so that
+    /// a merge that explodes stops shortly after the timeout (or the cancellation), or as soon as
+    /// forking a layout produces more layouts than `Env::layout_cap`.
+    pub fn merge_checked(
+        &self,
+        other: &Multiverse,
+        env: &Env,
+    ) -> Result<Multiverse, Box<dyn Error>> {
+        self.merge_polling(other, || env.check_abort(), &|count| {
+            env.check_layout_count(count)
+        })
+    }
+
+    fn merge_polling<F>(
+        &self,
+        other: &Multiverse,
+        poll: F,
+        check_count: &dyn Fn(usize) -> Result<(), Box<dyn Error>>,
+    ) -> Result<Multiverse, Box<dyn Error>>
+    where
+        F: Fn() -> Result<(), Box<dyn Error>>,
+    {
         let scope = self.scope.union(&other.scope).cloned().collect();
         match (self.state(), other.state()) {
-            (State::Empty, _) => return other.clone(),
-            (_, State::Empty) => return self.clone(),
-            (State::Stuck, _) | (_, State::Stuck) => return Multiverse::new(scope, vec![]),
+            (State::Empty, _) => return Ok(other.clone()),
+            (_, State::Empty) => return Ok(self.clone()),
+            (State::Stuck, _) | (_, State::Stuck) => return Ok(Multiverse::new(scope, vec![])),
             (State::Running, State::Running) => (),
         }
+        if self.scope.is_disjoint(&other.scope) {
+            // Nothing to align, each pair of layouts is a layout of the cross-product. These are
+            // distinct and cover `scope`, hence `Multiverse::new` is bypassed. The cross-product
+            // may explode, so `check_count` sees every layout produced so far.
+            let mut layouts = vec![];
+            for left_lay in &self.layouts {
+                poll()?;
+                for right_lay in &other.layouts {
+                    let mut binomial_coefs = left_lay.binomial_coefs.clone();
+                    binomial_coefs.extend(
+                        right_lay
+                            .binomial_coefs
+                            .iter()
+                            .map(|(k, v)| (k.clone(), *v)),
+                    );
+                    layouts.push(Layout { binomial_coefs });
+                }
+                check_count(layouts.len())?;
+            }
+            return Ok(Multiverse { scope, layouts });
+        }
         let mut layouts = vec![];
         for left_lay in &self.layouts {
+            poll()?;
             for right_lay in &other.layouts {
-                layouts.append(&mut left_lay.merge(right_lay));
+                layouts.append(&mut left_lay.merge(right_lay, check_count)?);
             }
         }
-        Multiverse::new(scope, layouts)
+        Ok(Multiverse::new(scope, layouts))
+    }
+
+    /// Merge all of `mvs`, in an order that keeps the intermediate multiverses small: the merges
+    /// start from the multiverse with the largest scope (e.g. the global constraint, whose blue
+    /// count prunes the most), then the next one is always the one that adds the fewest cells to
+    /// the scope merged so far, the smallest first on ties. The disjoint multiverses, whose merges
+    /// are cross-products, go last. Remaining ties are broken by the order of `mvs`.
+    pub fn merge_all<I>(mvs: I) -> Multiverse
+    where
+        I: IntoIterator,
+        I::Item: Borrow<Multiverse>,
+    {
+        Self::merge_all_with(mvs, |mv0, mv1| Ok(mv0.merge(mv1))).expect("Unreachable")
+    }
+
+    /// Same as [merge_all] but each merge is charged to `env` and checked, see [merge_checked].
+    pub fn merge_all_checked<I>(mvs: I, env: &Env) -> Result<Multiverse, Box<dyn Error>>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<Multiverse>,
+    {
+        Self::merge_all_with(mvs, |mv0, mv1| {
+            env.charge(1)?;
+            mv0.merge_checked(mv1, env)
+        })
+    }
+
+    fn merge_all_with<I, F>(mvs: I, merge: F) -> Result<Multiverse, Box<dyn Error>>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<Multiverse>,
+        F: Fn(&Multiverse, &Multiverse) -> Result<Multiverse, Box<dyn Error>>,
+    {
+        let mut mvs: Vec<I::Item> = mvs.into_iter().collect();
+        fn get<T: Borrow<Multiverse>>(mv: &T) -> &Multiverse {
+            mv.borrow()
+        }
+        let first = match mvs
+            .iter()
+            .enumerate()
+            .max_by_key(|(i, mv)| (get(*mv).scope.len(), Reverse(*i)))
+        {
+            None => return Ok(Multiverse::empty()),
+            Some((i, _)) => i,
+        };
+        let mut merged = get(&mvs.remove(first)).clone();
+        while !mvs.is_empty() {
+            let (next, _) = mvs
+                .iter()
+                .enumerate()
+                .min_by_key(|(i, mv)| {
+                    let new_cells = get(*mv).scope.difference(&merged.scope).count();
+                    (new_cells, get(*mv).scope.len(), *i)
+                })
+                .expect("Unreachable");
+            let mv = mvs.remove(next);
+            merged = merge(&merged, get(&mv))?;
+        }
+        Ok(merged)
+    }
+
+    /// Fast path of [learn_many] when the `findings` are already invariants of the multiverse with
+    /// the same colors, e.g. the cells that this very constraint forced: no layout is dropped and
+    /// only the keys that hold the findings change, the other ones are cloned as is. `None` as soon
+    /// as a finding lies in a key that isn't all blue or all black.
+    fn learn_invariants(&self, findings: &BTreeMap<Coords, Color>) -> Option<Vec<Layout>> {
+        self.layouts
+            .iter()
+            .map(|lay| {
+                let mut bc = lay.binomial_coefs.clone();
+                for (coords, color) in findings {
+                    let key = bc.keys().find(|k| k.contains(coords)).cloned();
+                    let (mut key, blue_count) = bc
+                        .remove_entry(&key.expect("Unreachable"))
+                        .expect("Unreachable");
+                    let blue_count = match color {
+                        Color::Blue if blue_count as usize == key.len() => blue_count - 1,
+                        Color::Black if blue_count == 0 => 0,
+                        _ => return None,
+                    };
+                    key.remove(coords);
+                    if !key.is_empty() {
+                        bc.insert(key, blue_count);
+                    }
+                }
+                Some(Layout { binomial_coefs: bc })
+            })
+            .collect()
+    }
+
+    /// Whether `colors` (that should cover the scope) is one of the solutions of the multiverse.
+    pub fn is_consistent(&self, colors: &BTreeMap<Coords, Color>) -> bool {
+        if self.scope.is_empty() {
+            return true;
+        }
+        self.layouts.iter().any(|lay| {
+            lay.binomial_coefs.iter().all(|(coords_set, blue_count)| {
+                let blues = coords_set
+                    .iter()
+                    .filter(|c| colors[c] == Color::Blue)
+                    .count();
+                blues == *blue_count as usize
+            })
+        })
     }
 
     pub fn learn(&self, coords: &Coords, color: Color) -> Multiverse {
+        self.learn_many(&BTreeMap::from([(*coords, color)]))
+    }
+
+    /// Same as folding [learn] over `findings`, but in a single pass over the layouts: within each
+    /// key of a layout, the learned cells are removed and their blues are subtracted from the
+    /// blue count.
+    pub fn learn_many(&self, findings: &BTreeMap<Coords, Color>) -> Multiverse {
         let mut scope = self.scope.clone();
-        let key = BTreeSet::from([*coords]);
-        if scope == key {
+        for coords in findings.keys() {
+            assert!(scope.remove(coords));
+        }
+        if scope.is_empty() {
             return Multiverse::empty();
         }
-        assert!(scope.remove(coords));
-        let layouts = Layout::split(&self.layouts, &key);
-        let layouts = layouts
+        if let Some(layouts) = self.learn_invariants(findings) {
+            return Multiverse::new(scope, layouts);
+        }
+        let layouts = self
+            .layouts
             .iter()
             .filter_map(|lay| {
-                match (color, lay.binomial_coefs[&key.clone()]) {
-                    (_, 2..=u16::MAX) => panic!("Unreachable"),
-                    (Color::Blue, 1) | (Color::Black, 0) => {
-                        // Keep that layout and remove that coords
-                        let mut bc = lay.binomial_coefs.clone();
-                        bc.remove(&key);
-                        Some(Layout::new(bc))
+                let mut bc = BTreeMap::new();
+                for (coords_set, blue_count) in &lay.binomial_coefs {
+                    let mut learned_blues = 0;
+                    let mut rest = BTreeSet::new();
+                    for coords in coords_set {
+                        match findings.get(coords) {
+                            None => {
+                                rest.insert(*coords);
+                            }
+                            Some(Color::Blue) => learned_blues += 1,
+                            Some(Color::Black) => (),
+                        }
+                    }
+                    // Drop the layouts that assumed other colors than the ones currently learned
+                    if learned_blues > *blue_count {
+                        return None;
                     }
-                    (Color::Blue, 0) | (Color::Black, 1) => {
-                        // The layout assumed the other color than the one currently
-                        // learned
-                        None
+                    let blue_count = blue_count - learned_blues;
+                    if blue_count as usize > rest.len() {
+                        return None;
+                    }
+                    if !rest.is_empty() {
+                        bc.insert(rest, blue_count);
                     }
                 }
+                Some(Layout::new(bc))
             })
             .collect();
         Multiverse::new(scope, layouts)
     }
+
+    /// The multiverse of the cells of `subset` (that should be part of the scope), the other cells
+    /// being marginalized out. Within each key of a layout, the cells of `subset` get any blue
+    /// count compatible with the other cells of the key, hence a layout may be split into several
+    /// ones. The invariants of the projection are the invariants of `self` within `subset`.
+    pub fn project(&self, subset: &BTreeSet<Coords>) -> Multiverse {
+        assert!(subset.is_subset(&self.scope));
+        if subset.is_empty() {
+            return Multiverse::empty();
+        }
+        let mut layouts = vec![];
+        for lay in &self.layouts {
+            let mut partial_layouts = vec![BTreeMap::new()];
+            for (coords_set, blue_count) in &lay.binomial_coefs {
+                let kept: BTreeSet<_> = coords_set.intersection(subset).cloned().collect();
+                if kept.is_empty() {
+                    continue;
+                }
+                let dropped_count = (coords_set.len() - kept.len()) as u16;
+                let min_count = blue_count.saturating_sub(dropped_count);
+                let max_count = (*blue_count).min(kept.len() as u16);
+                partial_layouts = partial_layouts
+                    .into_iter()
+                    .flat_map(|bc| {
+                        let kept = &kept;
+                        (min_count..=max_count).map(move |count| {
+                            let mut bc = bc.clone();
+                            bc.insert(kept.clone(), count);
+                            bc
+                        })
+                    })
+                    .collect();
+            }
+            layouts.extend(partial_layouts.into_iter().map(Layout::new));
+        }
+        Multiverse::new(subset.clone(), layouts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(coefs: &[(&[Coords], u16)]) -> Layout {
+        Layout::new(
+            coefs
+                .iter()
+                .map(|(cells, blue_count)| (cells.iter().cloned().collect(), *blue_count))
+                .collect(),
+        )
+    }
+
+    #[test]
+    pub fn test_merge_disjoint() {
+        let c: Vec<_> = (0..6).map(|i| Coords::new(i, 0, -i)).collect();
+        let mv0 = Multiverse::new(
+            c[..3].iter().cloned().collect(),
+            vec![
+                layout(&[(&c[..2], 1), (&c[2..3], 0)]),
+                layout(&[(&c[..2], 2), (&c[2..3], 1)]),
+            ],
+        );
+        let mv1 = Multiverse::new(
+            c[3..].iter().cloned().collect(),
+            vec![
+                layout(&[(&c[3..], 0)]),
+                layout(&[(&c[3..4], 1), (&c[4..], 1)]),
+                layout(&[(&c[3..], 3)]),
+            ],
+        );
+        // The layouts of the aligned path, which the fast path bypasses
+        let mut layouts = vec![];
+        for left_lay in &mv0.layouts {
+            for right_lay in &mv1.layouts {
+                layouts.append(&mut left_lay.merge(right_lay, &|_| Ok(())).unwrap());
+            }
+        }
+        let aligned = Multiverse::new(c.iter().cloned().collect(), layouts);
+        let mv = mv0.merge(&mv1);
+        assert_eq!(mv.scope, aligned.scope);
+        assert_eq!(mv.layouts, aligned.layouts);
+
+        let env = Env::new(60).with_layout_cap(5);
+        let err = mv0.merge_checked(&mv1, &env).unwrap_err();
+        assert!(err.is::<::env::TooManyLayouts>());
+        let env = Env::new(60).with_layout_cap(6);
+        assert_eq!(mv0.merge_checked(&mv1, &env).unwrap().layouts, mv.layouts);
+    }
 }
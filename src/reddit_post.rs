@@ -1,4 +1,5 @@
 use misc;
+use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::Deserialize;
 use serde::Serialize;
@@ -31,17 +32,177 @@ const PATTERN: &str = "(?s)\
 			)\
 			[\n<]";
 
+static STRDEFN: Lazy<Regex> = Lazy::new(|| Regex::new(PATTERN).expect("Unreachable"));
+static PASTEBIN_URL: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^https?://(?:www\.)?pastebin\.com/(?:raw/)?(\w+)/?$").expect("Unreachable")
+});
+static GIST_URL: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^https?://gist\.github\.com/([\w-]+)/(\w+)/?(?:#.*)?$").expect("Unreachable")
+});
+static HEXCELLS_HREF: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"href="(https?://[^"]+\.hexcells)""#).expect("Unreachable"));
+
+/// The url of the raw text of a pastebin or a gist page, `None` for the other urls
+fn raw_url_of_url(url: &str) -> Option<String> {
+    if let Some(captures) = PASTEBIN_URL.captures(url) {
+        return Some(format!("https://pastebin.com/raw/{}", &captures[1]));
+    }
+    if let Some(captures) = GIST_URL.captures(url) {
+        return Some(format!(
+            "https://gist.github.com/{}/{}/raw",
+            &captures[1], &captures[2]
+        ));
+    }
+    None
+}
+
+/// The urls of the `.hexcells` files (as saved by sixcells) linked from `html`, without
+/// duplicates
+fn hexcells_urls_of_html(html: &str) -> Vec<String> {
+    let mut urls: Vec<String> = vec![];
+    for captures in HEXCELLS_HREF.captures_iter(html) {
+        let url = captures[1].replace("&amp;", "&");
+        if !urls.contains(&url) {
+            urls.push(url);
+        }
+    }
+    urls
+}
+
+/// Fetch a raw text file, formatted for `PATTERN`
+fn get_raw_text(
+    url: &str,
+    cache_dir: &str,
+    options: &misc::FetchOptions,
+) -> Result<String, Box<dyn Error>> {
+    let text = misc::get_url_with_cache(&url.to_string(), cache_dir, options)?;
+    // `PATTERN` expects `\n` line endings and a char after the last line
+    Ok(format!("{}\n", text.replace("\r\n", "\n")))
+}
+
+/// The string definitions found in the page at `level.url`. The levels hosted on pastebin or in
+/// a gist are fetched from the raw text of the page, the `.hexcells` files linked from the page
+/// are fetched as well. These raw texts are often dead links, the ones that can't be fetched are
+/// skipped with a warning.
 pub fn strdefns_of_post(
     level: &RedditPost,
     cache_dir: &str,
+    options: &misc::FetchOptions,
 ) -> Result<Vec<String>, Box<dyn Error>> {
-    let html = misc::get_url_with_cache(&level.url, cache_dir)?;
-    let regex = Regex::new(PATTERN)?;
-    let occurrences: Vec<_> = regex.captures_iter(&html).collect();
+    let mut texts = vec![];
+    let push_raw_text =
+        |texts: &mut Vec<String>, url: &str| match get_raw_text(url, cache_dir, options) {
+            Ok(text) => texts.push(text),
+            Err(err) => log::warn!("  Skip {} because {}", url, err),
+        };
+    match raw_url_of_url(&level.url) {
+        None => {
+            let html = misc::get_url_with_cache(&level.url, cache_dir, options)?;
+            let urls = hexcells_urls_of_html(&html);
+            texts.push(html);
+            for url in urls {
+                push_raw_text(&mut texts, &url);
+            }
+        }
+        Some(url) => push_raw_text(&mut texts, &url),
+    };
     let mut res = vec![];
-    for occ in occurrences {
-        let s = occ.get(1).ok_or("Unreachable")?.as_str().to_string();
-        res.push(s)
+    for text in &texts {
+        for occ in STRDEFN.captures_iter(text) {
+            let s = occ.get(1).ok_or("Unreachable")?.as_str().to_string();
+            res.push(s)
+        }
     }
     Ok(res)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_raw_url_of_url() {
+        assert_eq!(
+            raw_url_of_url("https://pastebin.com/AbC123"),
+            Some("https://pastebin.com/raw/AbC123".to_string())
+        );
+        assert_eq!(
+            raw_url_of_url("https://pastebin.com/raw/AbC123"),
+            Some("https://pastebin.com/raw/AbC123".to_string())
+        );
+        assert_eq!(
+            raw_url_of_url("https://gist.github.com/some-one/0123abcd/"),
+            Some("https://gist.github.com/some-one/0123abcd/raw".to_string())
+        );
+        assert_eq!(
+            raw_url_of_url("https://www.reddit.com/r/hexcellslevels/comments/abc/level/"),
+            None
+        );
+    }
+
+    #[test]
+    pub fn test_hexcells_urls_of_html() {
+        let html = r#"<p>Get it <a href="https://example.com/files/level.hexcells">here</a>
+            (<a href="https://example.com/files/level.hexcells">mirror</a>,
+            <a href="https://example.com/get?id=1&amp;name=b.hexcells">other</a>,
+            <a href="https://example.com/level.png">screenshot</a>)</p>"#;
+        assert_eq!(
+            hexcells_urls_of_html(html),
+            vec![
+                "https://example.com/files/level.hexcells".to_string(),
+                "https://example.com/get?id=1&name=b.hexcells".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_strdefns_of_post() {
+        let mut cache_dir = std::env::temp_dir();
+        cache_dir.push(format!("hexcells-solver-test-post-{}", std::process::id()));
+        let cache_dir = cache_dir.to_str().unwrap().to_string();
+        let _ = fs::remove_dir_all(&cache_dir);
+
+        // The page of the post shows a level and links a live and a dead `.hexcells` file, the
+        // first two are put in the cache beforehand
+        let level = include_str!("../extra/the_trial.txt");
+        let post = RedditPost {
+            score: 1,
+            url: "https://www.reddit.com/r/hexcellslevels/comments/abc/level/".to_string(),
+            title: "Level".to_string(),
+            date: "2020-01-01".to_string(),
+            author: "someone".to_string(),
+        };
+        let live = "https://example.com/live.hexcells".to_string();
+        let html = format!(
+            "<div class=\"md\"><p>Play it:</p>\n<pre><code>{}</code></pre>\n\
+             <p><a href=\"{}\">live</a> <a href=\"http://127.0.0.1:1/dead.hexcells\">dead</a>\
+             </p></div>",
+            level, live
+        );
+        let cache = |url: &String, text: String| {
+            misc::with_cache(url, || Ok(text), &cache_dir).unwrap();
+        };
+        cache(&post.url, html);
+        cache(&live, level.trim().replace('\n', "\r\n"));
+
+        let options = misc::FetchOptions {
+            max_attempts: 1,
+            ..misc::FetchOptions::default()
+        };
+        let strdefns = strdefns_of_post(&post, &cache_dir, &options).unwrap();
+        assert_eq!(strdefns, vec![level.trim().to_string(); 2]);
+
+        // A paste is read from its raw text, with `\r\n` line endings
+        let paste = RedditPost {
+            url: "https://pastebin.com/AbC123".to_string(),
+            ..post
+        };
+        cache(
+            &"https://pastebin.com/raw/AbC123".to_string(),
+            level.trim().replace('\n', "\r\n"),
+        );
+        let strdefns = strdefns_of_post(&paste, &cache_dir, &options).unwrap();
+        assert_eq!(strdefns, vec![level.trim().to_string()]);
+        fs::remove_dir_all(&cache_dir).unwrap();
+    }
+}
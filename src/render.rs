@@ -0,0 +1,228 @@
+/// SVG rendering of a [Defn], in order to share solved puzzles.
+use std::collections::BTreeMap;
+
+use constraint;
+use defn;
+use defn::Cell;
+use defn::Color;
+use defn::Defn;
+use defn::Modifier;
+use misc::Coords;
+
+/// Distance between the center of a hexagon and its corners, in pixels
+const HEX_SIZE: f64 = 20.0;
+
+const COLOR_BLUE: &str = "#149cd8";
+const COLOR_BLACK: &str = "#3e3e3e";
+const COLOR_UNKNOWN: &str = "#ff9f00";
+const COLOR_TEXT: &str = "#ffffff";
+const COLOR_LINE: &str = "#3e3e3e";
+
+/// Center of the flat-top hexagon at `coords`.
+/// https://www.redblobgames.com/grids/hexagons/#hex-to-pixel
+fn pixel_of_coords(coords: &Coords) -> (f64, f64) {
+    let (q, r) = (coords.q() as f64, coords.r() as f64);
+    let x = HEX_SIZE * 1.5 * q;
+    let y = HEX_SIZE * 3f64.sqrt() * (r + q / 2.0);
+    (x, y)
+}
+
+/// Corners of the flat-top hexagon centered on `(x, y)`, clockwise starting from right.
+fn corners(x: f64, y: f64) -> [(f64, f64); 6] {
+    [0, 1, 2, 3, 4, 5].map(|i| {
+        let angle = std::f64::consts::PI / 3.0 * i as f64;
+        (x + HEX_SIZE * angle.cos(), y + HEX_SIZE * angle.sin())
+    })
+}
+
+fn format_hint(blue_count: usize, modifier: Modifier) -> String {
+    match modifier {
+        Modifier::Anywhere => format!("{}", blue_count),
+        Modifier::Together => format!("{{{}}}", blue_count),
+        Modifier::Separated => format!("-{}-", blue_count),
+    }
+}
+
+/// The number displayed on `cell`, if any, the lines having the gaps of the base game
+fn hint(defn: &Defn, coords: Coords, cell: &Cell) -> Option<String> {
+    let (mv, modifier) = match cell {
+        Cell::Empty | Cell::Zone0 { .. } => return None,
+        Cell::Zone6 { m, .. } => (constraint::zone6(defn, coords, *m), *m),
+        Cell::Zone18 { m, .. } => (constraint::zone18(defn, coords, *m), *m),
+        Cell::Line { o, m } => (constraint::line(defn, coords, *o, *m), *m),
+        Cell::Column => (constraint::column(defn, coords), Modifier::Anywhere),
+    };
+    let blue_count = mv
+        .scope
+        .iter()
+        .filter(|c| defn::color_of_cell(&defn[c]) == Some(Color::Blue))
+        .count();
+    Some(format_hint(blue_count, modifier))
+}
+
+/// The colors of the cells that are revealed at the beginning of the puzzle.
+pub fn revealed_colors(defn: &Defn) -> BTreeMap<Coords, Color> {
+    let mut colors = BTreeMap::new();
+    for (coords, cell) in defn {
+        let revealed = match cell {
+            Cell::Zone0 { revealed, .. } => *revealed,
+            Cell::Zone6 { revealed, .. } => *revealed,
+            Cell::Zone18 { revealed, .. } => *revealed,
+            Cell::Empty | Cell::Line { .. } | Cell::Column => false,
+        };
+        if revealed {
+            colors.insert(*coords, defn::color_of_cell(cell).expect("Unreachable"));
+        }
+    }
+    colors
+}
+
+/// Render `defn` to an SVG document. Cells are colored according to `colors`, the ones missing
+/// from it are rendered as unknown. The numbers are only displayed on the cells whose color is
+/// known, and on the lines. The lines count their cells with the gaps of the base game, see
+/// [constraint::LineGaps].
+pub fn svg(defn: &Defn, colors: &BTreeMap<Coords, Color>) -> String {
+    let (mut xmin, mut ymin, mut xmax, mut ymax) = (0f64, 0f64, 0f64, 0f64);
+    if let Some((coords, _)) = defn.iter().next() {
+        let (x, y) = pixel_of_coords(coords);
+        (xmin, ymin, xmax, ymax) = (x, y, x, y);
+    }
+    for coords in defn.keys() {
+        let (x, y) = pixel_of_coords(coords);
+        xmin = xmin.min(x);
+        ymin = ymin.min(y);
+        xmax = xmax.max(x);
+        ymax = ymax.max(y);
+    }
+    let margin = HEX_SIZE * 1.5;
+    let (xmin, ymin) = (xmin - margin, ymin - margin);
+    let (width, height) = (xmax - xmin + margin, ymax - ymin + margin);
+
+    let mut out = String::new();
+    let mut w = |s: String| out.push_str(&s);
+    w(format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"{:.2} {:.2} {:.2} {:.2}\" \
+         width=\"{:.0}\" height=\"{:.0}\">\n",
+        xmin, ymin, width, height, width, height
+    ));
+    w(format!(
+        "<g font-family=\"sans-serif\" font-size=\"{:.0}\" text-anchor=\"middle\" \
+         dominant-baseline=\"central\">\n",
+        HEX_SIZE * 0.8
+    ));
+    for (coords, cell) in defn {
+        let (x, y) = pixel_of_coords(coords);
+        match cell {
+            Cell::Empty => (),
+            Cell::Line { o, .. } => {
+                // A short stroke from the center towards the cells of the line
+                let (x1, y1) = pixel_of_coords(&(*coords + o.direction()));
+                let (dx, dy) = ((x1 - x) / 2.0, (y1 - y) / 2.0);
+                w(format!(
+                    "<line x1=\"{:.2}\" y1=\"{:.2}\" x2=\"{:.2}\" y2=\"{:.2}\" stroke=\"{}\" \
+                     stroke-width=\"2\"/>\n",
+                    x + dx * 0.5,
+                    y + dy * 0.5,
+                    x + dx,
+                    y + dy,
+                    COLOR_LINE
+                ));
+                let text = hint(defn, *coords, cell).expect("Unreachable");
+                w(format!(
+                    "<text x=\"{:.2}\" y=\"{:.2}\" fill=\"{}\">{}</text>\n",
+                    x, y, COLOR_LINE, text
+                ));
+            }
+            Cell::Column => {
+                let text = hint(defn, *coords, cell).expect("Unreachable");
+                w(format!(
+                    "<text x=\"{:.2}\" y=\"{:.2}\" fill=\"{}\">{}</text>\n",
+                    x, y, COLOR_LINE, text
+                ));
+            }
+            Cell::Zone0 { .. } | Cell::Zone6 { .. } | Cell::Zone18 { .. } => {
+                let color = colors.get(coords);
+                let fill = match color {
+                    None => COLOR_UNKNOWN,
+                    Some(Color::Blue) => COLOR_BLUE,
+                    Some(Color::Black) => COLOR_BLACK,
+                };
+                let points: Vec<_> = corners(x, y)
+                    .iter()
+                    .map(|(x, y)| format!("{:.2},{:.2}", x, y))
+                    .collect();
+                w(format!(
+                    "<polygon points=\"{}\" fill=\"{}\"/>\n",
+                    points.join(" "),
+                    fill
+                ));
+                if let (Some(_), Some(text)) = (color, hint(defn, *coords, cell)) {
+                    w(format!(
+                        "<text x=\"{:.2}\" y=\"{:.2}\" fill=\"{}\">{}</text>\n",
+                        x, y, COLOR_TEXT, text
+                    ));
+                }
+            }
+        }
+    }
+    w("</g>\n</svg>\n".to_string());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THE_TRIAL: &str = include_str!("../extra/the_trial.txt");
+
+    #[test]
+    pub fn test_tiling() {
+        // Adjacent hexagons share an edge, i.e. two corners
+        let c = Coords::new(0, 0, 0);
+        let (x, y) = pixel_of_coords(&c);
+        let corners0 = corners(x, y);
+        for neighbor in c.neighbors6() {
+            let (x, y) = pixel_of_coords(&neighbor);
+            let shared = corners(x, y)
+                .iter()
+                .filter(|(x, y)| {
+                    corners0
+                        .iter()
+                        .any(|(x0, y0)| (x - x0).abs() < 1e-9 && (y - y0).abs() < 1e-9)
+                })
+                .count();
+            assert_eq!(shared, 2);
+        }
+    }
+
+    #[test]
+    pub fn test_svg() {
+        let defn = defn::of_string(THE_TRIAL).unwrap();
+        let colors: BTreeMap<_, _> = defn
+            .iter()
+            .filter_map(|(coords, cell)| defn::color_of_cell(cell).map(|color| (*coords, color)))
+            .collect();
+        let out = svg(&defn, &colors);
+        assert!(out.starts_with("<svg "));
+        assert!(out.ends_with("</svg>\n"));
+        assert_eq!(out.matches("<polygon ").count(), colors.len());
+        let line_count = defn
+            .values()
+            .filter(|cell| matches!(cell, Cell::Line { .. }))
+            .count();
+        assert_eq!(out.matches("<line ").count(), line_count);
+        assert!(out.contains(&format!("fill=\"{}\"", COLOR_BLUE)));
+        assert!(!out.contains(&format!("fill=\"{}\"", COLOR_UNKNOWN)));
+
+        // Initial state
+        let revealed = revealed_colors(&defn);
+        assert!(!revealed.is_empty() && revealed.len() < colors.len());
+        let out = svg(&defn, &revealed);
+        assert!(out.contains(&format!("fill=\"{}\"", COLOR_UNKNOWN)));
+
+        // Nothing known
+        let out = svg(&defn, &BTreeMap::new());
+        assert_eq!(out.matches("<polygon ").count(), colors.len());
+        assert_eq!(out.matches("<text ").count(), line_count);
+    }
+}
@@ -1,8 +1,23 @@
 /// Produce the 2 csv files necessary for https://gist.github.com/Ngoguey42/a0f661c5cb36180a3a6aca4bb4d385b2/99b37bdf646d8dd76df6a1c26ea0d6acf4856219
+use defn;
+use defn::Defn;
 use reddit_post;
+use render;
 use solver;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs;
 use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
 
 pub enum Outcome {
     ParseFail,
@@ -13,11 +28,40 @@ pub struct Line {
     pub post: reddit_post::RedditPost,
     pub idx_in_post: u32,
     pub level_name: String,
+    /// `None` if the parsing failed
+    pub defn: Option<Defn>,
     pub outcome: Outcome,
+    /// The number of solutions of the puzzle, `None` if unknown, e.g. because counting them timed
+    /// out
+    pub solution_count: Option<solver::SolutionCount>,
+    /// Number of the other copies of the same puzzle, in the following posts or further down the
+    /// same post, they are not reported
+    pub repost_count: u32,
 }
 
-const HEADER0: &str = "Classif,Upvotes,Date,Author,Post,Title,URL\n";
-const HEADER1: &str = "Difficulty,Upvotes,Date,Author,Post,Title,URL\n";
+/// A puzzle read from a file, see `report_files`
+pub struct FileLine {
+    pub path: String,
+    pub level_name: String,
+    pub outcome: Outcome,
+}
+
+const HEADER0: &str =
+    "Classif,Upvotes,Date,Author,Post,Title,URL,Width,Height,Cells,Solutions,Reposts\n";
+const HEADER1: &str =
+    "Difficulty,Upvotes,Date,Author,Post,Title,URL,Width,Height,Cells,Solutions,Reposts\n";
+const HEADER2: &str = "Classif,File,Title\n";
+
+pub const ALL_FILE_NAME: &str = "2puzzles.csv";
+pub const RANKED_FILE_NAME: &str = "1puzzles_ranked.csv";
+pub const FILES_FILE_NAME: &str = "batch_puzzles.csv";
+pub const HTML_FILE_NAME: &str = "puzzles.html";
+
+/// Create `out_dir` if missing and open `file_name` in it for writing
+fn create_in(out_dir: &Path, file_name: &str) -> io::Result<File> {
+    fs::create_dir_all(out_dir)?;
+    File::create(out_dir.join(file_name))
+}
 
 fn cleanup_post_name(s: &str) -> String {
     let s = s
@@ -31,85 +75,752 @@ fn cleanup_post_name(s: &str) -> String {
     if s.len() > 40 {
         s.truncate(34);
         s = format!("{} [...]", s);
-        println!("> {}", s);
+        log::debug!("> {}", s);
     }
     s.to_string()
 }
 
-pub fn report_all(lines: &Vec<Line>) {
+fn classif_of_outcome(outcome: &Outcome) -> String {
+    match outcome {
+        Outcome::ParseFail => "Err".to_string(),
+        Outcome::Solver(solver::Outcome::Timeout(_)) => "T".to_string(),
+        Outcome::Solver(solver::Outcome::Cancelled) => "C".to_string(),
+        Outcome::Solver(solver::Outcome::BudgetExceeded) => "B".to_string(),
+        Outcome::Solver(solver::Outcome::Unsolvable { .. }) => "Spe".to_string(),
+        Outcome::Solver(solver::Outcome::Contradiction(_)) => "Bug".to_string(),
+        Outcome::Solver(solver::Outcome::ColorMismatch(_)) => "Bug".to_string(),
+        Outcome::Solver(solver::Outcome::Solved { guesses, .. }) if *guesses > 0 => {
+            format!("G{}", guesses)
+        }
+        Outcome::Solver(outcome @ solver::Outcome::Solved { .. }) => {
+            match (
+                outcome.max_local_difficulty(),
+                outcome.max_global_difficulty(),
+            ) {
+                // Already solved, nothing to find
+                (None, None) => "0".to_string(),
+                (Some(i), None) => format!("{}", i),
+                (Some(i), Some(j)) => format!("{}g{}", i, j),
+                (None, Some(j)) => format!("g{}", j),
+            }
+        }
+    }
+}
+
+/// The row of `line` in `ALL_FILE_NAME` and `RANKED_FILE_NAME`, without its `Reposts` cell. The
+/// `Reposts` cell is last, the `Classif` one is first. The size cells are empty when the parsing
+/// failed, the `Solutions` one when the number of solutions is unknown.
+fn csv_row(line: &Line) -> String {
+    let post = &line.post;
+    let classif = classif_of_outcome(&line.outcome);
+    let level_name = format!("\"{}\"", line.level_name.replace('\"', "'"));
+    let post_name = format!("\"{}\"", cleanup_post_name(&post.title));
+    let author = format!("\"{}\"", post.author.replace('\"', "'"));
+    let size = match &line.defn {
+        None => ",,".to_string(),
+        Some(defn) => {
+            let summary = defn::summary(defn);
+            format!(
+                "{},{},{}",
+                summary.width, summary.height, summary.cell_count
+            )
+        }
+    };
+    let solutions = match line.solution_count {
+        None => "",
+        Some(solver::SolutionCount::Zero) => "Zero",
+        Some(solver::SolutionCount::One) => "One",
+        Some(solver::SolutionCount::Many) => "Many",
+    };
+    format!(
+        "{},{},{},{},{},{},{},{},{}",
+        classif, post.score, post.date, author, post_name, level_name, post.url, size, solutions,
+    )
+}
+
+/// Write `ALL_FILE_NAME` in `out_dir`
+pub fn report_all(lines: &Vec<Line>, out_dir: &Path) -> io::Result<()> {
     let mut report_lines: Vec<String> = vec![];
     for line in lines {
-        let post = &line.post;
-        let classif = match &line.outcome {
-            Outcome::ParseFail => "Err".to_string(),
-            Outcome::Solver(solver::Outcome::Timeout) => "T".to_string(),
-            Outcome::Solver(solver::Outcome::Unsolvable) => "Spe".to_string(),
-            Outcome::Solver(solver::Outcome::Solved(findings_vec)) => {
-                let (max_local, max_global) = solver::difficulty_of_findings_vec(findings_vec);
-                match (max_local, max_global) {
-                    (None, None) => panic!(),
-                    (Some(i), None) => format!("{}", i),
-                    (Some(i), Some(j)) => format!("{}g{}", i, j),
-                    (None, Some(j)) => format!("g{}", j),
-                }
-            }
-        };
-        let level_name = format!("\"{}\"", line.level_name.replace('\"', "'"));
-        let post_name = format!("\"{}\"", cleanup_post_name(&post.title));
-        let author = format!("\"{}\"", post.author.replace('\"', "'"));
-        let report_line = format!(
-            "{},{},{},{},{},{},{}",
-            classif, post.score, post.date, author, post_name, level_name, post.url
-        );
-        report_lines.push(report_line);
+        report_lines.push(format!("{},{}", csv_row(line), line.repost_count));
     }
-    let mut file = File::create("a0f661c5cb36180a3a6aca4bb4d385b2/2puzzles.csv").unwrap();
-    file.write_all(HEADER0.as_bytes()).unwrap();
+    let mut file = create_in(out_dir, ALL_FILE_NAME)?;
+    file.write_all(HEADER0.as_bytes())?;
     for report_line in &report_lines {
-        file.write_all(report_line.as_bytes()).unwrap();
-        file.write_all("\n".as_bytes()).unwrap();
+        file.write_all(report_line.as_bytes())?;
+        file.write_all("\n".as_bytes())?;
     }
+    Ok(())
 }
 
-pub fn report_ranked(lines: &[Line]) {
+/// Write `RANKED_FILE_NAME` in `out_dir`
+pub fn report_ranked(lines: &[Line], out_dir: &Path) -> io::Result<()> {
     let mut report_lines = vec![];
     for (i, line) in lines.iter().enumerate() {
-        let post = &line.post;
-        let (max_local, max_global) = match &line.outcome {
-            Outcome::ParseFail => continue,
-            Outcome::Solver(solver::Outcome::Timeout) => continue,
-            Outcome::Solver(solver::Outcome::Unsolvable) => continue,
-            Outcome::Solver(solver::Outcome::Solved(findings_vec)) => {
-                solver::difficulty_of_findings_vec(findings_vec)
+        let key = match difficulty_key(&line.outcome) {
+            None => continue,
+            Some(key) => key,
+        };
+        let report_line = format!("{},{}", csv_row(line), line.repost_count);
+        report_lines.push(((key, i), report_line));
+    }
+    write_ranked(report_lines, out_dir)
+}
+
+/// Write `RANKED_FILE_NAME` in `out_dir` from the rows of the solved puzzles along with their
+/// sorting key and index
+fn write_ranked(
+    mut report_lines: Vec<((DifficultyKey, usize), String)>,
+    out_dir: &Path,
+) -> io::Result<()> {
+    report_lines.sort();
+    let mut file = create_in(out_dir, RANKED_FILE_NAME)?;
+    file.write_all(HEADER1.as_bytes())?;
+    for (_key, report_line) in &report_lines {
+        file.write_all(report_line.as_bytes())?;
+        file.write_all("\n".as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Same as `report_all` for puzzles read from files. Writes `FILES_FILE_NAME` in `out_dir`.
+pub fn report_files(lines: &[FileLine], out_dir: &Path) -> io::Result<()> {
+    let mut file = create_in(out_dir, FILES_FILE_NAME)?;
+    file.write_all(HEADER2.as_bytes())?;
+    for line in lines {
+        let classif = classif_of_outcome(&line.outcome);
+        let file_name = format!("\"{}\"", line.path.replace('\"', "'"));
+        let level_name = format!("\"{}\"", line.level_name.replace('\"', "'"));
+        let report_line = format!("{},{},{}\n", classif, file_name, level_name);
+        file.write_all(report_line.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The opposites of the max local and global difficulties
+type DifficultyKey = (i32, i32);
+
+/// Sorting key of the solved puzzles, the hardest ones first. The ones that needed guesses are not
+/// ranked.
+fn difficulty_key(outcome: &Outcome) -> Option<DifficultyKey> {
+    match outcome {
+        Outcome::Solver(solver::Outcome::Solved { guesses, .. }) if *guesses > 0 => None,
+        Outcome::Solver(outcome @ solver::Outcome::Solved { .. }) => Some((
+            outcome
+                .max_local_difficulty()
+                .map(|i| -(i as i32))
+                .unwrap_or(0),
+            outcome
+                .max_global_difficulty()
+                .map(|i| -(i as i32))
+                .unwrap_or(0),
+        )),
+        _ => None,
+    }
+}
+
+/// Same as [difficulty_key] from the `Classif` cell of a row, e.g. `4g15`
+fn difficulty_key_of_classif(classif: &str) -> Option<DifficultyKey> {
+    let (local, global) = match classif.split_once('g') {
+        None => (classif, None),
+        Some((local, global)) => (local, Some(global)),
+    };
+    let local = match local {
+        "" => 0,
+        local => -local.parse::<i32>().ok()?,
+    };
+    let global = match global {
+        None => 0,
+        Some(global) => -global.parse::<i32>().ok()?,
+    };
+    Some((local, global))
+}
+
+fn html_label_of_outcome(outcome: &Outcome) -> String {
+    match outcome {
+        Outcome::ParseFail => "Parse failure".to_string(),
+        Outcome::Solver(solver::Outcome::Solved { .. }) => classif_of_outcome(outcome),
+        Outcome::Solver(outcome) => format!("{}", outcome),
+    }
+}
+
+const HTML_HEADER: &str = "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>Hexcells levels</title>
+<style>
+body { font-family: sans-serif; }
+td { padding: 4px 12px; vertical-align: middle; }
+td svg { width: 240px; height: auto; }
+</style>
+</head>
+<body>
+<table>
+<tr><th>Difficulty</th><th>Grid</th><th>Level</th><th>Author</th><th>Upvotes</th><th>Date</th><th>Reposts</th></tr>
+";
+
+const HTML_FOOTER: &str = "</table>
+</body>
+</html>
+";
+
+/// Write `HTML_FILE_NAME` in `out_dir`. It lists the puzzles sorted by difficulty (the unsolved
+/// ones last) along with a preview of their initial grid.
+pub fn report_html(lines: &[Line], out_dir: &Path) -> io::Result<()> {
+    let mut sorted: Vec<_> = lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| (difficulty_key(&line.outcome), i, line))
+        .collect();
+    sorted.sort_by_key(|(key, i, _)| (key.is_none(), *key, *i));
+
+    let mut file = create_in(out_dir, HTML_FILE_NAME)?;
+    file.write_all(HTML_HEADER.as_bytes())?;
+    for (_key, _i, line) in sorted {
+        let row = format!("{}<td>{}</td></tr>\n", html_row(line), line.repost_count);
+        file.write_all(row.as_bytes())?;
+    }
+    file.write_all(HTML_FOOTER.as_bytes())?;
+    Ok(())
+}
+
+/// The html row of `line` without its last cell, the reposts, and the closing tag
+fn html_row(line: &Line) -> String {
+    let post = &line.post;
+    let grid = match &line.defn {
+        None => String::new(),
+        Some(defn) => render::svg(defn, &render::revealed_colors(defn)),
+    };
+    format!(
+        "<tr><td>{}</td><td>{}</td><td><a href=\"{}\">{}</a><br>{}</td><td>{}</td><td>{}</td><td>{}</td>",
+        escape_html(&html_label_of_outcome(&line.outcome)),
+        grid,
+        escape_html(&post.url),
+        escape_html(&line.level_name),
+        escape_html(&cleanup_post_name(&post.title)),
+        escape_html(&post.author),
+        post.score,
+        escape_html(&post.date),
+    )
+}
+
+/// A text table of the distribution of the difficulties of the puzzles of `lines`.
+///
+/// The puzzles solved without guessing are bucketed by their maximum local difficulty (`-` when
+/// only global steps were needed). The `global` column counts the puzzles of the bucket that also
+/// needed global steps. The other outcomes are counted below the table, the timeouts are also
+/// broken down by phase (the ones cached without a phase are in neither).
+pub fn report_histogram(lines: &[Line]) -> String {
+    let mut histogram = Histogram::default();
+    for line in lines {
+        histogram.add(&line.outcome);
+    }
+    histogram.render()
+}
+
+/// The counts of [report_histogram]
+#[derive(Default)]
+struct Histogram {
+    buckets: BTreeMap<Option<u32>, (usize, usize)>,
+    parse_fail: usize,
+    unsolvable: usize,
+    guessy: usize,
+    timeout: usize,
+    timeout_compound: usize,
+    timeout_global: usize,
+    cancelled: usize,
+    budget_exceeded: usize,
+    contradiction: usize,
+}
+
+impl Histogram {
+    fn add(&mut self, outcome: &Outcome) {
+        match outcome {
+            Outcome::ParseFail => self.parse_fail += 1,
+            Outcome::Solver(solver::Outcome::Unsolvable { .. }) => self.unsolvable += 1,
+            Outcome::Solver(solver::Outcome::Timeout(phase)) => {
+                self.timeout += 1;
+                match phase {
+                    None => (),
+                    Some(solver::Phase::Compound) => self.timeout_compound += 1,
+                    Some(solver::Phase::Global) => self.timeout_global += 1,
+                }
+            }
+            Outcome::Solver(solver::Outcome::Cancelled) => self.cancelled += 1,
+            Outcome::Solver(solver::Outcome::BudgetExceeded) => self.budget_exceeded += 1,
+            Outcome::Solver(solver::Outcome::Contradiction(_))
+            | Outcome::Solver(solver::Outcome::ColorMismatch(_)) => self.contradiction += 1,
+            Outcome::Solver(solver::Outcome::Solved { guesses, .. }) if *guesses > 0 => {
+                self.guessy += 1
             }
+            Outcome::Solver(outcome @ solver::Outcome::Solved { .. }) => {
+                let bucket = self
+                    .buckets
+                    .entry(outcome.max_local_difficulty())
+                    .or_insert((0, 0));
+                bucket.0 += 1;
+                if outcome.max_global_difficulty().is_some() {
+                    bucket.1 += 1;
+                }
+            }
+        }
+    }
+
+    fn render(&self) -> String {
+        let buckets = &self.buckets;
+        let mut out = String::new();
+        out.push_str("local  count  global\n");
+        // `None` sorts first, print it last
+        let ordered = buckets
+            .iter()
+            .filter(|(k, _)| k.is_some())
+            .chain(buckets.iter().filter(|(k, _)| k.is_none()));
+        for (max_local, (count, global)) in ordered {
+            let max_local = max_local.map_or("-".to_string(), |i| i.to_string());
+            out.push_str(&format!(
+                "{:>5}  {:>5}  {:>6}  {}\n",
+                max_local,
+                count,
+                global,
+                "#".repeat(*count)
+            ));
+        }
+        for (name, count) in [
+            ("Unsolvable", self.unsolvable),
+            ("Solved with guesses", self.guessy),
+            ("Timeout", self.timeout),
+            ("  in compound", self.timeout_compound),
+            ("  in global", self.timeout_global),
+            ("Cancelled", self.cancelled),
+            ("Budget exceeded", self.budget_exceeded),
+            ("Bugged grid", self.contradiction),
+            ("Parse failure", self.parse_fail),
+        ] {
+            out.push_str(&format!("{}: {}\n", name, count));
+        }
+        out
+    }
+}
+
+/// Writes the reports of the reddit puzzles while they are solved, instead of all at once like
+/// [report_all], [report_ranked], [report_html] and [report_histogram].
+/// Each line is appended to `ALL_FILE_NAME` as soon as it is known, hence a crash mid-scrape
+/// doesn't lose the lines written so far. Rerunning the scrape resumes it: the puzzles are replayed
+/// from the caches, the rows already in `ALL_FILE_NAME` are kept and the new ones are appended.
+/// The reposts are only known at the end, `finish` fills them in and derives `RANKED_FILE_NAME`
+/// from `ALL_FILE_NAME`. The html rows go to a temporary file until `finish` sorts them, only
+/// their sorting keys are kept in memory.
+pub struct ReportWriter {
+    out_dir: PathBuf,
+    all_file: File,
+    /// The rows found in `ALL_FILE_NAME` when it was opened, without their reposts cell, along
+    /// with their offset in the file
+    previous_rows: Vec<(u64, String)>,
+    line_count: usize,
+    html_file: File,
+    html_len: u64,
+    /// The sorting key, index, offset and length in `html_file` of each html row, the rows lack
+    /// their reposts cell
+    html_rows: Vec<(Option<DifficultyKey>, usize, u64, usize)>,
+    histogram: Histogram,
+}
+
+/// The rows of the `ALL_FILE_NAME` at `path`, see `ReportWriter::previous_rows`, along with the
+/// length of the file they span. `None` if there is no such file or if it has another header, e.g.
+/// from an older version.
+#[allow(clippy::type_complexity)]
+fn read_all_file(path: &Path) -> io::Result<Option<(Vec<(u64, String)>, u64)>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let mut rows = contents.split_inclusive('\n');
+    if rows.next() != Some(HEADER0) {
+        return Ok(None);
+    }
+    let mut offset = HEADER0.len() as u64;
+    let mut res = vec![];
+    for row in rows {
+        // A row cut by a crash is dropped
+        let (cells, _) = match row.strip_suffix('\n').and_then(|row| row.rsplit_once(',')) {
+            Some((cells, reposts)) if reposts.parse::<u32>().is_ok() => (cells, reposts),
+            _ => break,
         };
-        // let max_local = max_local as i32;
-        // let max_global = max_global as i32;
-        let classif = match (max_local, max_global) {
-            (None, None) => panic!(),
-            (Some(i), None) => format!("{}", i),
-            (Some(i), Some(j)) => format!("{}g{}", i, j),
-            (None, Some(j)) => format!("g{}", j),
+        res.push((offset, cells.to_string()));
+        offset += row.len() as u64;
+    }
+    Ok(Some((res, offset)))
+}
+
+/// The temporary file of the html rows of a `ReportWriter`
+fn html_rows_path(out_dir: &Path) -> PathBuf {
+    out_dir.join(format!("{}.rows.tmp", HTML_FILE_NAME))
+}
+
+impl ReportWriter {
+    /// Open `ALL_FILE_NAME` in `out_dir` to append to it, creating it if it doesn't exist or has
+    /// another header
+    pub fn create(out_dir: &Path) -> io::Result<ReportWriter> {
+        let all_path = out_dir.join(ALL_FILE_NAME);
+        let (all_file, previous_rows) = match read_all_file(&all_path)? {
+            Some((previous_rows, len)) => {
+                let all_file = OpenOptions::new().append(true).open(&all_path)?;
+                all_file.set_len(len)?;
+                (all_file, previous_rows)
+            }
+            None => {
+                let mut all_file = create_in(out_dir, ALL_FILE_NAME)?;
+                all_file.write_all(HEADER0.as_bytes())?;
+                (all_file, vec![])
+            }
         };
-        let level_name = format!("\"{}\"", line.level_name.replace('\"', "'"));
-        let post_name = format!("\"{}\"", cleanup_post_name(&post.title));
-        let author = format!("\"{}\"", post.author.replace('\"', "'"));
-        let report_line = format!(
-            "{},{},{},{},{},{},{}",
-            classif, post.score, post.date, author, post_name, level_name, post.url
+        let html_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(html_rows_path(out_dir))?;
+        Ok(ReportWriter {
+            out_dir: out_dir.to_path_buf(),
+            all_file,
+            previous_rows,
+            line_count: 0,
+            html_file,
+            html_len: 0,
+            html_rows: vec![],
+            histogram: Histogram::default(),
+        })
+    }
+
+    /// Write `line` to `ALL_FILE_NAME` and return its index. `line.repost_count` is ignored, the
+    /// reposts are given to `finish`. A line that is already in the file at that index is not
+    /// written again, a different one replaces the rows from that index on.
+    pub fn append(&mut self, line: &Line) -> io::Result<usize> {
+        let i = self.line_count;
+        let row = csv_row(line);
+        match self.previous_rows.get(i) {
+            Some((_, previous)) if *previous == row => (),
+            previous => {
+                if let Some((offset, _)) = previous {
+                    self.all_file.set_len(*offset)?;
+                    self.previous_rows.truncate(i);
+                }
+                writeln!(self.all_file, "{},0", row)?;
+                self.all_file.flush()?;
+            }
+        }
+        let html = html_row(line);
+        self.html_file.write_all(html.as_bytes())?;
+        self.html_rows
+            .push((difficulty_key(&line.outcome), i, self.html_len, html.len()));
+        self.html_len += html.len() as u64;
+        self.histogram.add(&line.outcome);
+        self.line_count += 1;
+        Ok(i)
+    }
+
+    /// Fill in the reposts of `ALL_FILE_NAME`, write `RANKED_FILE_NAME` and `HTML_FILE_NAME`, and
+    /// return the histogram of [report_histogram]. `repost_counts` maps the indices returned by
+    /// `append` to their number of reposts, the missing ones have none.
+    pub fn finish(self, repost_counts: &HashMap<usize, u32>) -> io::Result<String> {
+        let ReportWriter {
+            out_dir,
+            all_file,
+            previous_rows,
+            line_count,
+            mut html_file,
+            html_len: _,
+            mut html_rows,
+            histogram,
+        } = self;
+        // The rows of a previous run past the last line of this one
+        if let Some((offset, _)) = previous_rows.get(line_count) {
+            all_file.set_len(*offset)?;
+        }
+        drop(all_file);
+
+        let all_path = out_dir.join(ALL_FILE_NAME);
+        let tmp_path = out_dir.join(format!("{}.tmp", ALL_FILE_NAME));
+        let mut tmp_file = File::create(&tmp_path)?;
+        tmp_file.write_all(HEADER0.as_bytes())?;
+        let mut ranked_lines = vec![];
+        let rows = BufReader::new(File::open(&all_path)?).lines().skip(1);
+        for (i, row) in rows.enumerate() {
+            let row = row?;
+            let (row, _) = row.rsplit_once(',').expect("Unreachable");
+            let repost_count = repost_counts.get(&i).cloned().unwrap_or(0);
+            let row = format!("{},{}", row, repost_count);
+            writeln!(tmp_file, "{}", row)?;
+            let (classif, _) = row.split_once(',').expect("Unreachable");
+            if let Some(key) = difficulty_key_of_classif(classif) {
+                ranked_lines.push(((key, i), row));
+            }
+        }
+        drop(tmp_file);
+        fs::rename(&tmp_path, &all_path)?;
+        write_ranked(ranked_lines, &out_dir)?;
+
+        html_rows.sort_by_key(|(key, i, _, _)| (key.is_none(), *key, *i));
+        let mut file = create_in(&out_dir, HTML_FILE_NAME)?;
+        file.write_all(HTML_HEADER.as_bytes())?;
+        for (_key, i, offset, len) in html_rows {
+            let mut row = vec![0; len];
+            html_file.seek(SeekFrom::Start(offset))?;
+            html_file.read_exact(&mut row)?;
+            let repost_count = repost_counts.get(&i).cloned().unwrap_or(0);
+            file.write_all(&row)?;
+            writeln!(file, "<td>{}</td></tr>", repost_count)?;
+        }
+        file.write_all(HTML_FOOTER.as_bytes())?;
+        drop(html_file);
+        fs::remove_file(html_rows_path(&out_dir))?;
+
+        Ok(histogram.render())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use defn;
+    use env::Env;
+    use std::fs;
+
+    /// A parse failure, The Trial reposted twice and a timeout
+    fn sample_lines() -> Vec<Line> {
+        let post = reddit_post::RedditPost {
+            score: 12,
+            url: "https://example.com/?a=1&b=2".to_string(),
+            title: "[Level] Some <levels>".to_string(),
+            date: "2023-08-01".to_string(),
+            author: "someone".to_string(),
+        };
+        let defn = defn::of_string(include_str!("../extra/the_trial.txt")).unwrap();
+        let outcome = solver::solve(&mut Env::new(60), &defn, solver::SolveOptions::default());
+        vec![
+            Line {
+                post: post.clone(),
+                idx_in_post: 0,
+                level_name: "Broken".to_string(),
+                defn: None,
+                outcome: Outcome::ParseFail,
+                solution_count: None,
+                repost_count: 0,
+            },
+            Line {
+                post: post.clone(),
+                idx_in_post: 1,
+                level_name: "The Trial".to_string(),
+                defn: Some(defn.clone()),
+                outcome: Outcome::Solver(outcome),
+                solution_count: Some(solver::SolutionCount::One),
+                repost_count: 2,
+            },
+            Line {
+                post,
+                idx_in_post: 2,
+                level_name: "Too long".to_string(),
+                defn: Some(defn),
+                outcome: Outcome::Solver(solver::Outcome::Timeout(Some(solver::Phase::Global))),
+                solution_count: None,
+                repost_count: 0,
+            },
+        ]
+    }
+
+    fn temp_out_dir(name: &str) -> PathBuf {
+        let mut out_dir = std::env::temp_dir();
+        out_dir.push(format!(
+            "hexcells-solver-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        out_dir
+    }
+
+    #[test]
+    pub fn test_report_writer() {
+        let lines = sample_lines();
+        let expected_dir = temp_out_dir("expected");
+        report_all(&lines, &expected_dir).unwrap();
+        report_ranked(&lines, &expected_dir).unwrap();
+        report_html(&lines, &expected_dir).unwrap();
+
+        let out_dir = temp_out_dir("writer");
+        let mut writer = ReportWriter::create(&out_dir).unwrap();
+        for (i, line) in lines.iter().enumerate() {
+            assert_eq!(writer.append(line).unwrap(), i);
+        }
+        // The lines are on disk before `finish`
+        let all = fs::read_to_string(out_dir.join(ALL_FILE_NAME)).unwrap();
+        assert_eq!(all.lines().count(), 1 + lines.len());
+        let solutions: Vec<_> = all
+            .lines()
+            .map(|row| row.rsplit(',').nth(1).unwrap())
+            .collect();
+        assert_eq!(solutions, ["Solutions", "", "One", ""]);
+        let repost_counts = HashMap::from([(1, 2)]);
+        let histogram = writer.finish(&repost_counts).unwrap();
+        assert_eq!(histogram, report_histogram(&lines));
+        for file_name in [ALL_FILE_NAME, RANKED_FILE_NAME, HTML_FILE_NAME] {
+            assert_eq!(
+                fs::read_to_string(out_dir.join(file_name)).unwrap(),
+                fs::read_to_string(expected_dir.join(file_name)).unwrap(),
+                "{}",
+                file_name
+            );
+        }
+        fs::remove_dir_all(&out_dir).unwrap();
+        fs::remove_dir_all(&expected_dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_report_writer_resume() {
+        let lines = sample_lines();
+        let expected_dir = temp_out_dir("resume-expected");
+        report_all(&lines, &expected_dir).unwrap();
+        report_html(&lines, &expected_dir).unwrap();
+        let expected = |file_name| fs::read_to_string(expected_dir.join(file_name)).unwrap();
+        let repost_counts = HashMap::from([(1, 2)]);
+
+        // A scrape interrupted after 2 lines, in the middle of writing the third one
+        let out_dir = temp_out_dir("resume");
+        let _ = fs::remove_dir_all(&out_dir);
+        let mut writer = ReportWriter::create(&out_dir).unwrap();
+        writer.append(&lines[0]).unwrap();
+        writer.append(&lines[1]).unwrap();
+        drop(writer);
+        let all_path = out_dir.join(ALL_FILE_NAME);
+        let mut all_file = OpenOptions::new().append(true).open(&all_path).unwrap();
+        all_file.write_all(b"Timeout,12,2023").unwrap();
+        drop(all_file);
+        let before = fs::read_to_string(&all_path).unwrap();
+
+        // The rerun keeps the first 2 rows as they are and appends the third one
+        let mut writer = ReportWriter::create(&out_dir).unwrap();
+        for line in &lines {
+            writer.append(line).unwrap();
+        }
+        let all = fs::read_to_string(&all_path).unwrap();
+        let (_, partial) = before.rsplit_once('\n').unwrap();
+        assert!(all.starts_with(before.strip_suffix(partial).unwrap()));
+        assert_eq!(all.lines().count(), 1 + lines.len());
+        writer.finish(&repost_counts).unwrap();
+        assert_eq!(
+            fs::read_to_string(&all_path).unwrap(),
+            expected(ALL_FILE_NAME)
+        );
+        assert_eq!(
+            fs::read_to_string(out_dir.join(HTML_FILE_NAME)).unwrap(),
+            expected(HTML_FILE_NAME)
         );
-        let key = (
-            max_local.map(|i| -(i as i32)).unwrap_or(0),
-            max_global.map(|i| -(i as i32)).unwrap_or(0),
-            i,
+        assert!(!html_rows_path(&out_dir).exists());
+
+        // Rerunning a finished scrape changes nothing, even though its reposts are filled in
+        let mut writer = ReportWriter::create(&out_dir).unwrap();
+        for line in &lines {
+            writer.append(line).unwrap();
+        }
+        writer.finish(&repost_counts).unwrap();
+        assert_eq!(
+            fs::read_to_string(&all_path).unwrap(),
+            expected(ALL_FILE_NAME)
         );
-        report_lines.push((key, report_line));
+
+        // A different scrape replaces the rows from the first difference on
+        let mut writer = ReportWriter::create(&out_dir).unwrap();
+        writer.append(&lines[2]).unwrap();
+        writer.finish(&HashMap::new()).unwrap();
+        report_all(&sample_lines().split_off(2), &expected_dir).unwrap();
+        assert_eq!(
+            fs::read_to_string(&all_path).unwrap(),
+            expected(ALL_FILE_NAME)
+        );
+
+        // Another header starts from scratch
+        fs::write(&all_path, "Classif,Upvotes\nSpe,1\n").unwrap();
+        let mut writer = ReportWriter::create(&out_dir).unwrap();
+        writer.append(&lines[2]).unwrap();
+        writer.finish(&HashMap::new()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&all_path).unwrap(),
+            expected(ALL_FILE_NAME)
+        );
+        fs::remove_dir_all(&out_dir).unwrap();
+        fs::remove_dir_all(&expected_dir).unwrap();
     }
-    report_lines.sort();
-    let mut file = File::create("a0f661c5cb36180a3a6aca4bb4d385b2/1puzzles_ranked.csv").unwrap();
-    file.write_all(HEADER1.as_bytes()).unwrap();
-    for (_key, report_line) in &report_lines {
-        file.write_all(report_line.as_bytes()).unwrap();
-        file.write_all("\n".as_bytes()).unwrap();
+
+    #[test]
+    pub fn test_guessy() {
+        let outcome = Outcome::Solver(solver::Outcome::Solved {
+            findings: vec![],
+            guesses: 2,
+        });
+        let classif = classif_of_outcome(&outcome);
+        assert_eq!(classif, "G2");
+        assert_eq!(difficulty_key(&outcome), None);
+        assert_eq!(difficulty_key_of_classif(&classif), None);
+        let mut histogram = Histogram::default();
+        histogram.add(&outcome);
+        assert!(histogram.buckets.is_empty());
+        assert!(histogram.render().contains("Solved with guesses: 1\n"));
+    }
+
+    #[test]
+    pub fn test_already_solved() {
+        let outcome = Outcome::Solver(solver::Outcome::Solved {
+            findings: vec![],
+            guesses: 0,
+        });
+        let classif = classif_of_outcome(&outcome);
+        assert_eq!(classif, "0");
+        assert_eq!(difficulty_key(&outcome), Some((0, 0)));
+        assert_eq!(difficulty_key_of_classif(&classif), Some((0, 0)));
+        let mut histogram = Histogram::default();
+        histogram.add(&outcome);
+        assert!(histogram.render().contains("    -      1       0  #\n"));
+    }
+
+    #[test]
+    pub fn test_report_html() {
+        let lines = sample_lines();
+        let out_dir = temp_out_dir("html");
+        report_html(&lines, &out_dir).unwrap();
+        let html = fs::read_to_string(out_dir.join(HTML_FILE_NAME)).unwrap();
+        fs::remove_dir_all(&out_dir).unwrap();
+
+        assert_eq!(html.matches("<svg ").count(), 2);
+        assert!(html.contains("?a=1&amp;b=2"));
+        assert!(html.contains("Some &lt;levels&gt;"));
+        assert!(html.contains("<td>2</td></tr>"));
+        let solved = html.find("<td>4g15</td>").unwrap();
+        let parse_fail = html.find("<td>Parse failure</td>").unwrap();
+        let timeout = html.find("<td>Timeout in global_multiverse</td>").unwrap();
+        assert!(solved < parse_fail && parse_fail < timeout);
+
+        let histogram = report_histogram(&lines);
+        assert_eq!(
+            histogram,
+            "local  count  global\n\
+             \x20   4      1       1  #\n\
+             Unsolvable: 0\n\
+             Solved with guesses: 0\n\
+             Timeout: 1\n\
+             \x20 in compound: 0\n\
+             \x20 in global: 1\n\
+             Cancelled: 0\n\
+             Budget exceeded: 0\n\
+             Bugged grid: 0\n\
+             Parse failure: 1\n"
+        );
     }
 }
@@ -1,6 +1,7 @@
 use itertools::Itertools;
 use multiverse::Multiverse;
 use once_cell::sync::Lazy;
+use rayon::prelude::*;
 use serde::Deserialize;
 use serde::Serialize;
 use std::collections::BTreeMap;
@@ -8,8 +9,11 @@ use std::collections::BTreeSet;
 use std::convert::TryInto;
 use std::error::Error;
 use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
 use constraint;
+use constraint::LineGaps;
 use defn;
 use defn::Cell;
 use defn::Color;
@@ -26,33 +30,56 @@ struct Progress {
     unknowns: BTreeSet<Coords>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-enum Difficulty {
+/// The difficulty of a solving step. The difficulties are totally ordered: all the `Local` ones
+/// are below all the `Global` ones, which are below all the `Guess` ones. Ties are broken by the
+/// inner value, a guess being harder when its probability is lower.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Difficulty {
     Global(u32),
     Local(u32),
+    /// The solver was stuck and revealed the cell that was the most likely to have a given color.
+    /// The value is the probability of that color (i.e. between 0.5 and 1).
+    Guess(f64),
+}
+
+impl Ord for Difficulty {
+    fn cmp(&self, other: &Difficulty) -> std::cmp::Ordering {
+        use self::Difficulty::*;
+        match (self, other) {
+            (Local(a), Local(b)) | (Global(a), Global(b)) => a.cmp(b),
+            (Guess(a), Guess(b)) => b.total_cmp(a),
+            (Local(_), _) | (Global(_), Guess(_)) => std::cmp::Ordering::Less,
+            (_, Local(_)) | (Guess(_), Global(_)) => std::cmp::Ordering::Greater,
+        }
+    }
+}
+
+impl PartialOrd for Difficulty {
+    fn partial_cmp(&self, other: &Difficulty) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
+impl PartialEq for Difficulty {
+    fn eq(&self, other: &Difficulty) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for Difficulty {}
+
 impl Progress {
     fn of_defn(defn: &Defn) -> Progress {
         let mut blues = BTreeSet::new();
         let mut blacks = BTreeSet::new();
         let mut unknowns = BTreeSet::new();
-        let mut add = |coords: Coords, revealed: bool, color: Color| {
-            let _: bool = match (revealed, color) {
+        for (coords, cell) in defn::value_cells(defn) {
+            let color = defn::color_of_cell(cell).expect("Unreachable");
+            let _: bool = match (defn::is_revealed(cell), color) {
                 (false, _) => unknowns.insert(coords),
                 (true, Color::Black) => blacks.insert(coords),
                 (true, Color::Blue) => blues.insert(coords),
             };
-        };
-        for (coords, cell) in defn.iter() {
-            type C = defn::Cell;
-            match cell {
-                C::Empty => (),
-                C::Line { .. } => (),
-                C::Zone0 { revealed, color } => add(*coords, *revealed, *color),
-                C::Zone6 { revealed, .. } => add(*coords, *revealed, Color::Black),
-                C::Zone18 { revealed, .. } => add(*coords, *revealed, Color::Blue),
-            }
         }
         Progress {
             blues,
@@ -85,40 +112,45 @@ impl Progress {
 /// The exhausted ones are revealed but don't carry uncertainty anymore.
 /// The visible ones is the active set of constraint for the solver. The the actual puzzle, there
 /// are the constraints that the player has to look at in order to discover new cells.
+/// `merge_cache` memoizes the merged multiverses of the groups of visible constraints built by
+/// `compound_invariants`. An entry is dropped as soon as one of its constraints is narrowed.
 struct Constraints {
     constraints_hidden: BTreeMap<Coords, Multiverse>,
     constraints_visible: BTreeMap<Coords, Multiverse>,
     constraints_exhausted: BTreeSet<Coords>,
+    merge_cache: BTreeMap<BTreeSet<Coords>, Multiverse>,
 }
 
 /// This is used to give a virtual coordinate to the global constraint
 static UNIQUE_COORDS: Lazy<Coords> = Lazy::new(|| Coords::new(999, 0, -999));
 
 impl Constraints {
-    fn of_defn(defn: &Defn) -> Constraints {
+    /// `global_count` adds the global constraint, i.e. the count of blue cells of the whole grid.
+    fn of_defn(defn: &Defn, line_gaps: LineGaps, global_count: bool) -> Constraints {
         let mut constraints_hidden = BTreeMap::new();
         let mut constraints_visible = BTreeMap::new();
         let constraints_exhausted = BTreeSet::new();
-        for (coords, cell) in defn {
-            match cell {
-                Cell::Empty => (),
-                Cell::Zone0 { .. } => (),
-                Cell::Line { m, o } => {
-                    constraints_visible.insert(*coords, constraint::line(defn, *coords, *o, *m));
-                }
-                Cell::Zone6 { m, .. } => {
-                    constraints_hidden.insert(*coords, constraint::zone6(defn, *coords, *m));
+        let all = constraint::all_with_gaps(defn, line_gaps);
+        // The revealed zones start hidden as well, `reveal` moves them
+        for (coords, mv) in all.visible.into_iter().chain(all.hidden) {
+            match defn[&coords] {
+                Cell::Line { .. } | Cell::Column => {
+                    constraints_visible.insert(coords, mv);
                 }
-                Cell::Zone18 { .. } => {
-                    constraints_hidden.insert(*coords, constraint::zone18(defn, *coords));
+                Cell::Zone6 { .. } | Cell::Zone18 { .. } => {
+                    constraints_hidden.insert(coords, mv);
                 }
+                Cell::Empty | Cell::Zone0 { .. } => panic!("Unreachable"),
             }
         }
-        constraints_visible.insert(*UNIQUE_COORDS, constraint::global_blue_count(defn));
+        if global_count {
+            constraints_visible.insert(*UNIQUE_COORDS, all.global);
+        }
         Constraints {
             constraints_hidden,
             constraints_visible,
             constraints_exhausted,
+            merge_cache: BTreeMap::new(),
         }
     }
 
@@ -132,25 +164,37 @@ impl Constraints {
     }
 
     fn narrow(&mut self, visible_cells: &BTreeSet<Coords>, progress: &Progress) {
-        for (_k, mv) in self.constraints_visible.iter_mut() {
+        let mut narrowed = BTreeSet::new();
+        for (k, mv) in self.constraints_visible.iter_mut() {
             let inter: BTreeSet<_> = mv.scope.intersection(visible_cells).cloned().collect();
             if inter.is_empty() {
                 continue;
             }
+            let mut findings = BTreeMap::new();
             for coords in inter.intersection(&progress.blues) {
-                *mv = mv.learn(coords, Color::Blue);
+                findings.insert(*coords, Color::Blue);
             }
             for coords in inter.intersection(&progress.blacks) {
-                *mv = mv.learn(coords, Color::Black);
+                findings.insert(*coords, Color::Black);
             }
+            *mv = mv.learn_many(&findings);
+            narrowed.insert(*k);
         }
+        self.merge_cache
+            .retain(|kset, _| kset.is_disjoint(&narrowed));
     }
 
-    fn gc(&mut self) {
+    fn gc(&mut self) -> Result<(), Contradiction> {
         for k in self.constraints_visible.keys().cloned().collect::<Vec<_>>() {
             match self.constraints_visible[&k].state() {
                 State::Running => (),
-                State::Stuck => panic!("The grid is bugged and has no soltions"),
+                State::Stuck => {
+                    return Err(Contradiction {
+                        constraint: k,
+                        scope: self.constraints_visible[&k].scope.clone(),
+                        forced_both_ways: false,
+                    })
+                }
                 State::Empty => {
                     self.constraints_visible
                         .remove(&k.clone())
@@ -159,108 +203,208 @@ impl Constraints {
                 }
             }
         }
+        Ok(())
     }
 
     fn is_solved(&self) -> bool {
         self.constraints_visible.is_empty() && self.constraints_hidden.is_empty()
     }
 
-    fn trivial_invariants(&self, defn: &Defn) -> BTreeMap<Coords, Color> {
-        let mut invariants = BTreeMap::new();
-        for mv in self.constraints_visible.values() {
-            for (coords, color) in mv.invariants() {
-                if invariants.contains_key(&coords) {
-                    assert_eq!(color, invariants[&coords]);
+    /// Add `mv_invariants`, produced by the constraint at `constraint`, to `into`. Fails if a cell
+    /// is found with both colors, the constraints are then inconsistent (e.g. after a blind solver
+    /// was given wrong colors).
+    fn merge_invariants(
+        into: &mut BTreeMap<Coords, Color>,
+        mv_invariants: BTreeMap<Coords, Color>,
+        constraint: Coords,
+    ) -> Result<(), Contradiction> {
+        for (coords, color) in mv_invariants {
+            match into.insert(coords, color) {
+                Some(previous) if previous != color => {
+                    return Err(Contradiction {
+                        constraint,
+                        scope: BTreeSet::from([coords]),
+                        forced_both_ways: true,
+                    })
                 }
-                invariants.insert(coords, color);
-                assert_eq!(Some(color), defn::color_of_cell(&defn[&coords]));
+                _ => (),
             }
         }
-        invariants
+        Ok(())
     }
 
-    fn compound_invariants(
+    /// Returns the invariants along with the constraints that produced them.
+    #[allow(clippy::type_complexity)]
+    fn trivial_invariants(
         &self,
-        env: &mut Env,
-        defn: &Defn,
-    ) -> Result<(BTreeMap<Coords, Color>, Difficulty), Box<dyn Error>> {
-        // First construct the graph over visible constraints.
+    ) -> Result<(BTreeMap<Coords, Color>, BTreeSet<Coords>), Contradiction> {
+        let mut invariants = BTreeMap::new();
+        let mut justifications = BTreeSet::new();
+        for (k, mv) in &self.constraints_visible {
+            let mv_invariants = mv.invariants();
+            if !mv_invariants.is_empty() {
+                justifications.insert(*k);
+            }
+            Constraints::merge_invariants(&mut invariants, mv_invariants, *k)?;
+        }
+        Ok((invariants, justifications))
+    }
+
+    /// The graph over the visible constraints but the global one, two constraints are connected
+    /// when their scopes share a cell.
+    fn connections(&self) -> BTreeMap<Coords, BTreeSet<Coords>> {
         let mut connections: BTreeMap<Coords, BTreeSet<Coords>> = self
             .constraints_visible
             .keys()
+            .filter(|k| **k != *UNIQUE_COORDS)
             .map(|k| (*k, BTreeSet::new()))
             .collect();
-        for pair in self.constraints_visible.keys().combinations(2) {
-            let [k0, k1]: [&Coords; 2] = pair.try_into().expect("Unreachable");
-            if *k0 == *UNIQUE_COORDS || *k1 == *UNIQUE_COORDS {
-                continue;
-            }
-            let mv0 = &self.constraints_visible[k0];
-            let mv1 = &self.constraints_visible[k1];
+        let constraints = self
+            .constraints_visible
+            .iter()
+            .filter(|(k, _)| **k != *UNIQUE_COORDS);
+        for pair in constraints.combinations(2) {
+            let [(k0, mv0), (k1, mv1)]: [(&Coords, &Multiverse); 2] =
+                pair.try_into().expect("Unreachable");
             if !mv0.scope.is_disjoint(&mv1.scope) {
                 connections.get_mut(k0).expect("Unreachable").insert(*k1);
                 connections.get_mut(k1).expect("Unreachable").insert(*k0);
             }
         }
+        connections
+    }
+
+    /// Returns the invariants along with the constraints that produced them. With a `target`, only
+    /// the groups of constraints whose scope contains `target` are considered and the search stops
+    /// once `target` is found. The groups don't grow past `max_group_size` constraints.
+    /// The output is deterministic: the groups are built and scanned in the order of the
+    /// `BTreeMap`s and `BTreeSet`s of coords, and the merges distributed over the thread pool are
+    /// collected before being inserted, hence the scheduling of the threads doesn't matter.
+    #[allow(clippy::type_complexity)]
+    fn compound_invariants(
+        &mut self,
+        env: &mut Env,
+        target: Option<&Coords>,
+        max_group_size: Option<u32>,
+    ) -> Result<(BTreeMap<Coords, Color>, BTreeSet<Coords>, Difficulty), Box<dyn Error>> {
+        // First construct the graph over visible constraints.
+        let connections = self.connections();
 
         // Then build the set of compound invariants, starting with one visible constraint per
         // group
         let mut constraints_groups: BTreeMap<BTreeSet<Coords>, Multiverse> = self
             .constraints_visible
             .iter()
+            .filter(|(_, v)| target.is_none_or(|target| v.scope.contains(target)))
             .map(|(k, v)| (BTreeSet::from([*k]), v.clone()))
             .collect();
         constraints_groups.remove(&BTreeSet::from([*UNIQUE_COORDS]));
-        connections.remove(&*UNIQUE_COORDS);
 
         // Then escape if there are no visible constraints
         let mut invariants = BTreeMap::new();
+        let mut justifications = BTreeSet::new();
         let mut difficulty = 2;
         if constraints_groups.is_empty() {
-            return Ok((invariants, Difficulty::Local(difficulty)));
+            return Ok((invariants, justifications, Difficulty::Local(difficulty)));
         }
 
         // Then loop until one or more invariants are found or that all the graph has been collapsed
         loop {
+            if max_group_size.is_some_and(|max_group_size| difficulty > max_group_size) {
+                break;
+            }
             // One loop consists of increasing the size of constraint groups by one.
             // The first loop starts with `constraints_groups` being one group per node of the graph
             // and ends with `constraints_groups` being one group per edge of the graph.
 
             // For each group so far, for each neighbor cell in the graph, create a new group that
             // merges the old group with that neighbor.
-            for kset_old in constraints_groups.keys().cloned().collect::<Vec<_>>() {
-                env.check_timeout()?;
-                let mv_old = constraints_groups.remove(&kset_old).unwrap();
+            let groups_old = std::mem::take(&mut constraints_groups);
+            let mut candidates = BTreeMap::new();
+            for kset_old in groups_old.keys() {
+                env.check_abort()?;
                 let mut neighbor_contraints = BTreeSet::new();
-                for k in &kset_old {
+                for k in kset_old {
                     for k in &connections[k] {
                         if !kset_old.contains(k) {
                             neighbor_contraints.insert(k);
                         }
                     }
                 }
-                for k_new in &neighbor_contraints {
+                for k_new in neighbor_contraints {
                     let mut kset_new = kset_old.clone();
-                    kset_new.insert(**k_new);
-                    if constraints_groups.contains_key(&kset_new) {
+                    kset_new.insert(*k_new);
+                    if constraints_groups.contains_key(&kset_new)
+                        || candidates.contains_key(&kset_new)
+                    {
                         // A previous iteration already created that multiverse
                         continue;
                     }
-                    let mv_new = &self.constraints_visible[k_new];
-                    // `mv_old.merge(mv_new)` is computation intensive
-                    constraints_groups.insert(kset_new, mv_old.merge(mv_new));
+                    match self.merge_cache.get(&kset_new) {
+                        Some(mv) => {
+                            constraints_groups.insert(kset_new, mv.clone());
+                        }
+                        None => {
+                            candidates.insert(kset_new, (kset_old, k_new));
+                        }
+                    }
                 }
             }
 
-            // Look for invariants
-            for mv in constraints_groups.values() {
-                for (coords, color) in mv.invariants() {
-                    if invariants.contains_key(&coords) {
-                        assert_eq!(color, invariants[&coords]);
+            // `mv_old.merge(mv_new)` is computation intensive and each merge is independent from
+            // the others, they are distributed over the rayon thread pool. The workers poll
+            // `aborted` so that they all stop shortly after one of them hits the timeout (or the
+            // cancellation).
+            let aborted = AtomicBool::new(false);
+            let merged: Vec<_> = candidates
+                .into_par_iter()
+                .filter_map(|(kset_new, (kset_old, k_new))| {
+                    if aborted.load(Ordering::Relaxed) {
+                        return None;
                     }
-                    invariants.insert(coords, color);
-                    assert_eq!(Some(color), defn::color_of_cell(&defn[&coords]));
+                    if env.charge(1).is_err() {
+                        aborted.store(true, Ordering::Relaxed);
+                        return None;
+                    }
+                    let mv_old = &groups_old[kset_old];
+                    let mv_new = &self.constraints_visible[k_new];
+                    match mv_old.merge_checked(mv_new, env) {
+                        Ok(mv) => Some((kset_new, mv)),
+                        Err(_) => {
+                            aborted.store(true, Ordering::Relaxed);
+                            None
+                        }
+                    }
+                })
+                .collect();
+            if aborted.into_inner() {
+                // Timeouts and cancellations are sticky, `check_abort` still fails
+                return Err(env.check_abort().expect_err("Unreachable"));
+            }
+            for (kset_new, mv) in merged {
+                self.merge_cache.insert(kset_new.clone(), mv.clone());
+                constraints_groups.insert(kset_new, mv);
+            }
+
+            // Look for invariants. A group without layouts is inconsistent, which only a blind
+            // solver given wrong colors gets to.
+            for (kset, mv) in &constraints_groups {
+                let first = *kset.iter().next().expect("Unreachable");
+                if mv.state() == State::Stuck {
+                    return Err(Box::new(Contradiction {
+                        constraint: first,
+                        scope: mv.scope.clone(),
+                        forced_both_ways: false,
+                    }));
+                }
+                let mut mv_invariants = mv.invariants();
+                if let Some(target) = target {
+                    mv_invariants.retain(|coords, _| coords == target);
+                }
+                if !mv_invariants.is_empty() {
+                    justifications.extend(kset.iter().cloned());
                 }
+                Constraints::merge_invariants(&mut invariants, mv_invariants, first)?;
             }
 
             // Stop if necessary
@@ -272,109 +416,884 @@ impl Constraints {
             }
             difficulty += 1;
         }
-        Ok((invariants, Difficulty::Local(difficulty)))
+        Ok((invariants, justifications, Difficulty::Local(difficulty)))
     }
 
-    fn global_invariants(
-        &self,
-        env: &mut Env,
-        defn: &Defn,
-    ) -> Result<BTreeMap<Coords, Color>, Box<dyn Error>> {
-        let mut invariants = BTreeMap::new();
-        // Using rev() here is a quick and dirty hack to make sure that the
-        // global constraint is first in the fold. This greatly improves
-        // runtime.
-        let mut mv = Multiverse::empty();
-        for mv2 in self.constraints_visible.values().rev() {
-            env.check_timeout()?;
-            mv = mv.merge(mv2);
-        }
-        for (coords, color) in mv.invariants() {
-            if invariants.contains_key(&coords) {
-                assert_eq!(color, invariants[&coords]);
+    fn global_multiverse(&self, env: &mut Env) -> Result<Multiverse, Box<dyn Error>> {
+        Multiverse::merge_all_checked(self.constraints_visible.values(), env)
+    }
+
+    /// The coords of `mv` whose color is the most certain, along with the probability of that
+    /// color.
+    fn best_guess(mv: &Multiverse) -> Option<(Coords, f64)> {
+        let mut best: Option<(Coords, f64)> = None;
+        for (coords, p) in mv.marginals() {
+            let certainty = p.max(1.0 - p);
+            match best {
+                Some((_, best_certainty)) if best_certainty >= certainty => (),
+                _ => best = Some((coords, certainty)),
             }
-            invariants.insert(coords, color);
-            assert_eq!(Some(color), defn::color_of_cell(&defn[&coords]));
         }
-        Ok(invariants)
+        best
     }
 }
 
+/// The cells discovered during one step of the solver. `justifications` holds the coords of the
+/// constraints whose (merged) multiverse produced these cells, the global constraint being
+/// represented by `UNIQUE_COORDS`.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Findings {
     difficulty: Difficulty,
     cells: BTreeSet<Coords>,
+    #[serde(default)]
+    justifications: BTreeSet<Coords>,
+    #[serde(default)]
+    scope_size: Option<u32>,
+    #[serde(default)]
+    elapsed: Option<Duration>,
+    #[serde(default)]
+    blues: Option<BTreeSet<Coords>>,
+}
+
+impl Findings {
+    pub fn difficulty(&self) -> Difficulty {
+        self.difficulty
+    }
+
+    /// The cells discovered during that step
+    pub fn cells(&self) -> &BTreeSet<Coords> {
+        &self.cells
+    }
+
+    /// The constraints that justify the discovery of `cells`
+    pub fn justifications(&self) -> &BTreeSet<Coords> {
+        &self.justifications
+    }
+
+    /// The number of unknown cells that had to be considered jointly to make that step, i.e. the
+    /// union of the scopes of the `justifications`. Only computed with
+    /// `SolveOptions::scope_size`.
+    pub fn scope_size(&self) -> Option<u32> {
+        self.scope_size
+    }
+
+    /// The time spent looking for the `cells` (steps 5.1 to 5.4 of the solver loop). Only
+    /// measured with `SolveOptions::timing`.
+    pub fn elapsed(&self) -> Option<Duration> {
+        self.elapsed
+    }
+
+    /// The blue cells among `cells`, the others being black. Only recorded with
+    /// `SolveOptions::blind`, otherwise the colors are the ones of the `Defn`.
+    pub fn blues(&self) -> Option<&BTreeSet<Coords>> {
+        self.blues.as_ref()
+    }
+}
+
+/// The grid is bugged: the cells of the `Defn` don't satisfy one of its constraints.
+/// `constraint` is the coords of that constraint (`UNIQUE_COORDS` for the global one). When the
+/// constraints force opposite colors on a cell, `forced_both_ways` is set, `scope` is that cell
+/// and `constraint` is one of the constraints that forced it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contradiction {
+    pub constraint: Coords,
+    pub scope: BTreeSet<Coords>,
+    #[serde(default)]
+    pub forced_both_ways: bool,
+}
+
+impl Error for Contradiction {}
+
+impl fmt::Display for Contradiction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.scope.iter().next() {
+            Some(cell) if self.forced_both_ways => write!(
+                f,
+                "The cell at {} is forced blue and black (constraint at {})",
+                cell, self.constraint
+            ),
+            _ => write!(
+                f,
+                "The constraint at {} is not satisfied by the {} cells of its scope",
+                self.constraint,
+                self.scope.len()
+            ),
+        }
+    }
+}
+
+/// The solver deduced a color that differs from the one of the `Defn`. The hints of the grid are
+/// inconsistent with its cells in a way that `check_solvable` doesn't catch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ColorMismatch {
+    pub coords: Coords,
+    /// The color of the cell in the `Defn`
+    pub expected: Color,
+    /// The color deduced by the solver
+    pub got: Color,
+}
+
+impl Error for ColorMismatch {}
+
+impl fmt::Display for ColorMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The cell at {} was deduced {:?} instead of {:?}",
+            self.coords, self.got, self.expected
+        )
+    }
+}
+
+/// A cell that can't be revealed to the solver, see `Solver::new_from` and `Solver::reveal`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RevealError {
+    /// The cell has no color in the `Defn`, e.g. it is empty or holds a line hint
+    NoColor(Coords),
+    /// The cell is already visible
+    NotUnknown(Coords),
+    /// The color differs from the one of the `Defn`, `got` being the revealed one. Never raised
+    /// by a blind solver.
+    ColorMismatch(ColorMismatch),
+    /// The revealed colors break a hint, which only a blind solver lets through
+    Contradiction(Contradiction),
+}
+
+impl Error for RevealError {}
+
+impl fmt::Display for RevealError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RevealError::NoColor(coords) => write!(f, "The cell at {} has no color", coords),
+            RevealError::NotUnknown(coords) => write!(f, "The cell at {} is not unknown", coords),
+            RevealError::ColorMismatch(ColorMismatch {
+                coords,
+                expected,
+                got,
+            }) => write!(
+                f,
+                "The cell at {} was revealed {:?} instead of {:?}",
+                coords, got, expected
+            ),
+            RevealError::Contradiction(contradiction) => write!(f, "{}", contradiction),
+        }
+    }
+}
+
+/// Check that `coords` can take `color`: it must have a color in `defn`, the same one unless
+/// `blind`.
+fn check_reveal(defn: &Defn, coords: Coords, color: Color, blind: bool) -> Result<(), RevealError> {
+    match defn.get(&coords).and_then(defn::color_of_cell) {
+        None => Err(RevealError::NoColor(coords)),
+        Some(expected) if !blind && expected != color => {
+            Err(RevealError::ColorMismatch(ColorMismatch {
+                coords,
+                expected,
+                got: color,
+            }))
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+/// Check that the cells of `defn` satisfy all of its constraints, without running the solver.
+pub fn check_solvable(defn: &Defn) -> Result<(), Contradiction> {
+    check_solvable_with(defn, &SolveOptions::default())
+}
+
+/// Same as [check_solvable] but with the constraints built for `options`, only
+/// `options.use_global_count` and `options.line_gaps` are read.
+pub fn check_solvable_with(defn: &Defn, options: &SolveOptions) -> Result<(), Contradiction> {
+    let colors: BTreeMap<_, _> = defn
+        .iter()
+        .filter_map(|(coords, cell)| defn::color_of_cell(cell).map(|color| (*coords, color)))
+        .collect();
+    let constraints = Constraints::of_defn(defn, options.line_gaps, options.use_global_count);
+    let all = constraints
+        .constraints_hidden
+        .iter()
+        .chain(constraints.constraints_visible.iter());
+    for (k, mv) in all {
+        if !mv.is_consistent(&colors) {
+            return Err(Contradiction {
+                constraint: *k,
+                scope: mv.scope.clone(),
+                forced_both_ways: false,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// The hints of a puzzle and the ones that interact, see [constraint_graph]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConstraintGraph {
+    /// The coords of the hints
+    pub nodes: BTreeSet<Coords>,
+    /// The pairs of hints whose scopes share at least one cell, the lowest coords first
+    pub edges: BTreeSet<(Coords, Coords)>,
+}
+
+impl ConstraintGraph {
+    /// The graph in the DOT language of Graphviz, the nodes are named after their coords
+    pub fn to_dot(&self) -> String {
+        let mut dot = "graph constraints {\n".to_string();
+        for k in &self.nodes {
+            dot.push_str(&format!("    \"{}\";\n", k));
+        }
+        for (k0, k1) in &self.edges {
+            dot.push_str(&format!("    \"{}\" -- \"{}\";\n", k0, k1));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// The graph of the hints of `defn` that the local invariant search walks: the constraints grow
+/// groups along its edges. All the hints are nodes, the ones of the hidden cells included, but
+/// the global blue count is left out since it overlaps all the others.
+pub fn constraint_graph(defn: &Defn) -> ConstraintGraph {
+    // Left out anyway, whatever `SolveOptions::use_global_count`
+    let mut constraints = Constraints::of_defn(defn, LineGaps::default(), false);
+    let hidden: BTreeSet<_> = constraints.constraints_hidden.keys().cloned().collect();
+    constraints.reveal(&hidden);
+    let connections = constraints.connections();
+    let edges = connections
+        .iter()
+        .flat_map(|(k0, ks)| {
+            ks.iter()
+                .filter(move |k1| k0 < k1)
+                .map(move |k1| (*k0, *k1))
+        })
+        .collect();
+    ConstraintGraph {
+        nodes: connections.into_keys().collect(),
+        edges,
+    }
+}
+
+/// Number of solutions of a puzzle, see [count_solutions]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SolutionCount {
+    Zero,
+    One,
+    /// At least 2
+    Many,
 }
 
+/// Count the colorings of the initially unknown cells of `defn` that satisfy what the player can
+/// ever see, i.e. the visible hints, the global blue count and the hints of the hidden cells when
+/// these cells take their color from `defn` (a hidden zone6 is only a hint if it is black). A fair
+/// puzzle has exactly one solution.
+/// The cells that the solver finds have the same color in all the solutions, the constraints are
+/// then merged over the remaining cells. This may be expensive and it is charged to `env`.
+pub fn count_solutions(env: &mut Env, defn: &Defn) -> Result<SolutionCount, Box<dyn Error>> {
+    count_solutions_with(env, defn, &SolveOptions::default())
+}
+
+/// Same as [count_solutions] but with the rules of `options`, only `options.use_global_count` and
+/// `options.line_gaps` are read. Without the global count, the player never sees it and it doesn't
+/// restrict the solutions.
+pub fn count_solutions_with(
+    env: &mut Env,
+    defn: &Defn,
+    options: &SolveOptions,
+) -> Result<SolutionCount, Box<dyn Error>> {
+    let solver_options = SolveOptions {
+        use_global_count: options.use_global_count,
+        line_gaps: options.line_gaps,
+        ..SolveOptions::default()
+    };
+    let mut solver = Solver::new(defn, solver_options);
+    loop {
+        match solver.step(env) {
+            StepResult::Finding(_) => (),
+            StepResult::Solved => return Ok(SolutionCount::One),
+            StepResult::Unsolvable
+            | StepResult::Contradiction(_)
+            | StepResult::ColorMismatch(_) => break,
+            StepResult::Timeout(_) => return Err(Box::new(env::Timeout)),
+            StepResult::Cancelled => return Err(Box::new(env::Cancelled)),
+            StepResult::BudgetExceeded => return Err(Box::new(env::BudgetExceeded)),
+        }
+    }
+    let progress = solver.progress;
+    let found: BTreeMap<_, _> = progress
+        .blues
+        .iter()
+        .map(|c| (*c, Color::Blue))
+        .chain(progress.blacks.iter().map(|c| (*c, Color::Black)))
+        .collect();
+    count_solutions_from(env, defn, &found, options)
+}
+
+/// Same as [count_solutions_with] but skips the solver, the cells of `found` are taken as the ones
+/// it would find, e.g. the `Outcome::deductions` of a cached outcome. These must have the same
+/// color in all the solutions, any subset of them gives the same count, only slower.
+pub fn count_solutions_from(
+    env: &mut Env,
+    defn: &Defn,
+    found: &BTreeMap<Coords, Color>,
+    options: &SolveOptions,
+) -> Result<SolutionCount, Box<dyn Error>> {
+    let mut known = found.clone();
+    for (coords, cell) in defn.iter() {
+        if defn::is_revealed(cell) {
+            known.insert(*coords, defn::color_of_cell(cell).expect("Unreachable"));
+        }
+    }
+    let constraints = Constraints::of_defn(defn, options.line_gaps, options.use_global_count);
+    let hidden = constraints.constraints_hidden.iter().map(|(k, mv)| {
+        let color = defn::color_of_cell(&defn[k]).expect("Unreachable");
+        if known.contains_key(k) {
+            (k, mv.clone())
+        } else {
+            (k, constraint::conditional(mv, *k, color))
+        }
+    });
+    let visible = constraints
+        .constraints_visible
+        .iter()
+        .map(|(k, mv)| (k, mv.clone()));
+    let mut narrowed_all = vec![];
+    for (_, mv) in visible.chain(hidden) {
+        let findings: BTreeMap<_, _> = mv
+            .scope
+            .iter()
+            .filter_map(|c| known.get(c).map(|color| (*c, *color)))
+            .collect();
+        let narrowed = mv.learn_many(&findings);
+        if narrowed.scope.is_empty() && !mv.is_consistent(&findings) {
+            return Ok(SolutionCount::Zero);
+        }
+        narrowed_all.push(narrowed);
+    }
+    let merged = Multiverse::merge_all_checked(&narrowed_all, env)?;
+    // Without the global constraint, an unknown cell out of every scope takes both colors
+    let free = defn
+        .iter()
+        .filter(|(coords, cell)| defn::color_of_cell(cell).is_some() && !known.contains_key(coords))
+        .any(|(coords, _)| !merged.scope.contains(coords));
+    Ok(match merged.solution_count_capped(2) {
+        0 => SolutionCount::Zero,
+        1 if !free => SolutionCount::One,
+        _ => SolutionCount::Many,
+    })
+}
+
+/// Answer of [query]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryResult {
+    /// The color of the target follows from the `justifications` constraints (`UNIQUE_COORDS`
+    /// standing for the global one), `difficulty` is the one of the solver step that would find
+    /// it. A target that is already visible has no justifications and a `Local(0)` difficulty.
+    Determined {
+        color: Color,
+        justifications: BTreeSet<Coords>,
+        difficulty: Difficulty,
+    },
+    Undetermined,
+}
+
+/// Whether the color of `target` can be determined in the initial state of `defn`. This runs the
+/// invariant search of one solver step (trivial, then compound, then global) but only looks for
+/// `target`, hence the local searches only consider the constraints that touch `target`. A grid
+/// whose constraints force opposite colors on a cell errors with that [Contradiction].
+pub fn query(env: &mut Env, defn: &Defn, target: Coords) -> Result<QueryResult, Box<dyn Error>> {
+    query_with(env, defn, target, &SolveOptions::default())
+}
+
+/// Same as [query] but with the constraints built for `options`, only `options.use_global_count`
+/// and `options.line_gaps` are read.
+pub fn query_with(
+    env: &mut Env,
+    defn: &Defn,
+    target: Coords,
+    options: &SolveOptions,
+) -> Result<QueryResult, Box<dyn Error>> {
+    let progress = Progress::of_defn(defn);
+    for (cells, color) in [
+        (&progress.blues, Color::Blue),
+        (&progress.blacks, Color::Black),
+    ] {
+        if cells.contains(&target) {
+            return Ok(QueryResult::Determined {
+                color,
+                justifications: BTreeSet::new(),
+                difficulty: Difficulty::Local(0),
+            });
+        }
+    }
+    let mut constraints = Constraints::of_defn(defn, options.line_gaps, options.use_global_count);
+    let visible_cells: BTreeSet<_> = progress.blacks.union(&progress.blues).cloned().collect();
+    constraints.reveal(&visible_cells);
+    constraints.narrow(&visible_cells, &progress);
+    constraints.gc()?;
+    let determined = |color, justifications, difficulty| {
+        Ok(QueryResult::Determined {
+            color,
+            justifications,
+            difficulty,
+        })
+    };
+
+    // Trivial
+    let (invariants, _) = constraints.trivial_invariants()?;
+    if let Some(color) = invariants.get(&target) {
+        let justifications = constraints
+            .constraints_visible
+            .iter()
+            .filter(|(_, mv)| mv.invariants().contains_key(&target))
+            .map(|(k, _)| *k)
+            .collect();
+        return determined(*color, justifications, Difficulty::Local(1));
+    }
+
+    // Compound
+    let (invariants, justifications, difficulty) =
+        constraints.compound_invariants(env, Some(&target), None)?;
+    if let Some(color) = invariants.get(&target) {
+        return determined(*color, justifications, difficulty);
+    }
+
+    // Global
+    let mv = constraints.global_multiverse(env)?;
+    if let Some(color) = mv.invariants().get(&target) {
+        let difficulty =
+            Difficulty::Global(constraints.constraints_visible.len().try_into().unwrap());
+        let justifications = constraints.constraints_visible.keys().cloned().collect();
+        return determined(*color, justifications, difficulty);
+    }
+    Ok(QueryResult::Undetermined)
+}
+
+/// The phase of a solver step that ran out of time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Phase {
+    /// Looking for invariants within groups of constraints, see step 5.2 of `Solver::step`
+    Compound,
+    /// Merging all the constraints, see step 5.3 of `Solver::step`
+    Global,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::Compound => write!(f, "compound_invariants"),
+            Phase::Global => write!(f, "global_multiverse"),
+        }
+    }
+}
+
+/// `Serialize` and `Deserialize` are implemented by hand below, the derived ones are only reached
+/// through them.
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(remote = "Self")]
 pub enum Outcome {
+    /// The phase is `None` for the outcomes cached before it was recorded
+    Timeout(Option<Phase>),
+    Cancelled,
+    BudgetExceeded,
+    /// Stuck before the end, with no guess left to make (i.e. `SolveOptions::guess` is off). The
+    /// findings of the steps made until then.
+    Unsolvable {
+        findings: Vec<Findings>,
+    },
+    /// The findings of each step, `guesses` of them are `Difficulty::Guess` steps. A puzzle that
+    /// needs no guess is fair, the other ones lack information somewhere.
+    Solved {
+        findings: Vec<Findings>,
+        guesses: u32,
+    },
+    Contradiction(Contradiction),
+    ColorMismatch(ColorMismatch),
+}
+
+impl Serialize for Outcome {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Outcome::serialize(self, serializer)
+    }
+}
+
+/// The `Outcome`s as they may be found in the caches
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OutcomeRepr {
+    Legacy(LegacyOutcome),
+    Current(#[serde(with = "Outcome")] Outcome),
+}
+
+/// The variants whose shape changed since they were first cached
+#[derive(Deserialize)]
+enum LegacyOutcome {
     Timeout,
-    Unsolvable,
     Solved(Vec<Findings>),
+    Unsolvable,
 }
 
-pub fn difficulty_of_findings_vec(findings_vec: &Vec<Findings>) -> (Option<u32>, Option<u32>) {
-    let mut max_local = None;
-    let mut max_global = None;
-    for findings in findings_vec {
-        match findings.difficulty {
-            Difficulty::Global(diff) => {
-                max_global = Some(max_global.map_or(diff, |prev_max: u32| prev_max.max(diff)));
+impl<'de> Deserialize<'de> for Outcome {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Outcome, D::Error> {
+        Ok(match OutcomeRepr::deserialize(deserializer)? {
+            OutcomeRepr::Legacy(LegacyOutcome::Timeout) => Outcome::Timeout(None),
+            OutcomeRepr::Legacy(LegacyOutcome::Solved(findings)) => Outcome::solved(findings),
+            OutcomeRepr::Legacy(LegacyOutcome::Unsolvable) => {
+                Outcome::Unsolvable { findings: vec![] }
             }
-            Difficulty::Local(diff) => {
-                max_local = Some(max_local.map_or(diff, |prev_max: u32| prev_max.max(diff)));
+            OutcomeRepr::Current(outcome) => outcome,
+        })
+    }
+}
+
+/// Snapshot of the solver state passed to `SolveOptions::on_step`. `difficulty` is the one of the
+/// previous step, `None` on the first step.
+#[derive(Debug, Clone)]
+pub struct SolveProgress {
+    pub step: usize,
+    pub visible_count: usize,
+    pub unknown_count: usize,
+    pub difficulty: Option<Difficulty>,
+}
+
+/// Solver configuration. The default is a silent solver that never guesses.
+pub struct SolveOptions {
+    /// When the solver is stuck, instead of returning `Outcome::Unsolvable`, reveal the cell that
+    /// is the most likely to have a given color and continue. These steps are recorded with
+    /// `Difficulty::Guess`.
+    pub guess: bool,
+    /// Compute `Findings::scope_size`, an alternative difficulty metric
+    pub scope_size: bool,
+    /// Measure `Findings::elapsed`
+    pub timing: bool,
+    /// Don't look for compound invariants within groups of more than that many constraints, fall
+    /// through to the global constraint instead. Trades completeness for speed.
+    pub max_group_size: Option<u32>,
+    /// Don't consult the colors of the hidden cells of the `Defn`: the deduced colors are neither
+    /// checked against them nor used to shortcut the solving, and are recorded in
+    /// `Findings::blues`. For boards whose answer is unknown. Disables `guess`, which needs the
+    /// actual colors to reveal a cell.
+    pub blind: bool,
+    /// How the line hints treat the holes of their axis, the base game skips them
+    pub line_gaps: LineGaps,
+    /// Use the count of blue cells of the whole grid, on by default. Some custom levels hide that
+    /// counter, in which case the solver must rely on the hints of the grid only.
+    pub use_global_count: bool,
+    /// Called at the beginning of each iteration of the solver loop.
+    #[allow(clippy::type_complexity)]
+    pub on_step: Option<Box<dyn FnMut(&SolveProgress)>>,
+}
+
+impl Default for SolveOptions {
+    fn default() -> SolveOptions {
+        SolveOptions {
+            guess: false,
+            scope_size: false,
+            timing: false,
+            max_group_size: None,
+            blind: false,
+            line_gaps: LineGaps::default(),
+            use_global_count: true,
+            on_step: None,
+        }
+    }
+}
+
+impl fmt::Debug for SolveOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SolveOptions")
+            .field("guess", &self.guess)
+            .field("scope_size", &self.scope_size)
+            .field("timing", &self.timing)
+            .field("max_group_size", &self.max_group_size)
+            .field("blind", &self.blind)
+            .field("line_gaps", &self.line_gaps)
+            .field("use_global_count", &self.use_global_count)
+            .field("on_step", &self.on_step.as_ref().map(|_| "<callback>"))
+            .finish()
+    }
+}
+
+pub fn difficulty_of_findings_vec(findings_vec: &[Findings]) -> (Option<u32>, Option<u32>) {
+    let difficulties = findings_vec.iter().map(|findings| findings.difficulty);
+    let max_local = difficulties
+        .clone()
+        .filter(|difficulty| matches!(difficulty, Difficulty::Local(_)))
+        .max();
+    let max_global = difficulties
+        .filter(|difficulty| matches!(difficulty, Difficulty::Global(_)))
+        .max();
+    let inner = |difficulty| match difficulty {
+        Difficulty::Local(diff) | Difficulty::Global(diff) => diff,
+        Difficulty::Guess(_) => panic!("Unreachable"),
+    };
+    (max_local.map(inner), max_global.map(inner))
+}
+
+impl Outcome {
+    /// `Solved` with its guesses counted from `findings`
+    fn solved(findings: Vec<Findings>) -> Outcome {
+        let guesses = findings
+            .iter()
+            .filter(|findings| matches!(findings.difficulty, Difficulty::Guess(_)))
+            .count();
+        Outcome::Solved {
+            findings,
+            guesses: guesses.try_into().expect("Unreachable"),
+        }
+    }
+
+    fn findings_vec(&self) -> Option<&Vec<Findings>> {
+        match self {
+            Outcome::Solved { findings, .. } => Some(findings),
+            _ => None,
+        }
+    }
+
+    /// The cells found before the first guess, with their colors, i.e. the ones that have the same
+    /// color in all the solutions of `defn`. Empty for the outcomes that hold no findings.
+    pub fn deductions(&self, defn: &Defn) -> BTreeMap<Coords, Color> {
+        let findings_vec = match self {
+            Outcome::Solved { findings, .. } | Outcome::Unsolvable { findings } => findings,
+            _ => return BTreeMap::new(),
+        };
+        let mut deductions = BTreeMap::new();
+        for findings in findings_vec
+            .iter()
+            .take_while(|findings| !matches!(findings.difficulty, Difficulty::Guess(_)))
+        {
+            for coords in &findings.cells {
+                let color = match &findings.blues {
+                    Some(blues) if blues.contains(coords) => Color::Blue,
+                    Some(_) => Color::Black,
+                    None => defn::color_of_cell(&defn[coords]).expect("Unreachable"),
+                };
+                deductions.insert(*coords, color);
             }
         }
+        deductions
+    }
+
+    /// Number of guesses needed to solve the puzzle, `None` if it wasn't solved
+    pub fn guess_count(&self) -> Option<u32> {
+        match self {
+            Outcome::Solved { guesses, .. } => Some(*guesses),
+            _ => None,
+        }
+    }
+
+    /// Number of steps needed to solve the puzzle, `None` if it wasn't solved
+    pub fn step_count(&self) -> Option<usize> {
+        self.findings_vec().map(|findings_vec| findings_vec.len())
+    }
+
+    /// Highest difficulty of the local steps, `None` if the puzzle wasn't solved or if it didn't
+    /// need any local step
+    pub fn max_local_difficulty(&self) -> Option<u32> {
+        difficulty_of_findings_vec(self.findings_vec()?).0
+    }
+
+    /// Highest difficulty of the global steps, `None` if the puzzle wasn't solved or if it didn't
+    /// need any global step
+    pub fn max_global_difficulty(&self) -> Option<u32> {
+        difficulty_of_findings_vec(self.findings_vec()?).1
+    }
+
+    /// Highest `Findings::scope_size`, `None` if the puzzle wasn't solved or if the sizes were not
+    /// computed
+    pub fn max_scope_size(&self) -> Option<u32> {
+        self.findings_vec()?
+            .iter()
+            .filter_map(|findings| findings.scope_size)
+            .max()
+    }
+}
+
+/// The difficulty of the step that revealed each cell, empty if the puzzle wasn't solved. The cells
+/// revealed from the start are absent.
+pub fn cell_difficulties(outcome: &Outcome) -> BTreeMap<Coords, Difficulty> {
+    let mut difficulties = BTreeMap::new();
+    for findings in outcome.findings_vec().into_iter().flatten() {
+        for coords in &findings.cells {
+            difficulties.insert(*coords, findings.difficulty);
+        }
     }
-    (max_local, max_global)
+    difficulties
 }
 
 impl fmt::Display for Outcome {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Outcome::Unsolvable => write!(f, "Requires additional rules"),
-            Outcome::Timeout => write!(f, "Timeout"),
-            Outcome::Solved(findings_vec) => {
-                let mut steps = 0;
-                let mut max_local = None;
-                let mut max_global = None;
-                for findings in findings_vec {
-                    steps += 1;
-                    match findings.difficulty {
-                        Difficulty::Global(diff) => {
-                            max_global =
-                                Some(max_global.map_or(diff, |prev_max: u32| prev_max.max(diff)));
-                        }
-                        Difficulty::Local(diff) => {
-                            max_local =
-                                Some(max_local.map_or(diff, |prev_max: u32| prev_max.max(diff)));
-                        }
-                    }
-                }
+            Outcome::Unsolvable { .. } => write!(f, "Requires additional rules"),
+            Outcome::Timeout(None) => write!(f, "Timeout"),
+            Outcome::Timeout(Some(phase)) => write!(f, "Timeout in {}", phase),
+            Outcome::Cancelled => write!(f, "Cancelled"),
+            Outcome::BudgetExceeded => write!(f, "Merge budget exceeded"),
+            Outcome::Contradiction(contradiction) => write!(f, "Bugged grid: {}", contradiction),
+            Outcome::ColorMismatch(mismatch) => write!(f, "Bugged grid: {}", mismatch),
+            Outcome::Solved { findings, guesses } => {
                 write!(
                     f,
                     "Solved steps:{} max-local-difficulty:{:?} max-global-difficulty:{:?}",
-                    steps, max_local, max_global
-                )
+                    findings.len(),
+                    self.max_local_difficulty(),
+                    self.max_global_difficulty()
+                )?;
+                if *guesses > 0 {
+                    write!(f, " guesses:{}", guesses)?;
+                }
+                if let Some(scope_size) = self.max_scope_size() {
+                    write!(f, " max-scope-size:{}", scope_size)?;
+                }
+                Ok(())
             }
         }
     }
 }
 
-pub fn solve(env: &mut Env, defn: &Defn, verbose: bool) -> Outcome {
-    let mut progress = Progress::of_defn(defn);
-    let mut constraints = Constraints::of_defn(defn);
-    let mut history = vec![];
-    let mut difficulty;
-    loop {
+/// Result of one iteration of the solver loop.
+#[derive(Debug)]
+pub enum StepResult {
+    Finding(Findings),
+    Solved,
+    Unsolvable,
+    Timeout(Phase),
+    Cancelled,
+    BudgetExceeded,
+    Contradiction(Contradiction),
+    ColorMismatch(ColorMismatch),
+}
+
+impl StepResult {
+    /// Convert the error of an aborted computation, see `Env::check_abort`. Exceeding the layout
+    /// cap counts as exceeding the budget. The contradictions between the invariants of the
+    /// constraints are passed through.
+    fn of_abort(err: Box<dyn Error>, phase: Phase) -> StepResult {
+        if let Some(contradiction) = err.downcast_ref::<Contradiction>() {
+            StepResult::Contradiction(contradiction.clone())
+        } else if err.is::<env::Timeout>() {
+            StepResult::Timeout(phase)
+        } else if err.is::<env::Cancelled>() {
+            StepResult::Cancelled
+        } else if err.is::<env::BudgetExceeded>() || err.is::<env::TooManyLayouts>() {
+            StepResult::BudgetExceeded
+        } else {
+            panic!("{} failed: {}", phase, err)
+        }
+    }
+}
+
+/// Incremental solver. Each call to `step` performs one iteration of the solver loop, the state is
+/// kept between the calls.
+pub struct Solver<'a> {
+    defn: &'a Defn,
+    options: SolveOptions,
+    progress: Progress,
+    constraints: Constraints,
+    step_count: usize,
+    last_difficulty: Option<Difficulty>,
+}
+
+impl<'a> Solver<'a> {
+    pub fn new(defn: &'a Defn, options: SolveOptions) -> Solver<'a> {
+        Solver::new_from(defn, &BTreeMap::new(), options).expect("Unreachable")
+    }
+
+    /// Same as [new] but the cells of `known` are considered as already revealed, e.g. because
+    /// the player uncovered them.
+    /// Fails if one of the cells of `known` has no color in `defn` or, unless `options.blind`,
+    /// another color.
+    pub fn new_from(
+        defn: &'a Defn,
+        known: &BTreeMap<Coords, Color>,
+        options: SolveOptions,
+    ) -> Result<Solver<'a>, RevealError> {
+        for (coords, color) in known {
+            check_reveal(defn, *coords, *color, options.blind)?;
+        }
+        let mut progress = Progress::of_defn(defn);
+        progress.update(known.clone());
+        let constraints = Constraints::of_defn(defn, options.line_gaps, options.use_global_count);
+        Ok(Solver {
+            defn,
+            options,
+            progress,
+            constraints,
+            step_count: 0,
+            last_difficulty: None,
+        })
+    }
+
+    /// Reveal the unknown cell at `coords`, e.g. because the player uncovered it, and narrow the
+    /// constraints accordingly. The constraints are kept, the next [Solver::step] only looks for
+    /// the cells that follow from that one.
+    /// Fails, leaving the solver untouched, if the cell is not unknown or, unless `options.blind`,
+    /// if it doesn't have that color in the `defn`. A blind solver reports the colors that break a
+    /// hint as a `RevealError::Contradiction`.
+    pub fn reveal(&mut self, coords: Coords, color: Color) -> Result<(), RevealError> {
+        check_reveal(self.defn, coords, color, self.options.blind)?;
+        if !self.progress.unknowns.contains(&coords) {
+            return Err(RevealError::NotUnknown(coords));
+        }
+        self.progress.update(BTreeMap::from([(coords, color)]));
+        let progress = &self.progress;
         let visible_cells: BTreeSet<_> = progress.blacks.union(&progress.blues).cloned().collect();
-        if verbose {
-            println!(
-                "Solver loop with visibles:{}, unknown:{}",
-                visible_cells.len(),
-                progress.unknowns.len(),
-            );
+        self.constraints.reveal(&visible_cells);
+        self.constraints.narrow(&visible_cells, progress);
+        self.constraints.gc().map_err(RevealError::Contradiction)
+    }
+
+    /// The history of a puzzle that doesn't need the solver loop: either no cell is left unknown
+    /// or the unknown cells all have the same color, in which case the global blue count gives
+    /// them all in a single step.
+    fn trivial_history(&self) -> Option<Vec<Findings>> {
+        let unknowns = &self.progress.unknowns;
+        if unknowns.is_empty() {
+            return Some(vec![]);
+        }
+        if self.options.blind || !self.options.use_global_count {
+            return None;
+        }
+        let mut colors = unknowns
+            .iter()
+            .map(|coords| defn::color_of_cell(&self.defn[coords]).expect("Unreachable"));
+        let first = colors.next().expect("Unreachable");
+        if !colors.all(|color| color == first) {
+            return None;
         }
+        let findings = Findings {
+            difficulty: Difficulty::Local(1),
+            cells: unknowns.clone(),
+            justifications: BTreeSet::from([*UNIQUE_COORDS]),
+            scope_size: if self.options.scope_size {
+                Some(unknowns.len().try_into().unwrap())
+            } else {
+                None
+            },
+            elapsed: if self.options.timing {
+                Some(Duration::ZERO)
+            } else {
+                None
+            },
+            blues: None,
+        };
+        Some(vec![findings])
+    }
+
+    pub fn step(&mut self, env: &mut Env) -> StepResult {
+        let defn = self.defn;
+        let options = &mut self.options;
+        let progress = &mut self.progress;
+        let constraints = &mut self.constraints;
+        let mut difficulty;
+        let visible_cells: BTreeSet<_> = progress.blacks.union(&progress.blues).cloned().collect();
+        if let Some(on_step) = &mut options.on_step {
+            on_step(&SolveProgress {
+                step: self.step_count,
+                visible_count: visible_cells.len(),
+                unknown_count: progress.unknowns.len(),
+                difficulty: self.last_difficulty,
+            });
+        }
+        self.step_count += 1;
 
         // Step 1 - Transfer constraints from hidden to visible in order to reflect the status of
         // `progress`.
@@ -382,23 +1301,38 @@ pub fn solve(env: &mut Env, defn: &Defn, verbose: bool) -> Outcome {
 
         // Step 2 - Narrow down each of the visible constraints in order to reflect the status of
         // `progress`.
-        constraints.narrow(&visible_cells, &progress);
+        constraints.narrow(&visible_cells, progress);
 
         // Step 3 - Transfer visible constraints to exhausted if they don't carry uncertainty
         // anymore (i.e. the ones that were narrowed while `progress` knows all they scope).
-        constraints.gc();
+        if let Err(contradiction) = constraints.gc() {
+            return StepResult::Contradiction(contradiction);
+        }
 
         // Step 4 - Check if finished
         if progress.is_solved() {
             assert!(constraints.is_solved());
-            break;
-        } else {
-            assert!(!constraints.is_solved());
+            return StepResult::Solved;
+        } else if constraints.is_solved() {
+            // Without the global constraint, some unknown cells may be out of every scope, only a
+            // guess reveals them (see step 5.4)
+            assert!(!options.use_global_count);
+            if !options.guess || options.blind {
+                return StepResult::Unsolvable;
+            }
         }
 
         // Step 5.1 - Look for trivial invariants (i.e. previously unknown cells that can be infered
         // by looking at a single constraint).
-        let mut invariants = constraints.trivial_invariants(defn);
+        let start_time = if options.timing {
+            Some(env.now())
+        } else {
+            None
+        };
+        let (mut invariants, mut justifications) = match constraints.trivial_invariants() {
+            Ok(x) => x,
+            Err(contradiction) => return StepResult::Contradiction(contradiction),
+        };
         difficulty = Difficulty::Local(1);
 
         // Step 5.2 - Look for compound invariants, gradually increasing the level of cognitive load
@@ -406,37 +1340,1138 @@ pub fn solve(env: &mut Env, defn: &Defn, verbose: bool) -> Outcome {
         // combinatorial explosion, see step 5.3 for this)
         if invariants.is_empty() {
             env.reset_timer();
-            (invariants, difficulty) = match constraints.compound_invariants(env, defn) {
-                Ok(x) => x,
-                Err(err) => match err.downcast::<env::Timeout>() {
-                    Ok(_) => return Outcome::Timeout,
-                    Err(_) => panic!("compound_invariants failed"),
-                },
-            };
+            (invariants, justifications, difficulty) =
+                match constraints.compound_invariants(env, None, options.max_group_size) {
+                    Ok(x) => x,
+                    Err(err) => return StepResult::of_abort(err, Phase::Compound),
+                };
         }
 
         // Step 5.3 - Look for invariants using the global constraints
         if invariants.is_empty() {
             difficulty =
                 Difficulty::Global(constraints.constraints_visible.len().try_into().unwrap());
-            invariants = match constraints.global_invariants(env, defn) {
+            let mv = match constraints.global_multiverse(env) {
                 Ok(x) => x,
-                Err(err) => match err.downcast::<env::Timeout>() {
-                    Ok(_) => return Outcome::Timeout,
-                    Err(_) => panic!("compound_invariants failed"),
-                },
+                Err(err) => return StepResult::of_abort(err, Phase::Global),
             };
+            if mv.state() == State::Stuck {
+                return StepResult::Contradiction(Contradiction {
+                    constraint: *UNIQUE_COORDS,
+                    scope: mv.scope,
+                    forced_both_ways: false,
+                });
+            }
+            invariants = mv.invariants();
+            justifications = constraints.constraints_visible.keys().cloned().collect();
+
+            // Step 5.4 - Guess the most certain cell. Without the global constraint, the unknown
+            // cells out of every scope are coin flips, they come last.
+            if invariants.is_empty() && options.guess && !options.blind {
+                let guess = Constraints::best_guess(&mv).or_else(|| {
+                    let mut outside = progress.unknowns.difference(&mv.scope);
+                    outside.next().map(|coords| (*coords, 0.5))
+                });
+                if let Some((coords, certainty)) = guess {
+                    let color = defn::color_of_cell(&defn[&coords]).expect("Unreachable");
+                    difficulty = Difficulty::Guess(certainty);
+                    invariants.insert(coords, color);
+                }
+            }
             if invariants.is_empty() {
-                return Outcome::Unsolvable;
+                return StepResult::Unsolvable;
+            }
+        }
+
+        // Step 5.5 - Check the invariants against the colors of the defn
+        for (coords, got) in invariants.iter().filter(|_| !options.blind) {
+            let expected = defn::color_of_cell(&defn[coords]).expect("Unreachable");
+            if expected != *got {
+                return StepResult::ColorMismatch(ColorMismatch {
+                    coords: *coords,
+                    expected,
+                    got: *got,
+                });
             }
         }
-        history.push(Findings {
+        let scope_size = if options.scope_size {
+            let scope: BTreeSet<_> = justifications
+                .iter()
+                .filter_map(|k| constraints.constraints_visible.get(k))
+                .flat_map(|mv| mv.scope.iter())
+                .collect();
+            Some(scope.len().try_into().unwrap())
+        } else {
+            None
+        };
+        let findings = Findings {
             difficulty,
             cells: invariants.keys().cloned().collect(),
-        });
+            justifications,
+            scope_size,
+            elapsed: start_time.map(|start_time| env.now().saturating_sub(start_time)),
+            blues: if options.blind {
+                Some(
+                    invariants
+                        .iter()
+                        .filter(|(_, color)| **color == Color::Blue)
+                        .map(|(coords, _)| *coords)
+                        .collect(),
+                )
+            } else {
+                None
+            },
+        };
+
+        log::trace!("Step {}: {:?}", self.step_count, findings);
 
         // Step 6 - Reflect findings in progress
         progress.update(invariants);
+        self.last_difficulty = Some(difficulty);
+        StepResult::Finding(findings)
+    }
+}
+
+pub fn solve(env: &mut Env, defn: &Defn, options: SolveOptions) -> Outcome {
+    solve_from(env, defn, &BTreeMap::new(), options).expect("Unreachable")
+}
+
+/// Same as [solve] but resumes from a board where the cells of `known` are already revealed. The
+/// findings only contain the cells that are discovered from there.
+/// Fails like [Solver::new_from] on the cells of `known`.
+pub fn solve_from(
+    env: &mut Env,
+    defn: &Defn,
+    known: &BTreeMap<Coords, Color>,
+    options: SolveOptions,
+) -> Result<Outcome, RevealError> {
+    if !options.blind {
+        if let Err(contradiction) = check_solvable_with(defn, &options) {
+            return Ok(Outcome::Contradiction(contradiction));
+        }
+    }
+    let mut solver = Solver::new_from(defn, known, options)?;
+    if let Some(history) = solver.trivial_history() {
+        return Ok(Outcome::solved(history));
+    }
+    let mut history = vec![];
+    Ok(loop {
+        match solver.step(env) {
+            StepResult::Finding(findings) => history.push(findings),
+            StepResult::Solved => break Outcome::solved(history),
+            StepResult::Unsolvable => break Outcome::Unsolvable { findings: history },
+            StepResult::Timeout(phase) => break Outcome::Timeout(Some(phase)),
+            StepResult::Cancelled => break Outcome::Cancelled,
+            StepResult::BudgetExceeded => break Outcome::BudgetExceeded,
+            StepResult::Contradiction(contradiction) => {
+                break Outcome::Contradiction(contradiction)
+            }
+            StepResult::ColorMismatch(mismatch) => break Outcome::ColorMismatch(mismatch),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two unknown cells, one of which is blue, and no hints
+    const COIN_FLIP: &str = "Hexcells level v1
+Coin flip
+Nobody
+
+
+x...o...
+........";
+
+    /// The line finds its two blue cells, only the global constraint finds the black one
+    const LINE: &str = "Hexcells level v1
+Line
+Nobody
+
+
+|+....
+......
+x...o.
+......
+x.....";
+
+    /// The line says that its two blue cells are separated, they are together
+    const BUGGED: &str = "Hexcells level v1
+Bugged
+Nobody
+
+
+|n....
+......
+x...o.
+......
+x.....
+......
+o.....";
+
+    const THE_TRIAL: &str = include_str!("../extra/the_trial.txt");
+
+    #[test]
+    pub fn test_the_trial() {
+        let defn = defn::of_string(THE_TRIAL).unwrap();
+        let mut env = Env::new(60);
+        let outcome = solve(&mut env, &defn, SolveOptions::default());
+        assert_eq!(
+            format!("{}", outcome),
+            "Solved steps:44 max-local-difficulty:Some(4) max-global-difficulty:Some(15)"
+        );
+        assert_eq!(outcome.step_count(), Some(44));
+        assert_eq!(outcome.max_local_difficulty(), Some(4));
+        assert_eq!(outcome.max_global_difficulty(), Some(15));
+        assert_eq!(Outcome::Timeout(None).step_count(), None);
+    }
+
+    /// Solve each `.txt` level of `dir` and compare the outcome with its golden file in
+    /// `dir/golden/`. With `HEXCELLS_UPDATE_GOLDEN=1` the golden files are (re)written instead.
+    fn check_golden_dir(dir: &std::path::Path) {
+        let update = std::env::var_os("HEXCELLS_UPDATE_GOLDEN").is_some();
+        let mut paths: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "txt"))
+            .collect();
+        paths.sort();
+        assert!(!paths.is_empty(), "No level in {}", dir.display());
+        for path in paths {
+            let defn = defn::from_hexcells_file(path.to_str().unwrap()).unwrap();
+            let outcome = solve(&mut Env::new(60), &defn, SolveOptions::default());
+            let json = serde_json::to_string_pretty(&outcome).unwrap() + "\n";
+            let golden_path = dir
+                .join("golden")
+                .join(path.file_stem().unwrap())
+                .with_extension("json");
+            if update {
+                std::fs::create_dir_all(golden_path.parent().unwrap()).unwrap();
+                std::fs::write(&golden_path, json).unwrap();
+                continue;
+            }
+            let golden = std::fs::read_to_string(&golden_path).unwrap_or_else(|err| {
+                panic!(
+                    "Failed to read {} ({}), run with HEXCELLS_UPDATE_GOLDEN=1",
+                    golden_path.display(),
+                    err
+                )
+            });
+            assert!(
+                json == golden,
+                "The outcome of {} differs from {}",
+                path.display(),
+                golden_path.display()
+            );
+        }
+    }
+
+    /// Solve each level of `extra/` and compare the outcome with its golden file in
+    /// `extra/golden/`. Run with `HEXCELLS_UPDATE_GOLDEN=1` to (re)write the golden files.
+    /// The reddit corpus is not in the repo: `./cache_solver` only holds the outcomes of the batch
+    /// runs, keyed by hash, not the levels. See [test_golden_corpus] to cover more levels.
+    #[test]
+    pub fn test_golden() {
+        check_golden_dir(&std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("extra"));
+    }
+
+    /// Same as [test_golden] on the levels of the `HEXCELLS_GOLDEN_DIR` directory, e.g. a dump of
+    /// the reddit corpus, with the golden files in its `golden/` subdirectory. Skipped when the
+    /// variable is unset.
+    #[test]
+    pub fn test_golden_corpus() {
+        match std::env::var_os("HEXCELLS_GOLDEN_DIR") {
+            None => eprintln!("HEXCELLS_GOLDEN_DIR is unset, skipping"),
+            Some(dir) => check_golden_dir(std::path::Path::new(&dir)),
+        }
+    }
+
+    #[test]
+    pub fn test_count_solutions() {
+        let count = |strdefn: &str| {
+            let defn = defn::of_string(strdefn).unwrap();
+            count_solutions(&mut Env::new(60), &defn).unwrap()
+        };
+        assert_eq!(count(COIN_FLIP), SolutionCount::Many);
+        assert_eq!(count(THE_TRIAL), SolutionCount::One);
+        assert_eq!(
+            count(
+                "Hexcells level v1
+Upward
+Nobody
+
+
+x.
+..
+x.
+..
+|+"
+            ),
+            SolutionCount::One
+        );
+        // The single blue around the flower can't be separated
+        assert_eq!(
+            count(
+                "Hexcells level v1
+Separated
+Nobody
+
+
+Xn
+..
+x."
+            ),
+            SolutionCount::Zero
+        );
+    }
+
+    #[test]
+    pub fn test_count_solutions_from() {
+        for strdefn in [COIN_FLIP, THE_TRIAL] {
+            let defn = defn::of_string(strdefn).unwrap();
+            let count = count_solutions(&mut Env::new(60), &defn).unwrap();
+            let options = SolveOptions {
+                guess: true,
+                ..SolveOptions::default()
+            };
+            let outcome = solve(&mut Env::new(60), &defn, options);
+            let deductions = outcome.deductions(&defn);
+            for (coords, color) in &deductions {
+                assert_eq!(defn::color_of_cell(&defn[coords]), Some(*color));
+            }
+            let found = count_solutions_from(
+                &mut Env::new(60),
+                &defn,
+                &deductions,
+                &SolveOptions::default(),
+            )
+            .unwrap();
+            assert_eq!(found, count);
+        }
+        // The deductions stop at the first guess
+        let defn = defn::of_string(COIN_FLIP).unwrap();
+        let options = SolveOptions {
+            guess: true,
+            ..SolveOptions::default()
+        };
+        let outcome = solve(&mut Env::new(60), &defn, options);
+        assert_eq!(outcome.guess_count(), Some(1));
+        assert!(outcome.deductions(&defn).is_empty());
+        let count = count_solutions_from(
+            &mut Env::new(60),
+            &defn,
+            &BTreeMap::new(),
+            &SolveOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(count, SolutionCount::Many);
+        let outcome = solve(&mut Env::new(60), &defn, SolveOptions::default());
+        assert!(matches!(outcome, Outcome::Unsolvable { .. }));
+        assert!(outcome.deductions(&defn).is_empty());
+        let legacy: Outcome = serde_json::from_str("\"Unsolvable\"").unwrap();
+        assert!(matches!(legacy, Outcome::Unsolvable { findings } if findings.is_empty()));
+    }
+
+    #[test]
+    pub fn test_difficulty_ordering() {
+        let mut difficulties = vec![
+            Difficulty::Guess(0.5),
+            Difficulty::Global(3),
+            Difficulty::Local(10),
+            Difficulty::Guess(0.9),
+            Difficulty::Local(2),
+            Difficulty::Global(15),
+        ];
+        difficulties.sort();
+        assert_eq!(
+            difficulties,
+            vec![
+                Difficulty::Local(2),
+                Difficulty::Local(10),
+                Difficulty::Global(3),
+                Difficulty::Global(15),
+                Difficulty::Guess(0.9),
+                Difficulty::Guess(0.5),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_reveal() {
+        let defn = defn::of_string(COIN_FLIP).unwrap();
+        let blue = defn
+            .iter()
+            .find(|(_, cell)| defn::color_of_cell(cell) == Some(Color::Blue))
+            .map(|(coords, _)| *coords)
+            .unwrap();
+        let mut env = Env::new(60);
+        let mut solver = Solver::new(&defn, SolveOptions::default());
+        assert!(matches!(solver.step(&mut env), StepResult::Unsolvable));
+        let black = *defn.keys().find(|coords| **coords != blue).unwrap();
+        let mismatch = solver.reveal(black, Color::Blue).unwrap_err();
+        assert!(matches!(mismatch, RevealError::ColorMismatch(_)));
+        solver.reveal(blue, Color::Blue).unwrap();
+        let err = solver.reveal(blue, Color::Blue).unwrap_err();
+        assert_eq!(err, RevealError::NotUnknown(blue));
+        match solver.step(&mut env) {
+            StepResult::Finding(findings) => {
+                assert_eq!(findings.cells().len(), 1);
+                assert!(!findings.cells().contains(&blue));
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+        assert!(matches!(solver.step(&mut env), StepResult::Solved));
+
+        // A blind solver trusts the player, and reports the revealed cells that break a hint
+        let defn = defn::of_string(&COIN_FLIP.replace("........", "......o.")).unwrap();
+        let options = SolveOptions {
+            blind: true,
+            ..SolveOptions::default()
+        };
+        let mut solver = Solver::new(&defn, options);
+        let blacks: Vec<_> = Progress::of_defn(&defn)
+            .unknowns
+            .into_iter()
+            .filter(|coords| *coords != blue)
+            .collect();
+        solver.reveal(blacks[0], Color::Blue).unwrap();
+        let err = solver.reveal(blacks[1], Color::Blue).unwrap_err();
+        assert!(matches!(err, RevealError::Contradiction(_)));
+    }
+
+    #[test]
+    pub fn test_guess() {
+        let defn = defn::of_string(COIN_FLIP).unwrap();
+        let mut env = Env::new(60);
+        let outcome = solve(&mut env, &defn, SolveOptions::default());
+        assert!(matches!(outcome, Outcome::Unsolvable { .. }));
+
+        let options = SolveOptions {
+            guess: true,
+            ..SolveOptions::default()
+        };
+        let outcome = solve(&mut env, &defn, options);
+        assert_eq!(outcome.guess_count(), Some(1));
+        let findings_vec = match outcome {
+            Outcome::Solved { findings, .. } => findings,
+            outcome => panic!("Unexpected outcome {:?}", outcome),
+        };
+        assert_eq!(findings_vec.len(), 2);
+        assert!(matches!(findings_vec[0].difficulty, Difficulty::Guess(p) if p == 0.5));
+        assert!(matches!(findings_vec[1].difficulty, Difficulty::Local(1)));
+
+        // As cached before the guesses were recorded
+        let json = serde_json::to_string(&findings_vec).unwrap();
+        let outcome: Outcome = serde_json::from_str(&format!(r#"{{"Solved":{}}}"#, json)).unwrap();
+        assert_eq!(outcome.guess_count(), Some(1));
+        assert_eq!(outcome.step_count(), Some(2));
+        let json = serde_json::to_string(&outcome).unwrap();
+        assert!(json.ends_with(r#""guesses":1}}"#));
+        let outcome: Outcome = serde_json::from_str(&json).unwrap();
+        assert_eq!(outcome.guess_count(), Some(1));
+        assert_eq!(Outcome::Unsolvable { findings: vec![] }.guess_count(), None);
+    }
+
+    #[test]
+    pub fn test_step() {
+        let defn = defn::of_string(THE_TRIAL).unwrap();
+        let mut env = Env::new(60);
+        let mut solver = Solver::new(&defn, SolveOptions::default());
+        let mut discovered = BTreeSet::new();
+        let mut steps = 0;
+        loop {
+            match solver.step(&mut env) {
+                StepResult::Finding(findings) => {
+                    assert!(!findings.cells().is_empty());
+                    assert!(findings.cells().is_disjoint(&discovered));
+                    assert!(!findings.justifications().is_empty());
+                    discovered.extend(findings.cells().iter().cloned());
+                    steps += 1;
+                }
+                StepResult::Solved => break,
+                result => panic!("Unexpected result {:?}", result),
+            }
+        }
+        assert_eq!(steps, 44);
+        assert_eq!(discovered, Progress::of_defn(&defn).unknowns);
+        assert!(matches!(solver.step(&mut env), StepResult::Solved));
+    }
+
+    #[test]
+    pub fn test_justifications() {
+        let defn = defn::of_string(LINE).unwrap();
+        let line = defn
+            .iter()
+            .find(|(_, cell)| matches!(cell, Cell::Line { .. }))
+            .map(|(coords, _)| *coords)
+            .unwrap();
+        let mut env = Env::new(60);
+        let findings_vec = match solve(&mut env, &defn, SolveOptions::default()) {
+            Outcome::Solved { findings, .. } => findings,
+            outcome => panic!("Unexpected outcome {:?}", outcome),
+        };
+        assert_eq!(findings_vec.len(), 2);
+        assert_eq!(findings_vec[0].cells().len(), 2);
+        assert_eq!(findings_vec[0].justifications(), &BTreeSet::from([line]));
+        assert_eq!(findings_vec[1].cells().len(), 1);
+        assert_eq!(
+            findings_vec[1].justifications(),
+            &BTreeSet::from([*UNIQUE_COORDS])
+        );
+    }
+
+    #[test]
+    pub fn test_query() {
+        let defn = defn::of_string(LINE).unwrap();
+        let mut cells = defn
+            .iter()
+            .filter(|(_, cell)| defn::color_of_cell(cell).is_some());
+        let (black, _) = cells
+            .clone()
+            .find(|(_, cell)| defn::color_of_cell(cell) == Some(Color::Black))
+            .unwrap();
+        let (blue, _) = cells
+            .find(|(_, cell)| defn::color_of_cell(cell) == Some(Color::Blue))
+            .unwrap();
+        let line = defn
+            .iter()
+            .find(|(_, cell)| matches!(cell, Cell::Line { .. }))
+            .map(|(coords, _)| *coords)
+            .unwrap();
+        let mut env = Env::new(60);
+        assert_eq!(
+            query(&mut env, &defn, *blue).unwrap(),
+            QueryResult::Determined {
+                color: Color::Blue,
+                justifications: BTreeSet::from([line]),
+                difficulty: Difficulty::Local(1),
+            }
+        );
+        // Not before the blues are revealed
+        assert!(matches!(
+            query(&mut env, &defn, *black).unwrap(),
+            QueryResult::Determined {
+                color: Color::Black,
+                difficulty: Difficulty::Global(_),
+                ..
+            }
+        ));
+        let defn = defn::of_string(COIN_FLIP).unwrap();
+        let (coords, _) = defn.iter().next().unwrap();
+        assert_eq!(
+            query(&mut env, &defn, *coords).unwrap(),
+            QueryResult::Undetermined
+        );
+
+        // The line forces the black cell blue, the revealed cell forces it black
+        const FORCED_BOTH_WAYS: &str = "Hexcells level v1
+Bugged
+Nobody
+
+
+|n....
+......
+x.....
+......
+x.....
+......
+o.....
+..O+..";
+        let defn = defn::of_string(FORCED_BOTH_WAYS).unwrap();
+        let (black, _) = defn
+            .iter()
+            .find(|(_, cell)| {
+                matches!(
+                    cell,
+                    Cell::Zone0 {
+                        revealed: false,
+                        color: Color::Black
+                    }
+                )
+            })
+            .unwrap();
+        let err = query(&mut env, &defn, *black).unwrap_err();
+        let contradiction = err.downcast::<Contradiction>().unwrap();
+        assert_eq!(contradiction.scope, BTreeSet::from([*black]));
+    }
+
+    #[test]
+    pub fn test_scope_size() {
+        let defn = defn::of_string(LINE).unwrap();
+        let outcome = solve(&mut Env::new(60), &defn, SolveOptions::default());
+        assert_eq!(outcome.max_scope_size(), None);
+
+        let options = SolveOptions {
+            scope_size: true,
+            ..SolveOptions::default()
+        };
+        let outcome = solve(&mut Env::new(60), &defn, options);
+        let findings_vec = match &outcome {
+            Outcome::Solved { findings, .. } => findings,
+            outcome => panic!("Unexpected outcome {:?}", outcome),
+        };
+        // The 2 cells of the line, then the last unknown cell
+        assert_eq!(findings_vec[0].scope_size(), Some(2));
+        assert_eq!(findings_vec[1].scope_size(), Some(1));
+        assert_eq!(outcome.max_scope_size(), Some(2));
+        assert!(format!("{}", outcome).ends_with(" max-scope-size:2"));
+    }
+
+    #[test]
+    pub fn test_solve_from() {
+        let defn = defn::of_string(COIN_FLIP).unwrap();
+        let mut env = Env::new(60);
+        let outcome = solve(&mut env, &defn, SolveOptions::default());
+        assert!(matches!(outcome, Outcome::Unsolvable { .. }));
+
+        // Once one of the two cells is uncovered, the global constraint gives the other one
+        let (coords, cell) = defn.iter().next().unwrap();
+        let known = BTreeMap::from([(*coords, defn::color_of_cell(cell).unwrap())]);
+        let outcome = solve_from(&mut env, &defn, &known, SolveOptions::default()).unwrap();
+        let findings_vec = outcome.findings_vec().unwrap();
+        assert_eq!(findings_vec.len(), 1);
+        assert_eq!(findings_vec[0].cells().len(), 1);
+        assert!(!findings_vec[0].cells().contains(coords));
+    }
+
+    #[test]
+    pub fn test_solve_from_wrong_color() {
+        let defn = defn::of_string(COIN_FLIP).unwrap();
+        let (coords, cell) = defn.iter().next().unwrap();
+        let expected = defn::color_of_cell(cell).unwrap();
+        let got = match expected {
+            Color::Blue => Color::Black,
+            Color::Black => Color::Blue,
+        };
+        let known = BTreeMap::from([(*coords, got)]);
+        let err = solve_from(&mut Env::new(60), &defn, &known, SolveOptions::default());
+        assert_eq!(
+            err.unwrap_err(),
+            RevealError::ColorMismatch(ColorMismatch {
+                coords: *coords,
+                expected,
+                got
+            })
+        );
+        let known = BTreeMap::from([(Coords::new(-50, 0, 50), Color::Blue)]);
+        let err = solve_from(&mut Env::new(60), &defn, &known, SolveOptions::default());
+        assert_eq!(
+            err.unwrap_err(),
+            RevealError::NoColor(Coords::new(-50, 0, 50))
+        );
+    }
+
+    #[test]
+    pub fn test_max_group_size() {
+        let defn = defn::of_string(THE_TRIAL).unwrap();
+        let options = SolveOptions {
+            max_group_size: Some(2),
+            ..SolveOptions::default()
+        };
+        // The steps that needed groups of 3 or 4 constraints are made using the global constraint
+        let outcome = solve(&mut Env::new(60), &defn, options);
+        assert_eq!(
+            format!("{}", outcome),
+            "Solved steps:42 max-local-difficulty:Some(2) max-global-difficulty:Some(15)"
+        );
+    }
+
+    #[test]
+    pub fn test_deterministic() {
+        let defn = defn::of_string(THE_TRIAL).unwrap();
+        let json = || {
+            let options = SolveOptions {
+                scope_size: true,
+                ..SolveOptions::default()
+            };
+            let outcome = solve(&mut Env::new(60), &defn, options);
+            serde_json::to_string(&outcome).unwrap()
+        };
+        assert_eq!(json(), json());
+    }
+
+    #[test]
+    pub fn test_trivial_history() {
+        let outcome = |strdefn: &str| {
+            let defn = defn::of_string(strdefn).unwrap();
+            solve(&mut Env::new(60), &defn, SolveOptions::default())
+        };
+        // Already solved
+        let solved = outcome(
+            "Hexcells level v1
+Revealed
+Nobody
+
+
+O+..X+..
+..X+....",
+        );
+        assert!(solved.findings_vec().unwrap().is_empty());
+
+        // The two unknown cells are black
+        let blacks = outcome(
+            "Hexcells level v1
+Blacks
+Nobody
+
+
+O+..x...
+..x.....",
+        );
+        let findings_vec = blacks.findings_vec().unwrap();
+        assert_eq!(findings_vec.len(), 1);
+        assert_eq!(findings_vec[0].cells().len(), 2);
+        assert_eq!(
+            findings_vec[0].justifications(),
+            &BTreeSet::from([*UNIQUE_COORDS])
+        );
+    }
+
+    #[test]
+    pub fn test_cell_difficulties() {
+        let defn = defn::of_string(THE_TRIAL).unwrap();
+        let outcome = solve(&mut Env::new(60), &defn, SolveOptions::default());
+        let difficulties = cell_difficulties(&outcome);
+        assert_eq!(difficulties.len(), Progress::of_defn(&defn).unknowns.len());
+        assert_eq!(
+            difficulties.values().max(),
+            outcome
+                .findings_vec()
+                .unwrap()
+                .iter()
+                .map(|f| &f.difficulty)
+                .max()
+        );
+        assert!(cell_difficulties(&Outcome::Timeout(None)).is_empty());
+    }
+
+    #[test]
+    pub fn test_malformed_separated_line() {
+        // Only 2 cells, that can't hold 2 separated blues
+        const PUZZLE: &str = "Hexcells level v1
+Too short
+Nobody
+
+
+|n
+..
+o.
+..
+O.";
+        let defn = defn::of_string(PUZZLE).unwrap();
+        let outcome = solve(&mut Env::new(60), &defn, SolveOptions::default());
+        assert!(matches!(outcome, Outcome::Contradiction(_)));
+    }
+
+    #[test]
+    pub fn test_blind() {
+        let defn = defn::of_string(THE_TRIAL).unwrap();
+        let options = SolveOptions {
+            blind: true,
+            ..SolveOptions::default()
+        };
+        let outcome = solve(&mut Env::new(60), &defn, options);
+        let findings_vec = outcome.findings_vec().unwrap();
+        assert_eq!(findings_vec.len(), 44);
+        for findings in findings_vec {
+            let blues = findings.blues().unwrap();
+            for coords in findings.cells() {
+                let color = if blues.contains(coords) {
+                    Color::Blue
+                } else {
+                    Color::Black
+                };
+                assert_eq!(defn::color_of_cell(&defn[coords]), Some(color));
+            }
+        }
+
+        let outcome = solve(&mut Env::new(60), &defn, SolveOptions::default());
+        assert!(outcome.findings_vec().unwrap()[0].blues().is_none());
+    }
+
+    #[test]
+    pub fn test_timeout_phase() {
+        let defn = defn::of_string(THE_TRIAL).unwrap();
+        let outcome = solve(&mut Env::new(0), &defn, SolveOptions::default());
+        assert!(matches!(outcome, Outcome::Timeout(Some(Phase::Compound))));
+        assert_eq!(format!("{}", outcome), "Timeout in compound_invariants");
+
+        let json = serde_json::to_string(&outcome).unwrap();
+        assert_eq!(json, r#"{"Timeout":"Compound"}"#);
+        let outcome: Outcome = serde_json::from_str(&json).unwrap();
+        assert!(matches!(outcome, Outcome::Timeout(Some(Phase::Compound))));
+        // As cached before the phase was recorded
+        let outcome: Outcome = serde_json::from_str(r#""Timeout""#).unwrap();
+        assert!(matches!(outcome, Outcome::Timeout(None)));
+        let outcome: Outcome = serde_json::from_str(r#""Unsolvable""#).unwrap();
+        assert!(matches!(outcome, Outcome::Unsolvable { .. }));
+    }
+
+    #[test]
+    pub fn test_timing() {
+        let defn = defn::of_string(THE_TRIAL).unwrap();
+        let outcome = solve(&mut Env::new(60), &defn, SolveOptions::default());
+        let findings_vec = outcome.findings_vec().unwrap();
+        assert!(findings_vec
+            .iter()
+            .all(|findings| findings.elapsed().is_none()));
+
+        let options = SolveOptions {
+            timing: true,
+            ..SolveOptions::default()
+        };
+        let outcome = solve(&mut Env::new(60), &defn, options);
+        let findings_vec = outcome.findings_vec().unwrap();
+        assert!(findings_vec
+            .iter()
+            .all(|findings| findings.elapsed().is_some()));
+    }
+
+    #[test]
+    pub fn test_contradiction() {
+        let defn = defn::of_string(BUGGED).unwrap();
+        let line = defn
+            .iter()
+            .find(|(_, cell)| matches!(cell, Cell::Line { .. }))
+            .map(|(coords, _)| *coords)
+            .unwrap();
+        let contradiction = check_solvable(&defn).unwrap_err();
+        assert_eq!(contradiction.constraint, line);
+        assert_eq!(contradiction.scope.len(), 3);
+        let mut env = Env::new(60);
+        let outcome = solve(&mut env, &defn, SolveOptions::default());
+        assert!(matches!(outcome, Outcome::Contradiction(c) if c == contradiction));
+
+        assert_eq!(check_solvable(&defn::of_string(THE_TRIAL).unwrap()), Ok(()));
+    }
+
+    #[test]
+    pub fn test_color_mismatch() {
+        // The first and last cells of the line swap their colors, the blues are still together
+        // and the solver deduces the wrong colors. `solve` would report the contradiction first,
+        // `step` doesn't check it.
+        let puzzle = BUGGED
+            .replace("o.....", "x.....")
+            .replace("x...o.", "o.....");
+        let defn = defn::of_string(&puzzle).unwrap();
+        let black = defn
+            .iter()
+            .find(|(_, cell)| defn::color_of_cell(cell) == Some(Color::Black))
+            .map(|(coords, _)| *coords)
+            .unwrap();
+        let mut solver = Solver::new(&defn, SolveOptions::default());
+        let result = solver.step(&mut Env::new(60));
+        let expected = ColorMismatch {
+            coords: black,
+            expected: Color::Black,
+            got: Color::Blue,
+        };
+        assert!(
+            matches!(result, StepResult::ColorMismatch(ref m) if *m == expected),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    pub fn test_upward_line() {
+        // The only hint is below the column
+        const PUZZLE: &str = "Hexcells level v1
+Upward
+Nobody
+
+
+x.
+..
+x.
+..
+|+";
+        let defn = defn::of_string(PUZZLE).unwrap();
+        let mut env = Env::new(60);
+        let outcome = solve(&mut env, &defn, SolveOptions::default());
+        assert!(matches!(outcome, Outcome::Solved { .. }), "{}", outcome);
+    }
+
+    #[test]
+    pub fn test_use_global_count() {
+        // The line finds its two blue cells, nothing but the global count tells the color of the
+        // last cell
+        let no_global = || SolveOptions {
+            use_global_count: false,
+            ..SolveOptions::default()
+        };
+        let defn = defn::of_string(LINE).unwrap();
+        let outcome = solve(&mut Env::new(60), &defn, no_global());
+        assert!(matches!(outcome, Outcome::Unsolvable { .. }), "{}", outcome);
+        let outcome = solve(&mut Env::new(60), &defn, SolveOptions::default());
+        assert!(matches!(outcome, Outcome::Solved { .. }), "{}", outcome);
+
+        // The other entry points follow the option
+        let (black, _) = defn
+            .iter()
+            .find(|(_, cell)| defn::color_of_cell(cell) == Some(Color::Black))
+            .unwrap();
+        let mut env = Env::new(60);
+        assert_eq!(
+            query_with(&mut env, &defn, *black, &no_global()).unwrap(),
+            QueryResult::Undetermined
+        );
+        assert_eq!(
+            count_solutions_with(&mut env, &defn, &no_global()).unwrap(),
+            SolutionCount::Many
+        );
+        assert_eq!(
+            count_solutions(&mut env, &defn).unwrap(),
+            SolutionCount::One
+        );
+        assert!(check_solvable_with(&defn, &no_global()).is_ok());
+
+        // The cell out of every scope can still be guessed
+        let options = SolveOptions {
+            guess: true,
+            ..no_global()
+        };
+        let outcome = solve(&mut Env::new(60), &defn, options);
+        assert!(
+            matches!(outcome, Outcome::Solved { guesses: 1, .. }),
+            "{}",
+            outcome
+        );
+
+        // Without any hint, the trivial history relies on the global count too
+        let defn = defn::of_string(&LINE.replace("o.", "x.").replace("|+", "..")).unwrap();
+        let outcome = solve(&mut Env::new(60), &defn, SolveOptions::default());
+        assert_eq!(outcome.step_count(), Some(1));
+        let outcome = solve(&mut Env::new(60), &defn, no_global());
+        assert!(matches!(outcome, Outcome::Unsolvable { .. }), "{}", outcome);
+        let options = SolveOptions {
+            guess: true,
+            ..no_global()
+        };
+        let outcome = solve(&mut Env::new(60), &defn, options);
+        assert!(matches!(outcome, Outcome::Solved { .. }), "{}", outcome);
+    }
+
+    #[test]
+    pub fn test_line_gap() {
+        // Both cells of the line are counted across the hole, the zone6 hint then tells which
+        // one is blue. When stopping at the hole the line hint is enough.
+        let defn = defn::of_string(include_str!("../extra/line_gap.txt")).unwrap();
+        let outcome = solve(&mut Env::new(60), &defn, SolveOptions::default());
+        assert_eq!(outcome.step_count(), Some(2));
+        let options = SolveOptions {
+            line_gaps: LineGaps::Stop,
+            ..SolveOptions::default()
+        };
+        let outcome = solve(&mut Env::new(60), &defn, options);
+        assert_eq!(outcome.step_count(), Some(1));
+
+        // The other entry points follow the option
+        let stop = || SolveOptions {
+            line_gaps: LineGaps::Stop,
+            ..SolveOptions::default()
+        };
+        let (blue, _) = defn
+            .iter()
+            .find(|(_, cell)| defn::color_of_cell(cell) == Some(Color::Blue))
+            .unwrap();
+        let mut env = Env::new(60);
+        assert!(matches!(
+            query(&mut env, &defn, *blue).unwrap(),
+            QueryResult::Determined {
+                difficulty: Difficulty::Local(2),
+                ..
+            }
+        ));
+        assert!(matches!(
+            query_with(&mut env, &defn, *blue, &stop()).unwrap(),
+            QueryResult::Determined {
+                difficulty: Difficulty::Local(1),
+                ..
+            }
+        ));
+        assert_eq!(
+            count_solutions_with(&mut env, &defn, &stop()).unwrap(),
+            SolutionCount::One
+        );
+        assert!(check_solvable_with(&defn, &stop()).is_ok());
+    }
+
+    #[test]
+    pub fn test_column_count() {
+        // The zone6 hint leaves one blue between the top cells of the two columns, the column
+        // hint tells which one
+        let strdefn = include_str!("../extra/column_count.txt");
+        let defn = defn::of_string(strdefn).unwrap();
+        let outcome = solve(&mut Env::new(60), &defn, SolveOptions::default());
+        assert!(matches!(outcome, Outcome::Solved { .. }), "{}", outcome);
+        let defn = defn::of_string(&strdefn.replace("#+", "..")).unwrap();
+        let outcome = solve(&mut Env::new(60), &defn, SolveOptions::default());
+        assert!(matches!(outcome, Outcome::Unsolvable { .. }), "{}", outcome);
+    }
+
+    #[test]
+    pub fn test_constraint_graph() {
+        let defn = defn::of_string(THE_TRIAL).unwrap();
+        let graph = constraint_graph(&defn);
+        let hints: BTreeSet<_> = defn::constraint_cells(&defn).map(|(c, _)| c).collect();
+        assert_eq!(graph.nodes, hints);
+        assert!(!graph.edges.is_empty());
+        for (k0, k1) in &graph.edges {
+            assert!(k0 < k1);
+            assert!(graph.nodes.contains(k0) && graph.nodes.contains(k1));
+        }
+        let dot = graph.to_dot();
+        assert!(dot.starts_with("graph constraints {\n"));
+        assert_eq!(
+            dot.lines().count(),
+            graph.nodes.len() + graph.edges.len() + 2
+        );
+        let json = serde_json::to_value(&graph).unwrap();
+        assert_eq!(json["edges"].as_array().unwrap().len(), graph.edges.len());
+    }
+
+    #[test]
+    pub fn test_cancel() {
+        let defn = defn::of_string(THE_TRIAL).unwrap();
+        let mut env = Env::new(60);
+        let token = env.cancel_token();
+        std::thread::spawn(move || token.cancel()).join().unwrap();
+        let outcome = solve(&mut env, &defn, SolveOptions::default());
+        assert!(matches!(outcome, Outcome::Cancelled));
+
+        // Trivial steps don't check for cancellation
+        let mut env = Env::new(60);
+        let mut solver = Solver::new(&defn, SolveOptions::default());
+        env.cancel();
+        let mut results = vec![];
+        loop {
+            match solver.step(&mut env) {
+                StepResult::Finding(_) => results.push(()),
+                StepResult::Cancelled => break,
+                result => panic!("Unexpected result {:?}", result),
+            }
+        }
+        assert!(results.len() < 44);
+    }
+
+    #[test]
+    pub fn test_opposite_invariants() {
+        // Two constraints that each force `cell`, with opposite colors, as after a blind solver
+        // was given wrong colors
+        let cell = Coords::new(0, 0, 0);
+        let forced = |blue_count| {
+            let layout =
+                ::multiverse::Layout::new(BTreeMap::from([(BTreeSet::from([cell]), blue_count)]));
+            Multiverse::new(BTreeSet::from([cell]), vec![layout])
+        };
+        let (k0, k1) = (Coords::new(1, 0, -1), Coords::new(-1, 0, 1));
+        let mut constraints = Constraints {
+            constraints_hidden: BTreeMap::new(),
+            constraints_visible: BTreeMap::from([(k0, forced(1)), (k1, forced(0))]),
+            constraints_exhausted: BTreeSet::new(),
+            merge_cache: BTreeMap::new(),
+        };
+        // `k1` comes first, `k0` is the one that disagrees
+        let expected = Contradiction {
+            constraint: k0,
+            scope: BTreeSet::from([cell]),
+            forced_both_ways: true,
+        };
+        assert_eq!(constraints.trivial_invariants().unwrap_err(), expected);
+        assert_eq!(
+            expected.to_string(),
+            format!(
+                "The cell at {} is forced blue and black (constraint at {})",
+                cell, k0
+            )
+        );
+        // Merged, they have no layout left
+        let err = constraints
+            .compound_invariants(&mut Env::new(60), None, None)
+            .unwrap_err();
+        assert!(matches!(
+            StepResult::of_abort(err, Phase::Compound),
+            StepResult::Contradiction(contradiction) if contradiction.scope == expected.scope
+        ));
+
+        // Two consistent groups that force `cell` with opposite colors: `{cell, p}` holds 1 blue
+        // and `p` is black, `{cell, q}` holds 1 blue and `q` is blue
+        let (p, q) = (Coords::new(0, 1, -1), Coords::new(0, -1, 1));
+        let count = |cells: &[Coords], blue_count| {
+            let key: BTreeSet<_> = cells.iter().cloned().collect();
+            let layout = ::multiverse::Layout::new(BTreeMap::from([(key.clone(), blue_count)]));
+            Multiverse::new(key, vec![layout])
+        };
+        let ks: Vec<_> = (0..4).map(|i| Coords::new(10 + i, 0, -10 - i)).collect();
+        let mut constraints = Constraints {
+            constraints_hidden: BTreeMap::new(),
+            constraints_visible: BTreeMap::from([
+                (ks[0], count(&[cell, p], 1)),
+                (ks[1], count(&[p], 0)),
+                (ks[2], count(&[cell, q], 1)),
+                (ks[3], count(&[q], 1)),
+            ]),
+            constraints_exhausted: BTreeSet::new(),
+            merge_cache: BTreeMap::new(),
+        };
+        let err = constraints
+            .compound_invariants(&mut Env::new(60), None, None)
+            .unwrap_err();
+        let contradiction = err.downcast::<Contradiction>().unwrap();
+        assert_eq!(contradiction.scope, BTreeSet::from([cell]));
+    }
+
+    #[test]
+    pub fn test_merge_budget() {
+        let defn = defn::of_string(THE_TRIAL).unwrap();
+        let mut env = Env::new(60);
+        solve(&mut env, &defn, SolveOptions::default());
+        let merge_count = env.merge_count();
+        assert!(merge_count > 0);
+
+        // The number of merges is deterministic
+        let mut env = Env::new(60).with_merge_budget(merge_count);
+        let outcome = solve(&mut env, &defn, SolveOptions::default());
+        assert!(matches!(outcome, Outcome::Solved { .. }));
+        assert_eq!(env.merge_count(), merge_count);
+
+        let mut env = Env::new(60).with_merge_budget(merge_count - 1);
+        let outcome = solve(&mut env, &defn, SolveOptions::default());
+        assert!(matches!(outcome, Outcome::BudgetExceeded));
+    }
+
+    #[test]
+    pub fn test_on_step() {
+        use std::sync::{Arc, Mutex};
+
+        let defn = defn::of_string(THE_TRIAL).unwrap();
+        let mut env = Env::new(60);
+        let snapshots = Arc::new(Mutex::new(vec![]));
+        let snapshots2 = snapshots.clone();
+        let options = SolveOptions {
+            on_step: Some(Box::new(move |progress: &SolveProgress| {
+                snapshots2.lock().unwrap().push(progress.clone())
+            })),
+            ..SolveOptions::default()
+        };
+        solve(&mut env, &defn, options);
+        let snapshots = snapshots.lock().unwrap();
+        // One call per finding, plus the last one that notices that the puzzle is solved
+        assert_eq!(snapshots.len(), 45);
+        assert!(snapshots[0].difficulty.is_none());
+        assert!(matches!(
+            snapshots[1].difficulty,
+            Some(Difficulty::Local(1))
+        ));
+        assert_eq!(snapshots.last().unwrap().unknown_count, 0);
+        for (i, progress) in snapshots.iter().enumerate() {
+            assert_eq!(progress.step, i);
+            if i > 0 {
+                assert!(progress.unknown_count < snapshots[i - 1].unknown_count);
+                assert!(progress.visible_count > snapshots[i - 1].visible_count);
+            }
+        }
     }
-    Outcome::Solved(history)
 }